@@ -1,6 +1,7 @@
 import com.sun.jna.Library;
 import com.sun.jna.Native;
 import com.sun.jna.Platform;
+import com.sun.jna.Pointer;
 
 import java.io.File;
 import java.io.FileOutputStream;
@@ -14,37 +15,54 @@ public class JavaMetrics {
             Native.loadLibrary("metrics_controller",
                                RustLibrary.class);
 
-        void init_metrics(String app_name,
-                          String app_version,
-                          String app_update_channel,
-                          String app_build_id,
-                          String app_platform,
-                          String locale,
-                          String device,
-                          String arch,
-                          String os,
-                          String os_version);
-        int record_event(String category, String action,
+        // Statuses returned by the calls below, see include/metrics_controller.h.
+        int METRICS_OK = 0;
+
+        String metrics_last_error();
+        Pointer init_metrics(String app_name,
+                             String app_version,
+                             String app_update_channel,
+                             String app_platform,
+                             String locale,
+                             String device,
+                             String arch,
+                             String os,
+                             String os_version,
+                             String storage_dir);
+        int record_event(Pointer handle, String category, String action,
                          String label, int value);
-        int record_floating_point_event(String category, String action,
-                                        String label, double value);
+        int record_floating_point_event(Pointer handle, String category, String action,
+                                        String label, float value);
+        int metrics_set_upload_enabled(Pointer handle, byte enabled);
+        int metrics_flush(Pointer handle);
+        int metrics_shutdown(Pointer handle);
     }
 
     public static void main(String[] args) throws InterruptedException {
-      RustLibrary.INSTANCE.init_metrics("myapp",
-                                        "1.0",
-                                        "default",
-                                        "20160303",
-                                        "c",
-                                        "en-us",
-                                        "pi",
-                                        "LAMP",
-                                        "linux",
-                                        "redhat");
+      RustLibrary metrics_lib = RustLibrary.INSTANCE;
+      Pointer metrics = metrics_lib.init_metrics("myapp",
+                                                 "1.0",
+                                                 "default",
+                                                 "java",
+                                                 "en-us",
+                                                 "pi",
+                                                 "LAMP",
+                                                 "linux",
+                                                 "redhat",
+                                                 null);
+      if (metrics == null) {
+          System.err.println("init_metrics failed: " + metrics_lib.metrics_last_error());
+          return;
+      }
       for (int i = 0; i < 5; i ++) {
-          RustLibrary.INSTANCE.record_event("test", "click", "order", i);
-          RustLibrary.INSTANCE.record_floating_point_event("test", "click", "order", i * .1);
+          if (metrics_lib.record_event(metrics, "test", "click", "order", i) != RustLibrary.METRICS_OK) {
+              System.err.println("record_event failed: " + metrics_lib.metrics_last_error());
+          }
+          metrics_lib.record_floating_point_event(metrics, "test", "click", "order", i * .1f);
+      }
+      if (metrics_lib.metrics_flush(metrics) != RustLibrary.METRICS_OK) {
+          System.err.println("metrics_flush failed: " + metrics_lib.metrics_last_error());
       }
-      Thread.sleep(30000);
+      metrics_lib.metrics_shutdown(metrics);
     }
 }
@@ -2,44 +2,42 @@
  * This is a sample that shows how to invoke the metrics library from a C application.
  * To run this sample on MacOS:
  * 1.  cargo build
- * 2.  gcc ./examples/ffi_test.c -L ./target/debug/ -lmetrics_controller -o ffitest
+ * 2.  gcc ./examples/ffi_test.c -I ./include -L ./target/debug/ -lmetrics_controller -o ffitest
  * 3.  LD_LIBRARY_PATH=./target/debug ./ffitest
  */
 
-#include <stdint.h>
 #include <stdio.h>
 #include <unistd.h>
 
-void init_metrics(const char* app_name,
-                     const char* app_version,
-                     const char* app_update_channel,
-                     const char* app_platform,
-                     const char* locale,
-                     const char* device,
-                     const char* arch,
-                     const char* os,
-                     const char* os_version);
-int record_event(const char* category, const char* action,
-                     const char* label, int value);
-int record_floating_point_event(const char* category, const char* action,
-                                   const char* label, float value);
+#include "metrics_controller.h"
 
 int main() {
-    init_metrics("myapp",
-                 "1.0",
-                 "default",
-                 "c",
-                 "en-us",
-                 "pi",
-                 "LAMP",
-                 "linux",
-                 "redhat");
+    Foreign* metrics = init_metrics("myapp",
+                                    "1.0",
+                                    "default",
+                                    "c",
+                                    "en-us",
+                                    "pi",
+                                    "LAMP",
+                                    "linux",
+                                    "redhat",
+                                    NULL);
+
+    if (metrics == NULL) {
+      fprintf(stderr, "init_metrics failed: %s\n", metrics_last_error());
+      return 1;
+    }
 
     for(int i = 0; i < 21; i++) {
-      record_event("test", "click", "order", i);
-      record_floating_point_event("test", "click", "order", i * .1);
+      if (record_event(metrics, "test", "click", "order", i) != METRICS_OK) {
+        fprintf(stderr, "record_event failed: %s\n", metrics_last_error());
+      }
+      record_floating_point_event(metrics, "test", "click", "order", i * .1);
       sleep(1);
     }
-    sleep(45);
+    if (metrics_flush(metrics) != METRICS_OK) {
+      fprintf(stderr, "metrics_flush failed: %s\n", metrics_last_error());
+    }
+    metrics_shutdown(metrics);
     return 0;
 }
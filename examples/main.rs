@@ -4,7 +4,6 @@
 extern crate chrono;
 extern crate serde;
 extern crate serde_json;
-extern crate timer;
 extern crate metrics_controller;
 
 use metrics_controller::MetricsController;
@@ -25,11 +24,13 @@ fn main() {
     metrics_controller.record_event("event category",
                                     "event action",
                                     "event label",
-                                    999999);
+                                    999999)
+        .unwrap();
     metrics_controller.record_floating_point_event("event category",
                                                    "event action",
                                                    "event label",
-                                                   999999.9);
+                                                   999999.9)
+        .unwrap();
 
     // This sleep is necessary so the main thread does not exit.
     thread::sleep(std::time::Duration::from_secs(20));
@@ -0,0 +1,89 @@
+// Generated by tools/gen_header.py from src/foreign_metrics/mod.rs.  Do not edit.
+
+#ifndef METRICS_CONTROLLER_H
+#define METRICS_CONTROLLER_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Foreign Foreign;
+
+// What a call returns.  `metrics_last_error` describes any status other
+// than `MetricsOk`.
+typedef enum MetricsStatus {
+    METRICS_OK = 0,
+    // The handle is NULL.
+    METRICS_NULL_HANDLE = 1,
+    // A field of the event is longer than the Measurement Protocol allows.
+    METRICS_FIELD_TOO_LONG = 2,
+    // The event is longer than the Measurement Protocol allows.
+    METRICS_HIT_TOO_LONG = 3,
+    // Some events could not be sent.  They are kept for the next send.
+    METRICS_NOT_SENT = 4,
+    // A string argument is NULL.
+    METRICS_INVALID_ARGUMENT = 5,
+    // The library failed unexpectedly.  The controller may still work.
+    METRICS_PANIC = 6,
+} MetricsStatus;
+
+// Returns a description of the last error on the calling thread, e.g. why
+// `record_event` did not return `MetricsOk`, or an empty string.  The string
+// belongs to the library and stays valid until the next failing call on the
+// same thread.
+const char* metrics_last_error(void);
+
+// Initializes a controller for the Metrics Libary and returns a handle to
+// it.  Caller passes information about their application and environment.
+// This information will be associated with the metrics data recorded by the
+// `record_event` function.
+Foreign* init_metrics(const char* app_name,
+                      const char* app_version,
+                      const char* app_update_channel,
+                      const char* app_platform,
+                      const char* locale,
+                      const char* device,
+                      const char* arch,
+                      const char* os,
+                      const char* os_version,
+                      const char* storage_dir);
+
+// Constructs a new event which is batched and sent to the Google Analytics
+// server.
+MetricsStatus record_event(Foreign* handle,
+                           const char* event_category,
+                           const char* event_action,
+                           const char* event_label,
+                           int32_t event_value);
+
+// Constructs a new event which is batched and sent to the Google Analytics
+// server.
+MetricsStatus record_floating_point_event(Foreign* handle,
+                                          const char* event_category,
+                                          const char* event_action,
+                                          const char* event_label,
+                                          float event_value);
+
+// Turns uploading on or off, as `MetricsController::set_upload_enabled`
+// does.  The setting is kept across restarts.  Disabling upload deletes the
+// events that have not been sent yet and ignores events recorded afterwards.
+MetricsStatus metrics_set_upload_enabled(Foreign* handle,
+                                         bool enabled);
+
+// Sends everything recorded so far and waits until it has been sent, e.g.
+// before the application exits.  The network settings still apply.
+MetricsStatus metrics_flush(Foreign* handle);
+
+// Stops the controller's worker, waits for it to finish and frees the
+// controller.  The handle must not be used afterwards.  Call `metrics_flush`
+// first to send what has been recorded.
+MetricsStatus metrics_shutdown(Foreign* handle);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,175 @@
+package org.mozilla.metrics;
+
+import java.util.concurrent.locks.ReadWriteLock;
+import java.util.concurrent.locks.ReentrantReadWriteLock;
+
+/**
+ * The metrics controller for Java, through the JNI functions of
+ * libmetrics_controller (src/foreign_metrics/jni.rs).  Each instance has its
+ * own client id, events and worker, kept in its storage directory.
+ *
+ * The methods can be called from any thread.  Failures throw a
+ * MetricsException with the status and description from the library.
+ */
+public class MetricsController implements AutoCloseable {
+
+    // The values of MetricsStatus in include/metrics_controller.h.
+    public static final int OK = 0;
+    public static final int NULL_HANDLE = 1;
+    public static final int FIELD_TOO_LONG = 2;
+    public static final int HIT_TOO_LONG = 3;
+    public static final int NOT_SENT = 4;
+    public static final int INVALID_ARGUMENT = 5;
+    public static final int PANIC = 6;
+
+    static {
+        System.loadLibrary("metrics_controller");
+    }
+
+    // Recording and flushing share the lock, shutting down takes it alone so
+    // that the handle is not freed while another thread uses it.
+    private final ReadWriteLock lock = new ReentrantReadWriteLock();
+    private long handle;
+
+    /**
+     * Creates a controller.  storageDir is where metricsconfig.json is read
+     * from and the library keeps its files, or null for the current
+     * directory.  Components in the same process each need their own.
+     */
+    public MetricsController(String appName,
+                             String appVersion,
+                             String appUpdateChannel,
+                             String appPlatform,
+                             String locale,
+                             String device,
+                             String arch,
+                             String os,
+                             String osVersion,
+                             String storageDir) {
+        handle = nativeInit(appName, appVersion, appUpdateChannel, appPlatform, locale, device,
+                            arch, os, osVersion, storageDir);
+        if (handle == 0) {
+            throw new MetricsException(INVALID_ARGUMENT, nativeLastError());
+        }
+    }
+
+    /** Records an event, sent with the next batch. */
+    public void recordEvent(String category, String action, String label, long value) {
+        lock.readLock().lock();
+        try {
+            check(nativeRecordEvent(handle(), category, action, label, value));
+        } finally {
+            lock.readLock().unlock();
+        }
+    }
+
+    /** Records an event that is sent right away, e.g. for a crash. */
+    public void recordUrgentEvent(String category, String action, String label, long value) {
+        lock.readLock().lock();
+        try {
+            check(nativeRecordUrgentEvent(handle(), category, action, label, value));
+        } finally {
+            lock.readLock().unlock();
+        }
+    }
+
+    /** Records an event with a floating point value. */
+    public void recordFloatingPointEvent(String category, String action, String label, double value) {
+        lock.readLock().lock();
+        try {
+            check(nativeRecordFloatingPointEvent(handle(), category, action, label, value));
+        } finally {
+            lock.readLock().unlock();
+        }
+    }
+
+    /**
+     * Turns uploading on or off.  Disabling it (opting out) deletes the
+     * events that have not been sent yet.  The setting is kept across
+     * restarts.
+     */
+    public void setUploadEnabled(boolean enabled) {
+        lock.readLock().lock();
+        try {
+            check(nativeSetUploadEnabled(handle(), enabled));
+        } finally {
+            lock.readLock().unlock();
+        }
+    }
+
+    /**
+     * Sends everything recorded so far and waits until it has been sent.
+     * Returns false if something could not be sent, e.g. on a metered
+     * network; it is kept for the next send.
+     */
+    public boolean flush() {
+        lock.readLock().lock();
+        try {
+            int status = nativeFlush(handle());
+            if (status == NOT_SENT) {
+                return false;
+            }
+            check(status);
+            return true;
+        } finally {
+            lock.readLock().unlock();
+        }
+    }
+
+    /**
+     * Stops the controller and frees it.  Call flush first to send what has
+     * been recorded.  Does nothing if the controller is already shut down.
+     */
+    public void shutdown() {
+        lock.writeLock().lock();
+        try {
+            if (handle != 0) {
+                long shutting = handle;
+                handle = 0;
+                check(nativeShutdown(shutting));
+            }
+        } finally {
+            lock.writeLock().unlock();
+        }
+    }
+
+    @Override
+    public void close() {
+        shutdown();
+    }
+
+    private long handle() {
+        if (handle == 0) {
+            throw new IllegalStateException("The metrics controller has been shut down");
+        }
+        return handle;
+    }
+
+    private static void check(int status) {
+        if (status != OK) {
+            throw new MetricsException(status, nativeLastError());
+        }
+    }
+
+    private static native long nativeInit(String appName,
+                                          String appVersion,
+                                          String appUpdateChannel,
+                                          String appPlatform,
+                                          String locale,
+                                          String device,
+                                          String arch,
+                                          String os,
+                                          String osVersion,
+                                          String storageDir);
+    private static native int nativeRecordEvent(long handle, String category, String action,
+                                                String label, long value);
+    private static native int nativeRecordUrgentEvent(long handle, String category, String action,
+                                                      String label, long value);
+    private static native int nativeRecordFloatingPointEvent(long handle, String category,
+                                                             String action, String label,
+                                                             double value);
+    private static native int nativeSetUploadEnabled(long handle, boolean enabled);
+    private static native int nativeFlush(long handle);
+    private static native int nativeShutdown(long handle);
+    private static native String nativeLastError();
+}
@@ -0,0 +1,17 @@
+package org.mozilla.metrics;
+
+/** A call of the metrics library failed. */
+public class MetricsException extends RuntimeException {
+
+    private final int status;
+
+    public MetricsException(int status, String message) {
+        super(message);
+        this.status = status;
+    }
+
+    /** One of the status constants of MetricsController. */
+    public int getStatus() {
+        return status;
+    }
+}
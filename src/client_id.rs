@@ -0,0 +1,306 @@
+extern crate serde_json;
+extern crate uuid;
+
+use config::Config;
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::serde_json::Value;
+use self::serde_json::builder::ObjectBuilder;
+use self::uuid::Uuid;
+use storage::Storage;
+use std::fs::File;
+use std::io::{Read, Write};
+
+// The client id sent with every hit.  It is kept in cid.dat:
+//
+//     {"cid": "9eccb690-93aa-4513-835a-9a4f0f0e2a71", "created": 1464215817, "injected": false}
+//
+// A random UUID is generated the first time.  The application can reset it
+// (e.g. on a factory reset) or replace it with its own stable device id
+// ("injected").  Generated ids can be rotated on a schedule for privacy by
+// setting "clientIdRotation" (in seconds) in metricsconfig.json; injected ids
+// are never rotated.  A missing or unreadable cid.dat is replaced with a new
+// id rather than stopping the library.  Files written by older versions,
+// which only have "cid", are still read.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+#[cfg(not(test))]
+const CLIENT_ID_FILE: &'static str = "cid.dat";
+// Google Analytics also sends the client id as a custom dimension, which is
+// limited to 150 bytes.
+const MAX_CLIENT_ID_BYTES: usize = 150;
+
+const KEY_CID: &'static str = "cid";
+const KEY_CREATED: &'static str = "created";
+const KEY_INJECTED: &'static str = "injected";
+#[cfg(not(test))]
+const KEY_ROTATION: &'static str = "clientIdRotation";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientId {
+    pub id: String,
+    /// When the id was created or injected, in seconds since the epoch.
+    pub created: i64,
+    /// Supplied by the application rather than generated.
+    pub injected: bool,
+}
+
+impl ClientId {
+    fn generate(now: i64) -> ClientId {
+        ClientId {
+            id: Uuid::new_v4().to_hyphenated_string(),
+            created: now,
+            injected: false,
+        }
+    }
+
+    fn from_json(json: &str, now: i64) -> Option<ClientId> {
+        let value: Value = match serde_json::from_str(json) {
+            Ok(value) => value,
+            Err(_) => return None,
+        };
+        let id = match value.find(KEY_CID).and_then(|cid| cid.as_str()) {
+            Some(id) if !id.is_empty() => id.to_string(),
+            _ => return None,
+        };
+        Some(ClientId {
+            id: id,
+            created: value.find(KEY_CREATED).and_then(|c| c.as_i64()).unwrap_or(now),
+            injected: value.find(KEY_INJECTED).and_then(|i| i.as_bool()).unwrap_or(false),
+        })
+    }
+
+    fn to_json(&self) -> Result<String, String> {
+        let value = ObjectBuilder::new()
+            .insert(KEY_CID, &self.id)
+            .insert(KEY_CREATED, self.created)
+            .insert(KEY_INJECTED, self.injected)
+            .build();
+        serde_json::to_string(&value).map_err(|e| e.to_string())
+    }
+}
+
+pub struct ClientIdStore {
+    // None for an id that is not persisted.
+    file_name: Option<String>,
+    current: ClientId,
+    // Seconds after which a generated id is replaced.
+    rotation: Option<i64>,
+}
+
+impl ClientIdStore {
+    /// Reads the client id from `file_name`, creating a new one if the file
+    /// is missing or unreadable.
+    pub fn open(file_name: &str, rotation: Option<i64>, now: i64) -> ClientIdStore {
+        let current = match read_file(file_name) {
+            None => None,
+            Some(json) => {
+                let cid = ClientId::from_json(&json, now);
+                if cid.is_none() {
+                    logger().log(LogLevelFilter::Error,
+                                 format!("{} is corrupt, creating a new client id", file_name)
+                                     .as_str());
+                }
+                cid
+            }
+        };
+        let mut store = ClientIdStore {
+            file_name: Some(file_name.to_string()),
+            current: current.clone().unwrap_or(ClientId::generate(now)),
+            rotation: rotation,
+        };
+        if current.is_none() {
+            store.save();
+        }
+        store
+    }
+
+    /// A client id that is kept in memory only.
+    pub fn in_memory(id: &str, now: i64) -> ClientIdStore {
+        ClientIdStore {
+            file_name: None,
+            current: ClientId {
+                id: id.to_string(),
+                created: now,
+                injected: true,
+            },
+            rotation: None,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.current.id
+    }
+
+    pub fn client_id(&self) -> &ClientId {
+        &self.current
+    }
+
+    /// Replaces the client id with a new random one.  Returns the new id.
+    pub fn reset(&mut self, now: i64) -> String {
+        self.current = ClientId::generate(now);
+        self.save();
+        self.current.id.clone()
+    }
+
+    /// Uses `id`, e.g. a stable device id, as the client id from now on.
+    pub fn inject(&mut self, id: &str, now: i64) -> Result<(), String> {
+        if id.is_empty() {
+            return Err("the client id is empty".to_string());
+        }
+        if id.len() > MAX_CLIENT_ID_BYTES {
+            return Err(format!("the client id is {} bytes long, the limit is {}",
+                               id.len(),
+                               MAX_CLIENT_ID_BYTES));
+        }
+        if id.chars().any(|c| c.is_control()) {
+            return Err("the client id contains control characters".to_string());
+        }
+        self.current = ClientId {
+            id: id.to_string(),
+            created: now,
+            injected: true,
+        };
+        self.save();
+        Ok(())
+    }
+
+    /// Replaces a generated id that is older than the rotation period.
+    /// Returns true if the id changed.
+    pub fn rotate_if_due(&mut self, now: i64) -> bool {
+        match self.rotation {
+            Some(rotation) if !self.current.injected && now - self.current.created >= rotation => {
+                logger().log(LogLevelFilter::Info, "Rotating the client id");
+                self.reset(now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn save(&self) {
+        let file_name = match self.file_name {
+            Some(ref file_name) => file_name,
+            None => return,
+        };
+        let written = self.current
+            .to_json()
+            .and_then(|json| {
+                File::create(file_name)
+                    .and_then(|mut file| file.write_all(json.as_bytes()))
+                    .map_err(|e| e.to_string())
+            });
+        if let Err(e) = written {
+            // The id still works for this run; a new one is created next time.
+            logger().log(LogLevelFilter::Error,
+                         format!("Could not write {}: {}", file_name, e).as_str());
+        }
+    }
+}
+
+fn read_file(file_name: &str) -> Option<String> {
+    let mut json = String::new();
+    match File::open(file_name) {
+        Ok(mut file) => {
+            match file.read_to_string(&mut json) {
+                Ok(_) => Some(json),
+                // Treated as corrupt.
+                Err(_) => Some(String::new()),
+            }
+        }
+        Err(_) => None,
+    }
+}
+
+/// The client id in cid.dat, rotated as configured in metricsconfig.json.
+#[cfg(not(test))]
+pub fn open_default(storage: &Storage, cfg: &mut Config, now: i64) -> ClientIdStore {
+    ClientIdStore::open(&storage.path(CLIENT_ID_FILE), rotation_from_config(cfg), now)
+}
+
+#[cfg(test)]
+pub fn open_default(_storage: &Storage, _cfg: &mut Config, now: i64) -> ClientIdStore {
+    ClientIdStore::in_memory("9eccb690-93aa-4513-835a-9a4f0f0e2a71", now)
+}
+
+/// The rotation period ("clientIdRotation"), if any.
+#[cfg(not(test))]
+fn rotation_from_config(cfg: &mut Config) -> Option<i64> {
+    match cfg.get(KEY_ROTATION) {
+        None => None,
+        Some(value) => {
+            match value.as_u64() {
+                Some(secs) if secs > 0 => Some(secs as i64),
+                _ => {
+                    logger().log(LogLevelFilter::Error,
+                                 "\"clientIdRotation\" must be a positive number of seconds");
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! client_id_store {
+    before_each {
+        use std::fs;
+
+        let file = "test_client_id.dat";
+        let _ = fs::remove_file(file);
+        let now = 1464215817;
+    }
+
+    it "should keep the same id across loads" {
+        let id = ClientIdStore::open(file, None, now).id().to_string();
+        assert_eq!(ClientIdStore::open(file, None, now + 10).id(), id);
+        let _ = fs::remove_file(file);
+    }
+
+    it "should read the old format" {
+        File::create(file).unwrap().write_all(b"{\"cid\":\"old-client\"}").unwrap();
+        let store = ClientIdStore::open(file, None, now);
+        assert_eq!(store.id(), "old-client");
+        assert_eq!(store.client_id().injected, false);
+        let _ = fs::remove_file(file);
+    }
+
+    it "should replace a corrupt file instead of panicking" {
+        File::create(file).unwrap().write_all(b"{\"something\": 1}").unwrap();
+        let id = ClientIdStore::open(file, None, now).id().to_string();
+        assert!(!id.is_empty());
+        assert_eq!(ClientIdStore::open(file, None, now).id(), id);
+        let _ = fs::remove_file(file);
+    }
+
+    it "should reset the id" {
+        let mut store = ClientIdStore::open(file, None, now);
+        let old = store.id().to_string();
+        let new = store.reset(now);
+        assert!(old != new);
+        assert_eq!(ClientIdStore::open(file, None, now).id(), new);
+        let _ = fs::remove_file(file);
+    }
+
+    it "should rotate generated ids but not injected ones" {
+        let mut store = ClientIdStore::open(file, Some(100), now);
+        assert!(!store.rotate_if_due(now + 99));
+        assert!(store.rotate_if_due(now + 100));
+
+        store.inject("device-1234", now).unwrap();
+        assert!(!store.rotate_if_due(now + 1000));
+        assert_eq!(ClientIdStore::open(file, Some(100), now + 1000).id(), "device-1234");
+        let _ = fs::remove_file(file);
+    }
+
+    it "should reject an invalid injected id" {
+        let mut store = ClientIdStore::in_memory("client", now);
+        assert!(store.inject("", now).is_err());
+        assert!(store.inject("a\nb", now).is_err());
+        assert_eq!(store.id(), "client");
+    }
+}
@@ -0,0 +1,148 @@
+extern crate time;
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The source of time for the library's scheduling and timestamps.  The
+// controller uses the system clock unless it is given another one; tests use
+// a `ManualClock`, which only moves when it is advanced, so that save and
+// send cycles can be driven without waiting.
+
+/// A source of wall-clock and monotonic time.
+pub trait Clock: Send + Sync {
+    /// Wall-clock time in milliseconds since the epoch.
+    fn now_millis(&self) -> i64;
+
+    /// Monotonic time, for measuring intervals.
+    fn instant(&self) -> Instant;
+
+    /// Wall-clock time in seconds since the epoch.
+    fn now_secs(&self) -> i64 {
+        self.now_millis() / 1000
+    }
+
+    /// How long to block before `deadline` (in seconds since the epoch) is
+    /// reached, or None to block until `on_advance` wakes the caller.
+    fn wait_until(&self, deadline: i64) -> Option<Duration>;
+
+    /// Registers `wake` to be called whenever the clock is moved forward.
+    /// Only clocks that do not advance by themselves call it.
+    fn on_advance(&self, _wake: Box<Fn() + Send>) {}
+}
+
+/// The system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        let now = time::get_time();
+        now.sec * 1000 + (now.nsec / 1000000) as i64
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wait_until(&self, deadline: i64) -> Option<Duration> {
+        let remaining = deadline - self.now_secs();
+        Some(Duration::from_secs(if remaining > 0 { remaining as u64 } else { 0 }))
+    }
+}
+
+/// A clock that stands still until it is advanced.
+pub struct ManualClock {
+    start: Instant,
+    // Milliseconds since the epoch and since `start`.
+    state: Mutex<(i64, u64)>,
+    wakers: Mutex<Vec<Box<Fn() + Send>>>,
+}
+
+impl ManualClock {
+    /// A clock showing `millis` since the epoch.
+    pub fn new(millis: i64) -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            state: Mutex::new((millis, 0)),
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Moves the clock forward by `duration` and wakes whoever waits on it.
+    pub fn advance(&self, duration: Duration) {
+        let millis = duration.as_secs() * 1000 + (duration.subsec_nanos() / 1000000) as u64;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.0 += millis as i64;
+            state.1 += millis;
+        }
+        for wake in self.wakers.lock().unwrap().iter() {
+            wake();
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_millis(&self) -> i64 {
+        self.state.lock().unwrap().0
+    }
+
+    fn instant(&self) -> Instant {
+        self.start + Duration::from_millis(self.state.lock().unwrap().1)
+    }
+
+    fn wait_until(&self, deadline: i64) -> Option<Duration> {
+        if self.now_secs() >= deadline {
+            Some(Duration::from_secs(0))
+        } else {
+            None
+        }
+    }
+
+    fn on_advance(&self, wake: Box<Fn() + Send>) {
+        self.wakers.lock().unwrap().push(wake);
+    }
+}
+
+/// The clock time as an `Event::timestamp`, e.g. "2016-05-25 22:36:57" (UTC).
+pub fn format_timestamp(millis: i64) -> String {
+    let ts = time::at_utc(time::Timespec::new(millis / 1000, 0));
+    format!("{0:4}-{1:02}-{2:02} {3:02}:{4:02}:{5:02}",
+            ts.tm_year + 1900,
+            ts.tm_mon + 1,
+            ts.tm_mday,
+            ts.tm_hour,
+            ts.tm_min,
+            ts.tm_sec)
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! manual_clock {
+    before_each {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let clock = ManualClock::new(1464215817000);
+    }
+
+    it "should only move when advanced" {
+        let instant = clock.instant();
+        assert_eq!(clock.now_secs(), 1464215817);
+        assert_eq!(format_timestamp(clock.now_millis()), "2016-05-25 22:36:57");
+        clock.advance(Duration::from_millis(61500));
+        assert_eq!(clock.now_millis(), 1464215878500);
+        assert_eq!(clock.instant().duration_since(instant), Duration::from_millis(61500));
+    }
+
+    it "should wake waiters when advanced" {
+        let wakes = Arc::new(AtomicUsize::new(0));
+        let counter = wakes.clone();
+        clock.on_advance(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        assert_eq!(clock.wait_until(1464215820), None);
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(wakes.load(Ordering::SeqCst), 1);
+        assert_eq!(clock.wait_until(1464215820), Some(Duration::from_secs(0)));
+    }
+}
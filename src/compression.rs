@@ -0,0 +1,96 @@
+extern crate flate2;
+extern crate hyper;
+
+use self::flate2::write::{GzEncoder, ZlibEncoder};
+use self::hyper::header::Encoding;
+use std::io;
+use std::io::Write;
+
+// Optional compression of request bodies.  Batch bodies repeat the same
+// analytics property, client id and EventInfo fields on every hit, so they
+// compress well.  Only use this with backends that accept a compressed body;
+// Google Analytics does not.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Deflate,
+}
+
+impl Compression {
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "none" => Some(Compression::None),
+            "gzip" => Some(Compression::Gzip),
+            "deflate" => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+
+    /// The `Content-Encoding` to send with a body compressed this way.
+    pub fn content_encoding(&self) -> Option<Encoding> {
+        match *self {
+            Compression::None => None,
+            Compression::Gzip => Some(Encoding::Gzip),
+            Compression::Deflate => Some(Encoding::Deflate),
+        }
+    }
+
+    pub fn compress(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            Compression::None => Ok(body.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::Default);
+                try!(encoder.write_all(body));
+                encoder.finish()
+            }
+            Compression::Deflate => {
+                // HTTP's "deflate" coding is the zlib format.
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+                try!(encoder.write_all(body));
+                encoder.finish()
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! body_compression {
+    before_each {
+        let body = "v=1&t=event&tid=UA-1&cid=9eccb690-93aa-4513-835a-9a4f0f0e2a71&ec=category\n\
+                    v=1&t=event&tid=UA-1&cid=9eccb690-93aa-4513-835a-9a4f0f0e2a71&ec=category\n\
+                    v=1&t=event&tid=UA-1&cid=9eccb690-93aa-4513-835a-9a4f0f0e2a71&ec=category\n";
+    }
+
+    it "should parse the configured names" {
+        assert_eq!(Compression::from_name("gzip"), Some(Compression::Gzip));
+        assert_eq!(Compression::from_name("deflate"), Some(Compression::Deflate));
+        assert_eq!(Compression::from_name("none"), Some(Compression::None));
+        assert_eq!(Compression::from_name("brotli"), None);
+    }
+
+    it "should leave the body alone without compression" {
+        let compressed = Compression::None.compress(body.as_bytes()).unwrap();
+        assert_eq!(compressed, body.as_bytes());
+        assert!(Compression::None.content_encoding().is_none());
+    }
+
+    it "should produce a smaller gzip body" {
+        let compressed = Compression::Gzip.compress(body.as_bytes()).unwrap();
+        assert_eq!(&compressed[..2], &[0x1f, 0x8b]);
+        assert!(compressed.len() < body.len());
+    }
+
+    it "should produce a deflate body that decompresses to the original" {
+        use std::io::Read;
+        use compression::flate2::read::ZlibDecoder;
+
+        let compressed = Compression::Deflate.compress(body.as_bytes()).unwrap();
+        let mut decoder = ZlibDecoder::new(&compressed[..]);
+        let mut decompressed = String::new();
+        decoder.read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, body);
+    }
+}
@@ -4,6 +4,7 @@ use log::LogLevelFilter;
 use logger::MetricsLoggerFactory;
 use logger::MetricsLogger;
 use self::serde_json::Value;
+use storage::Storage;
 use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
@@ -13,11 +14,14 @@ use std::collections::BTreeMap;
 
 // This is the config file that reads all the json from metricsconfig.json.  We can initially use
 // an environment variable to locate this file or can be passed in.
-// The worker thread and the app thread will both read from this file.
+// A controller reads it once, with `Config::load`, and hands the parsed config to each module it
+// sets up (the events, the worker's schedule, sinks and network, ...), which reads its own keys
+// with `from_config`.
 
 #[allow(non_upper_case_globals)]
 const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
 
+#[derive(Clone)]
 pub struct Config {
     parsed_json: Option<BTreeMap<String, Value>>,
 }
@@ -27,6 +31,24 @@ impl Config {
         Config { parsed_json: None }
     }
 
+    /// The metricsconfig.json in `storage`.  Without one, every setting has its default.
+    #[cfg(not(test))]
+    pub fn load(storage: &Storage) -> Config {
+        let mut cfg = Config::new();
+        if !cfg.init(&storage.config_file()) {
+            cfg.parse_json("{}".to_string());
+        }
+        cfg
+    }
+
+    /// Tests do not read the metricsconfig.json in the working directory.
+    #[cfg(test)]
+    pub fn load(_storage: &Storage) -> Config {
+        let mut cfg = Config::new();
+        cfg.parse_json("{}".to_string());
+        cfg
+    }
+
     pub fn create_and_write_json(&mut self, file_name: &str, json: &str) {
         logger().log(LogLevelFilter::Debug,
                      format!("file: {}", file_name).as_str());
@@ -72,7 +94,7 @@ impl Config {
         true
     }
 
-    fn parse_json(&mut self, json_string: String) {
+    pub fn parse_json(&mut self, json_string: String) {
         // It's ok to unwrap here because if something is wrong here, we want to
         // know and expose the bug.
         let data: Value = serde_json::from_str(&json_string).unwrap();
@@ -92,6 +114,13 @@ impl Config {
         }
     }
 
+    pub fn keys(&self) -> Vec<String> {
+        match self.parsed_json {
+            Some(ref parsed_json) => parsed_json.keys().cloned().collect(),
+            None => panic!("Data not parsed"),
+        }
+    }
+
     pub fn get_string(&mut self, key: &str) -> String {
         if let Some(ref mut parsed_json) = self.parsed_json {
             let val = parsed_json.get(key);
@@ -131,6 +160,37 @@ impl Config {
 }
 
 
+// Helpers for reading optional members of a nested JSON object, e.g. one
+// entry of the "sinks" array.  A member of the wrong type is an error.
+
+pub fn get_optional_string(value: &Value, key: &str) -> Result<Option<String>, String> {
+    match value.find(key) {
+        None => Ok(None),
+        Some(&Value::String(ref s)) => Ok(Some(s.clone())),
+        Some(_) => Err(format!("expected a string for {}", key)),
+    }
+}
+
+pub fn get_optional_u64(value: &Value, key: &str) -> Result<Option<u64>, String> {
+    match value.find(key) {
+        None => Ok(None),
+        Some(v) => {
+            match v.as_u64() {
+                Some(n) => Ok(Some(n)),
+                None => Err(format!("expected a number for {}", key)),
+            }
+        }
+    }
+}
+
+pub fn get_optional_bool(value: &Value, key: &str) -> Result<Option<bool>, String> {
+    match value.find(key) {
+        None => Ok(None),
+        Some(&Value::Bool(b)) => Ok(Some(b)),
+        Some(_) => Err(format!("expected true or false for {}", key)),
+    }
+}
+
 #[cfg(not(feature = "integration"))]
 #[cfg(test)]
 describe! config_file_found {
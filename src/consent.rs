@@ -0,0 +1,83 @@
+extern crate serde_json;
+
+use config::Config;
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::serde_json::Value;
+use std::fs::File;
+use std::io::Read;
+
+// The user's upload preference, kept in upload.dat next to cid.dat so that it
+// survives a restart:
+//
+//     {"uploadEnabled": false}
+//
+// Upload is enabled until the application turns it off.  If the file exists
+// but cannot be read, upload is treated as disabled: the user may have opted
+// out, and sending data they asked us not to send is worse than losing it.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+pub const UPLOAD_FILE: &'static str = "upload.dat";
+const KEY_UPLOAD_ENABLED: &'static str = "uploadEnabled";
+
+pub fn read_upload_enabled(file_name: &str) -> bool {
+    let mut json = String::new();
+    match File::open(file_name) {
+        Ok(mut file) => {
+            if let Err(e) = file.read_to_string(&mut json) {
+                logger().log(LogLevelFilter::Error,
+                             format!("Could not read {}, upload disabled: {}", file_name, e)
+                                 .as_str());
+                return false;
+            }
+        }
+        // Never set.
+        Err(_) => return true,
+    }
+    let enabled = serde_json::from_str::<Value>(&json)
+        .ok()
+        .and_then(|value| value.find(KEY_UPLOAD_ENABLED).and_then(|v| v.as_bool()));
+    match enabled {
+        Some(enabled) => enabled,
+        None => {
+            logger().log(LogLevelFilter::Error,
+                         format!("{} is corrupt, upload disabled", file_name).as_str());
+            false
+        }
+    }
+}
+
+pub fn write_upload_enabled(file_name: &str, enabled: bool) {
+    let json = format!("{{\"{0}\":{1}}}", KEY_UPLOAD_ENABLED, enabled);
+    Config::new().create_and_write_json(file_name, &json);
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! upload_preference {
+    before_each {
+        use std::fs;
+        use std::io::Write;
+
+        let file = "test_upload_preference.dat";
+        let _ = fs::remove_file(file);
+    }
+
+    it "should be enabled until it is turned off" {
+        assert!(read_upload_enabled(file));
+        write_upload_enabled(file, false);
+        assert!(!read_upload_enabled(file));
+        write_upload_enabled(file, true);
+        assert!(read_upload_enabled(file));
+        let _ = fs::remove_file(file);
+    }
+
+    it "should be disabled if the file is corrupt" {
+        File::create(file).unwrap().write_all(b"{\"uploadEnab").unwrap();
+        assert!(!read_upload_enabled(file));
+        let _ = fs::remove_file(file);
+    }
+}
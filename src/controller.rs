@@ -1,9 +1,20 @@
-use metrics_worker::MetricsWorker;
-use events::Events;
+use metrics_worker::{Flusher, MetricsWorker};
+use clock::{Clock, SystemClock};
+use config::Config;
+use events::{Events, Priority};
 use log::LogLevelFilter;
 use logger::MetricsLoggerFactory;
 use logger::MetricsLogger;
+use network::NetworkPolicy;
+use observer::{WorkerEvent, WorkerObserver};
+use privacy::PrivacySettings;
+use scrubber::ScrubCallback;
+use stats::Stats;
+use std::fs;
+use storage::Storage;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use validation::{EventError, HitReport};
 
 #[allow(non_upper_case_globals)]
 // Shortcut to MetricsLoggerFactory function that gets the logger instance.
@@ -47,20 +58,41 @@ impl EventInfo {
     }
 }
 
+/// How a controller is set up, for `MetricsController::with_options`.  Controllers in the same
+/// process need a storage directory each, or they share their client id and saved events.
+pub struct ControllerOptions {
+    /// Where metricsconfig.json is read from and the library keeps its files.  None means the
+    /// current directory.  It is created if needed.
+    pub storage_dir: Option<String>,
+    /// Overrides the "analytics" property in metricsconfig.json.
+    pub analytics_property: Option<String>,
+    pub clock: Arc<Clock>,
+    /// Without a worker thread, as for `MetricsController::polled`.
+    pub polled: bool,
+}
+
+impl ControllerOptions {
+    pub fn new() -> ControllerOptions {
+        ControllerOptions {
+            storage_dir: None,
+            analytics_property: None,
+            clock: Arc::new(SystemClock),
+            polled: false,
+        }
+    }
+}
+
 pub struct AnalyticsProperty;
 
 impl AnalyticsProperty {
     #[cfg(not(test))]
-    pub fn get() -> String {
-        use config::Config;
+    pub fn get(cfg: &mut Config) -> String {
         const ANALYTICS_KEY: &'static str = "analytics";
 
-        let mut cfg = Config::new();
-        cfg.init("metricsconfig.json");
         cfg.get_string(ANALYTICS_KEY)
     }
     #[cfg(test)]
-    pub fn get() -> String {
+    pub fn get(_cfg: &mut Config) -> String {
         "test_analytics_property".to_string()
     }
 }
@@ -105,10 +137,60 @@ impl MetricsController {
                os: &str,
                os_version: &str)
                -> MetricsController {
-        logger().log(LogLevelFilter::Info, "Creating Controller");
+        MetricsController::with_clock(app_name,
+                                      app_version,
+                                      app_update_channel,
+                                      app_platform,
+                                      locale,
+                                      device,
+                                      arch,
+                                      os,
+                                      os_version,
+                                      Arc::new(SystemClock))
+    }
 
-        let analytics_property = AnalyticsProperty::get();
+    /// Like `new`, but takes its time from `clock`, which schedules saving and sending and
+    /// timestamps the events.  With a `ManualClock` a test can run the save and send cycles
+    /// without waiting for them.
+    pub fn with_clock(app_name: &str,
+                      app_version: &str,
+                      app_update_channel: &str,
+                      app_platform: &str,
+                      locale: &str,
+                      device: &str,
+                      arch: &str,
+                      os: &str,
+                      os_version: &str,
+                      clock: Arc<Clock>)
+                      -> MetricsController {
+        let event_info = EventInfo::new(locale,
+                                        os,
+                                        os_version,
+                                        device,
+                                        app_name,
+                                        app_version,
+                                        app_update_channel,
+                                        app_platform,
+                                        arch);
+        let mut options = ControllerOptions::new();
+        options.clock = clock;
+        MetricsController::with_options(event_info, options)
+    }
 
+    /// Like `with_clock`, but without a worker thread, for applications with their own event
+    /// loop.  Nothing is saved or sent until the application calls `poll` or `tick`, which
+    /// return when to call them again.
+    pub fn polled(app_name: &str,
+                  app_version: &str,
+                  app_update_channel: &str,
+                  app_platform: &str,
+                  locale: &str,
+                  device: &str,
+                  arch: &str,
+                  os: &str,
+                  os_version: &str,
+                  clock: Arc<Clock>)
+                  -> MetricsController {
         let event_info = EventInfo::new(locale,
                                         os,
                                         os_version,
@@ -118,15 +200,65 @@ impl MetricsController {
                                         app_update_channel,
                                         app_platform,
                                         arch);
-        let events = Arc::new(Mutex::new(Events::new(event_info, analytics_property)));
+        let mut options = ControllerOptions::new();
+        options.clock = clock;
+        options.polled = true;
+        MetricsController::with_options(event_info, options)
+    }
+
+    /// Constructs a controller as set up by `options`.  Each controller has its own client id,
+    /// events, worker and files, so several components in one process can each have one, with
+    /// their own storage directory.
+    pub fn with_options(event_info: EventInfo, options: ControllerOptions) -> MetricsController {
+        logger().log(LogLevelFilter::Info, "Creating Controller");
+
+        let storage = match options.storage_dir {
+            Some(dir) => {
+                if let Err(e) = fs::create_dir_all(&dir) {
+                    logger().log(LogLevelFilter::Error,
+                                 format!("Could not create {}: {}", dir, e).as_str());
+                }
+                Storage::new(dir)
+            }
+            None => Storage::current(),
+        };
+        // Read once, for the events and the worker.
+        let mut cfg = Config::load(&storage);
+        let analytics_property = match options.analytics_property {
+            Some(property) => property,
+            None => AnalyticsProperty::get(&mut cfg),
+        };
+        let clock = options.clock;
+
+        let events = Events::new(event_info, analytics_property, &storage, &mut cfg, clock.clone());
+        let events = Arc::new(Mutex::new(events));
 
         MetricsController {
             events: events.clone(),
-            mw: MetricsWorker::new(events),
+            mw: if options.polled {
+                MetricsWorker::polled(events, &mut cfg, clock)
+            } else {
+                MetricsWorker::new(events, &mut cfg, clock)
+            },
         }
 
     }
 
+    /// Saves and sends what is due at `now` (seconds since the epoch), for a controller created
+    /// with `polled`.  Returns when to call it again, or None once collecting has stopped.
+    /// Sending happens on the calling thread, so `poll` blocks while a send is due: each sink
+    /// stops at its first failed batch, which takes at most "sendDeadline" seconds (default
+    /// 300) plus the connect, read and write timeouts of its last attempt.  Name lookups have
+    /// no timeout.
+    pub fn poll(&mut self, now: i64) -> Option<i64> {
+        self.mw.poll(now)
+    }
+
+    /// `poll` at the current time of the controller's clock.
+    pub fn tick(&mut self) -> Option<i64> {
+        self.mw.tick()
+    }
+
     // TODO determine if we still want this function
     /// This function starts the metrics service, which also starts the
     /// worker thread needed to operate the metrics service.  The worker thread
@@ -148,15 +280,56 @@ impl MetricsController {
 
     // TODO determine if we still want this function
     /// This function stops the metrics service and deletes metrics data that has been collected
-    /// but not sent to the server.
+    /// but not sent to the server, both in memory and on disk.  Events recorded afterwards
+    /// are ignored.
     pub fn stop_collecting(&mut self) {
-        // TODO:  Eventually, this API will need to also delete the Histograms
-        // from memory and delete the ones on disk.
+        self.events.lock().unwrap().stop_collecting();
+        self.mw.delete_data(false);
         self.mw.quit();
     }
 
-    /// Constructs and records an event. The recorded events are sent to the server based on an internal
-    /// schedule (when twenty events are recorded). Currently, this schedule is not configurable.
+    /// Sends everything recorded so far and waits until it has been sent, e.g. before the
+    /// application exits.  The network policy still applies.  Returns false if something
+    /// could not be sent; it is kept for the next send.
+    pub fn flush(&self) -> bool {
+        self.mw.flush()
+    }
+
+    /// A handle that flushes this controller, for applications that share the controller
+    /// behind a lock: they can release the lock before the flush waits for the worker.
+    pub fn flusher(&self) -> Flusher {
+        self.mw.flusher()
+    }
+
+    /// Stops the worker and waits for it to finish.  Events recorded afterwards are kept but
+    /// not sent.  Call `flush` first to send what has been recorded.
+    pub fn shutdown(&mut self) {
+        self.mw.shutdown();
+    }
+
+    /// Turns uploading on or off.  The setting is kept in `upload.dat`, next to `cid.dat`,
+    /// and applies across restarts.
+    ///
+    /// While upload is disabled, recorded events are ignored.  Disabling it deletes the
+    /// events that have not been sent yet, in memory and on disk.  If `sendDeletionRequest`
+    /// is set in metricsconfig.json, a single `metrics-controller`/`deletion-request` event
+    /// is then sent to every backend.
+    pub fn set_upload_enabled(&mut self, enabled: bool) {
+        let changed = self.events.lock().unwrap().set_upload_enabled(enabled);
+        if !enabled {
+            // Also delete batches already handed to the sinks.  The deletion
+            // request is only sent when the user opts out, not every time.
+            self.mw.delete_data(changed);
+        }
+    }
+
+    /// Whether upload is enabled.  See `set_upload_enabled`.
+    pub fn upload_enabled(&self) -> bool {
+        self.events.lock().unwrap().upload_enabled()
+    }
+
+    /// Constructs and records an event. The recorded events are sent to the server every
+    /// "sendInterval" seconds, or "flushDelay" seconds after twenty events are waiting.
     ///
     /// Params:
     ///
@@ -168,24 +341,58 @@ impl MetricsController {
     ///
     /// **event_value** - Numeric value of the metric.
     ///
+    /// The category, action and label are checked against the Measurement Protocol
+    /// limits (150, 500 and 500 bytes).  Longer values are truncated with a warning,
+    /// or the event is rejected if "oversizeFields" is "reject" in metricsconfig.json.
+    ///
     /// Returns:
     ///
-    /// **true** - Success
+    /// **Ok** - The event was queued, or was dropped by the "sampling" or "rateLimits" settings
+    /// or a full queue; `get_stats` counts those
     ///
-    /// **false** - Unable to record the event
+    /// **Err** - Unable to record the event; the error says why
     pub fn record_event(&mut self,
                         event_category: &str,
                         event_action: &str,
                         event_label: &str,
                         event_value: u64)
-                        -> bool {
+                        -> Result<(), EventError> {
+        self.record_event_with_priority(event_category,
+                                        event_action,
+                                        event_label,
+                                        event_value,
+                                        Priority::Normal)
+    }
+
+    /// Like `record_event`, but with `Priority::Urgent` the event is sent right away, e.g. for
+    /// a crash, a security alert or a first-run install, without the events that are waiting
+    /// for the next batch.  Up to twenty urgent events are sent together; past that they wait
+    /// for the next batch.  Urgent events are saved to disk before this returns, and are sent
+    /// ahead of any batches left over from earlier sends.  The network policy still applies.
+    pub fn record_event_with_priority(&mut self,
+                                      event_category: &str,
+                                      event_action: &str,
+                                      event_label: &str,
+                                      event_value: u64,
+                                      priority: Priority)
+                                      -> Result<(), EventError> {
         let mut events = self.events.lock().unwrap();
-        events.insert_event(event_category, event_action, event_label, event_value)
+        let result = events.insert_event_with_priority(event_category,
+                                                       event_action,
+                                                       event_label,
+                                                       event_value,
+                                                       priority);
+        if priority == Priority::Urgent && events.urgent_len() > 0 {
+            self.mw.urgent();
+        }
+        if events.is_time_to_send() {
+            self.mw.queue_full();
+        }
+        result
     }
 
-   /// Constructs and records an event with a floating point value. The recorded events are sent to the
-   /// server based on an internal schedule (when twenty events are recorded). Currently, this schedule
-   /// is not configurable.
+   /// Constructs and records an event with a floating point value. The recorded events are sent as
+   /// for `record_event`.
    ///
    /// Params:
    ///
@@ -197,19 +404,130 @@ impl MetricsController {
   ///
   /// **event_value** - Numeric (floating point) value of the metric.
   ///
+  /// The fields are checked as for `record_event`.
+  ///
   /// Returns:
   ///
-  /// **true** - Success
+  /// **Ok** - The event was queued, or was dropped as for `record_event`
   ///
-  /// **false** - Unable to record the event
+  /// **Err** - Unable to record the event; the error says why
   pub fn record_floating_point_event(&mut self,
                                      event_category: &str,
                                      event_action: &str,
                                      event_label: &str,
                                      event_value: f64)
-                                     -> bool {
+                                     -> Result<(), EventError> {
       let mut events = self.events.lock().unwrap();
-      events.insert_floating_point_event(event_category, event_action, event_label, event_value)
+      let result = events.insert_floating_point_event(event_category, event_action, event_label, event_value);
+      if events.is_time_to_send() {
+          self.mw.queue_full();
+      }
+      result
   }
 
+    /// Returns the client id sent with every hit.
+    pub fn client_id(&self) -> String {
+        self.events.lock().unwrap().client_id().to_string()
+    }
+
+    /// Replaces the client id with a new random one, e.g. on a factory reset, and returns it.
+    /// Events recorded afterwards cannot be linked to the old id; events already recorded are
+    /// still sent under the old one.
+    pub fn reset_client_id(&mut self) -> String {
+        self.events.lock().unwrap().reset_client_id()
+    }
+
+    /// Uses the application's own stable device id as the client id.  It is kept in `cid.dat`
+    /// and is never rotated.  Fails if the id is empty, longer than 150 bytes or contains
+    /// control characters.
+    pub fn set_client_id(&mut self, client_id: &str) -> Result<(), String> {
+        self.events.lock().unwrap().set_client_id(client_id)
+    }
+
+    /// Starts a Google Analytics session, ending the current one.  The first hit of the
+    /// session is sent with `sc=start`, and every hit carries the session id (cd9) and its
+    /// sequence number in the session (cd10).  After "sessionTimeout" seconds (default 1800)
+    /// without events the session ends, and the next event starts a new one.
+    pub fn start_session(&mut self) {
+        self.events.lock().unwrap().start_session();
+    }
+
+    /// Ends the current session.  A `metrics-controller`/`session-duration` event with the
+    /// duration in seconds is sent with `sc=end`.  Events recorded afterwards are not part of
+    /// a session until `start_session` is called again.
+    pub fn end_session(&mut self) {
+        self.events.lock().unwrap().end_session();
+    }
+
+    /// Returns the privacy parameters (`aip`, `npa` and `ds`) sent with the events recorded
+    /// from now on.
+    pub fn privacy(&self) -> PrivacySettings {
+        self.events.lock().unwrap().privacy().clone()
+    }
+
+    /// Sets the privacy parameters sent with the events recorded from now on, replacing the
+    /// defaults for the update channel from "privacy" in metricsconfig.json.  Events already
+    /// recorded are sent with the parameters they were recorded with, even if they are still
+    /// queued.  The setting is not kept across restarts.
+    pub fn set_privacy(&mut self, privacy: PrivacySettings) {
+        self.events.lock().unwrap().set_privacy(privacy);
+    }
+
+    /// Installs a callback that removes personal data from the category, action and label of
+    /// every recorded event, after the detectors and patterns configured under "scrubbing" in
+    /// metricsconfig.json.  `None` removes it.
+    pub fn set_scrubber(&mut self, callback: Option<ScrubCallback>) {
+        self.events.lock().unwrap().set_scrub_callback(callback);
+    }
+
+    /// Replaces the policy that decides whether the worker may send on the current link,
+    /// e.g. with a closure returning `NetworkState::Metered` while the device is on LTE.
+    /// Sends are deferred until the policy returns `NetworkState::Unmetered`; call
+    /// `network_changed` when its answer may have changed.
+    pub fn set_network_policy(&mut self, policy: Box<NetworkPolicy>) {
+        self.mw.set_network_policy(policy);
+        self.mw.network_changed();
+    }
+
+    /// Marks the current link as metered, deferring sends until it is unmarked, whatever the
+    /// network policy says.
+    pub fn set_metered(&mut self, metered: bool) {
+        self.mw.set_metered(metered);
+        if !metered {
+            self.mw.network_changed();
+        }
+    }
+
+    /// Tells the worker the network changed, so that a deferred send is tried right away
+    /// rather than after "retryInterval" seconds.
+    pub fn network_changed(&self) {
+        self.mw.network_changed();
+    }
+
+    /// Returns a snapshot of the library's counters, e.g. how many bytes
+    /// request body compression has saved.
+    pub fn get_stats(&self) -> Stats {
+        self.events.lock().unwrap().stats().clone()
+    }
+
+    /// Returns the per-hit results collected in dry-run mode ("dryRun" in
+    /// metricsconfig.json) since the last call.  Invalid hits are also logged.
+    pub fn take_validation_reports(&self) -> Vec<HitReport> {
+        self.events.lock().unwrap().take_validation_reports()
+    }
+
+    /// Calls `observer` on the worker thread for each of its save and send
+    /// cycles.  It should return quickly.  It is called without the
+    /// controller's locks held, so it may record events, but it must not call
+    /// `flush` or `shutdown`, or wait for a thread that does: they wait for the
+    /// worker, which is waiting for the observer.
+    pub fn observe(&self, observer: WorkerObserver) {
+        self.mw.observe(observer);
+    }
+
+    /// Returns a receiver for the worker's save and send cycles from now on.
+    pub fn worker_events(&self) -> Receiver<WorkerEvent> {
+        self.mw.events()
+    }
+
 }
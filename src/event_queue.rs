@@ -0,0 +1,493 @@
+extern crate serde_json;
+
+use config::{Config, get_optional_string, get_optional_u64};
+use events::Event;
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::serde_json::Value;
+use storage::Storage;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::mem;
+use std::path::Path;
+
+// The queue of recorded events waiting to be sent.  It is bounded so that a
+// device that cannot reach the server for a long time does not run out of
+// memory.  The limits are read from a "queue" object in metricsconfig.json:
+//
+//     "queue": {
+//         "maxEvents": 1000,
+//         "maxBytes": 262144,
+//         "overflow": "spill",
+//         "spillFile": "events_spill.dat",
+//         "maxSpilledEvents": 20000
+//     }
+//
+// When a limit is reached, "dropOldest" (the default) drops the oldest queued
+// event, "dropNewest" drops the event being recorded and "spill" moves the
+// oldest events to disk, from which they are sent first.  Spilled events are
+// written in chunks, each to its own file named after "spillFile" with a
+// sequence number appended, so that spilling and sending only touch one small
+// file.  The chunks are bounded by "maxSpilledEvents"; past it the oldest
+// spilled events are dropped.  Every dropped event is counted.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+const DEFAULT_MAX_EVENTS: usize = 10000;
+const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_SPILLED_EVENTS: usize = 100000;
+const DEFAULT_SPILL_FILE: &'static str = "events_spill.dat";
+// Events are spilled this many at a time, each chunk to its own file.
+const SPILL_CHUNK: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+    Spill,
+}
+
+impl OverflowPolicy {
+    pub fn from_name(name: &str) -> Option<OverflowPolicy> {
+        match name {
+            "dropOldest" => Some(OverflowPolicy::DropOldest),
+            "dropNewest" => Some(OverflowPolicy::DropNewest),
+            "spill" => Some(OverflowPolicy::Spill),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueLimits {
+    pub max_events: usize,
+    pub max_bytes: usize,
+    pub overflow: OverflowPolicy,
+    pub spill_file: String,
+    pub max_spilled_events: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> QueueLimits {
+        QueueLimits {
+            max_events: DEFAULT_MAX_EVENTS,
+            max_bytes: DEFAULT_MAX_BYTES,
+            overflow: OverflowPolicy::DropOldest,
+            spill_file: DEFAULT_SPILL_FILE.to_string(),
+            max_spilled_events: DEFAULT_MAX_SPILLED_EVENTS,
+        }
+    }
+}
+
+impl QueueLimits {
+    pub fn from_config(cfg: &mut Config, storage: &Storage) -> QueueLimits {
+        let mut limits = match QueueLimits::from_value(cfg.get("queue").as_ref()) {
+            Ok(limits) => limits,
+            Err(e) => {
+                logger().log(LogLevelFilter::Error,
+                             format!("Invalid queue settings, using the defaults: {}", e).as_str());
+                QueueLimits::default()
+            }
+        };
+        // A relative spill file is kept in the storage directory.
+        limits.spill_file = storage.path(&limits.spill_file);
+        limits
+    }
+
+    pub fn from_value(value: Option<&Value>) -> Result<QueueLimits, String> {
+        let mut limits = QueueLimits::default();
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(limits),
+        };
+        if !value.is_object() {
+            return Err("\"queue\" must be an object".to_string());
+        }
+        if let Some(max_events) = try!(get_optional_u64(value, "maxEvents")) {
+            limits.max_events = max_events as usize;
+        }
+        if let Some(max_bytes) = try!(get_optional_u64(value, "maxBytes")) {
+            limits.max_bytes = max_bytes as usize;
+        }
+        if let Some(overflow) = try!(get_optional_string(value, "overflow")) {
+            limits.overflow = match OverflowPolicy::from_name(&overflow) {
+                Some(policy) => policy,
+                None => return Err(format!("unknown overflow policy {}", overflow)),
+            };
+        }
+        if let Some(spill_file) = try!(get_optional_string(value, "spillFile")) {
+            limits.spill_file = spill_file;
+        }
+        if let Some(max_spilled) = try!(get_optional_u64(value, "maxSpilledEvents")) {
+            limits.max_spilled_events = max_spilled as usize;
+        }
+        if limits.max_events == 0 {
+            return Err("maxEvents must be at least 1".to_string());
+        }
+        Ok(limits)
+    }
+}
+
+pub struct EventQueue {
+    events: VecDeque<Event>,
+    bytes: usize,
+    limits: QueueLimits,
+    // The spill files, oldest first.  Their events are older than the events
+    // in memory.
+    chunks: VecDeque<SpillChunk>,
+    next_chunk: u64,
+    // Number of events in the spill files.
+    spilled: usize,
+}
+
+// A spill file and the number of events in it.
+struct SpillChunk {
+    sequence: u64,
+    len: usize,
+}
+
+impl EventQueue {
+    pub fn new(limits: QueueLimits) -> EventQueue {
+        let mut queue = EventQueue {
+            events: VecDeque::new(),
+            bytes: 0,
+            limits: limits,
+            chunks: VecDeque::new(),
+            next_chunk: 0,
+            spilled: 0,
+        };
+        if queue.limits.overflow == OverflowPolicy::Spill {
+            queue.load_chunks();
+        }
+        queue
+    }
+
+    /// Number of queued events, including spilled ones.
+    pub fn len(&self) -> usize {
+        self.events.len() + self.spilled
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate memory used by the events held in memory.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Adds an event, applying the overflow policy if a limit is reached.
+    /// Returns the number of events dropped.
+    pub fn push_back(&mut self, event: Event) -> u64 {
+        let size = event_size(&event);
+        let mut dropped = 0;
+        while !self.events.is_empty() && self.over_limit(size) {
+            match self.limits.overflow {
+                OverflowPolicy::DropNewest => return 1,
+                OverflowPolicy::DropOldest => {
+                    self.pop_memory();
+                    dropped += 1;
+                }
+                OverflowPolicy::Spill => dropped += self.spill(),
+            }
+        }
+        if self.over_limit(size) {
+            // A single event larger than the byte budget.
+            return dropped + 1;
+        }
+        self.bytes += size;
+        self.events.push_back(event);
+        dropped
+    }
+
+    /// Removes every queued event, including the spill files.
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.bytes = 0;
+        while let Some(chunk) = self.chunks.pop_front() {
+            let _ = fs::remove_file(self.chunk_file(chunk.sequence));
+        }
+        self.spilled = 0;
+    }
+
+    /// Removes the oldest event.
+    pub fn pop_front(&mut self) -> Option<Event> {
+        let mut taken = self.take(1);
+        taken.pop()
+    }
+
+    /// Removes up to `count` of the oldest events.
+    pub fn take(&mut self, count: usize) -> Vec<Event> {
+        let mut taken = Vec::new();
+        while taken.len() < count && !self.chunks.is_empty() {
+            let mut spilled = self.read_first_chunk();
+            let n = cmp::min(count - taken.len(), spilled.len());
+            taken.extend(spilled.drain(..n));
+            self.write_first_chunk(&spilled);
+        }
+        while taken.len() < count {
+            match self.pop_memory() {
+                Some(event) => taken.push(event),
+                None => break,
+            }
+        }
+        taken
+    }
+
+    fn pop_memory(&mut self) -> Option<Event> {
+        let event = self.events.pop_front();
+        if let Some(ref event) = event {
+            self.bytes -= event_size(event);
+        }
+        event
+    }
+
+    fn over_limit(&self, incoming: usize) -> bool {
+        self.events.len() + 1 > self.limits.max_events ||
+        self.bytes + incoming > self.limits.max_bytes
+    }
+
+    // Moves the oldest events in memory to a new spill file.  Returns the
+    // number of spilled events dropped to keep within the limit.
+    fn spill(&mut self) -> u64 {
+        let mut chunk = Vec::new();
+        for _ in 0..SPILL_CHUNK {
+            match self.pop_memory() {
+                Some(event) => chunk.push(event),
+                None => break,
+            }
+        }
+        self.add_chunk(&chunk);
+
+        let mut dropped = 0;
+        while self.spilled > self.limits.max_spilled_events {
+            let excess = self.spilled - self.limits.max_spilled_events;
+            let first_len = self.chunks[0].len;
+            if first_len <= excess {
+                if let Some(first) = self.chunks.pop_front() {
+                    let _ = fs::remove_file(self.chunk_file(first.sequence));
+                }
+                self.spilled -= first_len;
+                dropped += first_len;
+            } else {
+                let mut spilled = self.read_first_chunk();
+                let n = cmp::min(excess, spilled.len());
+                spilled.drain(..n);
+                self.write_first_chunk(&spilled);
+                dropped += n;
+            }
+        }
+        dropped as u64
+    }
+
+    fn add_chunk(&mut self, events: &[Event]) {
+        if events.is_empty() {
+            return;
+        }
+        let sequence = self.next_chunk;
+        self.next_chunk += 1;
+        write_events(&self.chunk_file(sequence), events);
+        self.chunks.push_back(SpillChunk {
+            sequence: sequence,
+            len: events.len(),
+        });
+        self.spilled += events.len();
+    }
+
+    fn read_first_chunk(&self) -> Vec<Event> {
+        // A lost file is read as an empty chunk and dropped.
+        read_events(&self.chunk_file(self.chunks[0].sequence))
+    }
+
+    // Replaces the oldest spill file with `events`, removing it if there are
+    // none left.
+    fn write_first_chunk(&mut self, events: &[Event]) {
+        let sequence = self.chunks[0].sequence;
+        write_events(&self.chunk_file(sequence), events);
+        self.spilled -= self.chunks[0].len;
+        self.spilled += events.len();
+        if events.is_empty() {
+            self.chunks.pop_front();
+        } else {
+            self.chunks[0].len = events.len();
+        }
+    }
+
+    fn chunk_file(&self, sequence: u64) -> String {
+        format!("{}.{:020}", self.limits.spill_file, sequence)
+    }
+
+    // Finds the spill files left by an earlier run.
+    fn load_chunks(&mut self) {
+        let path = Path::new(&self.limits.spill_file).to_path_buf();
+        let prefix = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => format!("{}.", name),
+            None => return,
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => Path::new(".").to_path_buf(),
+        };
+        let mut sequences: Vec<u64> = match fs::read_dir(&dir) {
+            Ok(entries) => {
+                entries.filter_map(|entry| entry.ok())
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .filter(|name| name.starts_with(&prefix))
+                    .filter_map(|name| name[prefix.len()..].parse::<u64>().ok())
+                    .collect()
+            }
+            Err(_) => Vec::new(),
+        };
+        sequences.sort();
+        for sequence in sequences {
+            let len = read_events(&self.chunk_file(sequence)).len();
+            self.next_chunk = sequence + 1;
+            if len > 0 {
+                self.chunks.push_back(SpillChunk {
+                    sequence: sequence,
+                    len: len,
+                });
+                self.spilled += len;
+            }
+        }
+    }
+}
+
+/// Reads a file written by `write_events`.  A missing or unreadable file
+/// holds no events.
+pub fn read_events(file_name: &str) -> Vec<Event> {
+    let mut json = String::new();
+    match File::open(file_name) {
+        Ok(mut file) => {
+            if file.read_to_string(&mut json).is_err() {
+                return Vec::new();
+            }
+        }
+        Err(_) => return Vec::new(),
+    }
+    match serde_json::from_str::<Value>(&json) {
+        Ok(Value::Array(values)) => values.iter().filter_map(Event::from_value).collect(),
+        _ => {
+            logger().log(LogLevelFilter::Error,
+                         format!("Ignoring unreadable event file {}", file_name).as_str());
+            Vec::new()
+        }
+    }
+}
+
+/// Writes `events` to `file_name` as a JSON array, or deletes the file if
+/// there are none.
+pub fn write_events(file_name: &str, events: &[Event]) {
+    if events.is_empty() {
+        let _ = fs::remove_file(file_name);
+        return;
+    }
+    let value = Value::Array(events.iter().map(|event| event.to_value()).collect());
+    let written = serde_json::to_string(&value)
+        .map_err(|e| e.to_string())
+        .and_then(|json| {
+            File::create(file_name)
+                .and_then(|mut file| file.write_all(json.as_bytes()))
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = written {
+        logger().log(LogLevelFilter::Error,
+                     format!("Could not write {}: {}", file_name, e).as_str());
+    }
+}
+
+// Approximate memory used by a queued event.
+fn event_size(event: &Event) -> usize {
+    mem::size_of::<Event>() + event.category.len() + event.action.len() + event.label.len() +
+    event.timestamp.len()
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! event_queue {
+    before_each {
+        use events::Event;
+        use privacy::PrivacySettings;
+
+        let event = |value: u64| Event {
+            category: "category".to_string(),
+            action: "action".to_string(),
+            label: "label".to_string(),
+            value: value,
+            floating_point_value: None,
+            timestamp: "2016-05-25 22:36:57".to_string(),
+            created: 1464215817000,
+            client_id: "9eccb690-93aa-4513-835a-9a4f0f0e2a71".to_string(),
+            privacy: PrivacySettings::new(),
+            session: None,
+        };
+        let mut limits = QueueLimits::default();
+        limits.max_events = 3;
+    }
+
+    it "should drop the oldest events by default" {
+        let mut queue = EventQueue::new(limits);
+        let dropped: u64 = (0..5).map(|i| queue.push_back(event(i))).sum();
+        assert_eq!(dropped, 2);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop_front().unwrap().value, 2);
+    }
+
+    it "should drop the newest events when asked to" {
+        limits.overflow = OverflowPolicy::DropNewest;
+        let mut queue = EventQueue::new(limits);
+        let dropped: u64 = (0..5).map(|i| queue.push_back(event(i))).sum();
+        assert_eq!(dropped, 2);
+        assert_eq!(queue.pop_front().unwrap().value, 0);
+    }
+
+    it "should keep within the byte budget" {
+        limits.max_events = 100;
+        limits.max_bytes = 2 * event_size(&event(0));
+        let mut queue = EventQueue::new(limits);
+        for i in 0..4 {
+            queue.push_back(event(i));
+        }
+        assert_eq!(queue.len(), 2);
+        assert!(queue.bytes() <= 2 * event_size(&event(0)));
+    }
+
+    it "should spill to disk and send the spilled events first" {
+        limits.overflow = OverflowPolicy::Spill;
+        limits.spill_file = "test_events_spill.dat".to_string();
+        let mut queue = EventQueue::new(limits);
+        let dropped: u64 = (0..5).map(|i| queue.push_back(event(i))).sum();
+        assert_eq!(dropped, 0);
+        assert_eq!(queue.len(), 5);
+        let values: Vec<u64> = queue.take(10).iter().map(|e| e.value).collect();
+        assert_eq!(values, vec![0, 1, 2, 3, 4]);
+        assert!(fs::metadata(queue.chunk_file(0)).is_err());
+    }
+
+    it "should spill in chunks and read them back after a restart" {
+        limits.max_events = 5;
+        limits.overflow = OverflowPolicy::Spill;
+        limits.spill_file = "test_events_spill_chunks.dat".to_string();
+        limits.max_spilled_events = 30;
+        let mut queue = EventQueue::new(limits.clone());
+        queue.clear();
+        let dropped: u64 = (0..50).map(|i| queue.push_back(event(i))).sum();
+        // 45 spilled, five at a time, past the limit of 30.
+        assert_eq!(dropped, 15);
+        assert_eq!(queue.len(), 35);
+        assert_eq!(queue.chunks.len(), 6);
+        assert_eq!(queue.pop_front().unwrap().value, 15);
+
+        let mut restarted = EventQueue::new(limits);
+        assert_eq!(restarted.len(), 29);
+        let values: Vec<u64> = restarted.take(3).iter().map(|e| e.value).collect();
+        assert_eq!(values, vec![16, 17, 18]);
+        restarted.clear();
+        assert!(fs::metadata(restarted.chunk_file(3)).is_err());
+        assert!(fs::metadata(restarted.chunk_file(8)).is_err());
+    }
+}
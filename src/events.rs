@@ -1,51 +1,364 @@
 extern crate serde_json;
-extern crate time;
-extern crate uuid;
 extern crate url;
 
+use client_id::{ClientIdStore, open_default};
+use clock::{Clock, format_timestamp};
+use config::Config;
+use consent::{UPLOAD_FILE, read_upload_enabled, write_upload_enabled};
 use controller::EventInfo;
 use log::LogLevelFilter;
 use logger::MetricsLoggerFactory;
 use logger::MetricsLogger;
-use std::collections::VecDeque;
+use event_queue::{EventQueue, QueueLimits};
+use privacy::PrivacySettings;
+use queue_time::{MAX_QUEUE_TIME_BYTES, StaleHitPolicy, batch_to_value};
+use sampling::{Admission, IngestionFilter};
+use scrubber::{ScrubCallback, Scrubber};
+use session;
+use session::{Session, SessionInfo, SessionTracker};
+use stats::Stats;
+use storage::Storage;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
 use url::percent_encoding;
 use url::percent_encoding::SIMPLE_ENCODE_SET;
 
-#[cfg(not(test))]
-use config::Config;
-#[cfg(not(test))]
-use self::uuid::Uuid;
-#[cfg(not(test))]
 use self::serde_json::Value;
+use self::serde_json::builder::ObjectBuilder;
+use telemetry;
+use telemetry::TelemetryConfig;
+use validation::{EventError, HitReport, MAX_HIT_BYTES, OversizePolicy, check_field};
 
 #[allow(non_upper_case_globals)]
 // Shortcut to MetricsLoggerFactory function that gets the logger instance.
 const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
 
 const MAX_EVENT_SIZE: usize = 20;
-#[cfg(not(test))]
-const KEY_CID: &'static str = "cid";
+// Category of the events the library sends about itself.
+const INTERNAL_CATEGORY: &'static str = "metrics-controller";
+// Action of the events that report dropped events.
+const DROPPED_ACTION: &'static str = "dropped";
+// Action of the event sent when a session ends.
+const SESSION_DURATION_ACTION: &'static str = "session-duration";
+// Dry-run reports kept until they are taken by the application.
+const MAX_VALIDATION_REPORTS: usize = 100;
+// Urgent events not yet handed to the sinks.
+const URGENT_FILE: &'static str = "urgent_events.dat";
 
 define_encode_set! {
     /// This encode set is used in the URL parser for query strings.
     pub GOOGLE_ENCODE_SET = [SIMPLE_ENCODE_SET] | {' ', '!', '$', ')', '/'}
 }
 
+/// How soon an event should be sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    /// Sent with the next batch.
+    Normal,
+    /// Sent right away, e.g. a crash or a security alert, ahead of and apart
+    /// from the batch.  Urgent events are written to disk when they are
+    /// recorded, so they survive the crash they report.
+    Urgent,
+}
+
+/// A single recorded event.  Events are kept in this structured form while
+/// they are queued and are only encoded for a particular backend (a Google
+/// Analytics hit or a Telemetry ping) when they are transmitted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub category: String,
+    pub action: String,
+    pub label: String,
+    pub value: u64,
+    pub floating_point_value: Option<f64>,
+    pub timestamp: String,
+    /// When the event was recorded, in milliseconds since the epoch.
+    pub created: i64,
+    /// The client id when the event was recorded.  The event is sent under
+    /// it even if the id is reset or rotated before the event goes out.
+    pub client_id: String,
+    /// The privacy parameters when the event was recorded.
+    pub privacy: PrivacySettings,
+    pub session: Option<SessionInfo>,
+}
+
+impl Event {
+    /// The event as a JSON object, for storing it on disk.
+    pub fn to_value(&self) -> Value {
+        let mut builder = ObjectBuilder::new()
+            .insert("category", &self.category)
+            .insert("action", &self.action)
+            .insert("label", &self.label)
+            .insert("value", self.value)
+            .insert("timestamp", &self.timestamp)
+            .insert("created", self.created)
+            .insert("clientId", &self.client_id)
+            .insert("privacy", self.privacy.to_value());
+        if let Some(value) = self.floating_point_value {
+            builder = builder.insert("floatingPointValue", value);
+        }
+        if let Some(ref session) = self.session {
+            builder = builder.insert("session", session.to_value());
+        }
+        builder.build()
+    }
+
+    pub fn from_value(value: &Value) -> Option<Event> {
+        let string = |key: &str| value.find(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+        match (string("category"),
+               string("action"),
+               string("label"),
+               value.find("value").and_then(|v| v.as_u64()),
+               string("timestamp"),
+               value.find("created").and_then(|v| v.as_i64()),
+               string("clientId"),
+               value.find("privacy").and_then(PrivacySettings::from_value)) {
+            (Some(category),
+             Some(action),
+             Some(label),
+             Some(event_value),
+             Some(timestamp),
+             Some(created),
+             Some(client_id),
+             Some(privacy)) => {
+                Some(Event {
+                    category: category,
+                    action: action,
+                    label: label,
+                    value: event_value,
+                    floating_point_value: value.find("floatingPointValue")
+                        .and_then(|v| v.as_f64()),
+                    timestamp: timestamp,
+                    created: created,
+                    client_id: client_id,
+                    privacy: privacy,
+                    session: value.find("session").and_then(SessionInfo::from_value),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
 pub struct Events {
-    event_storage: VecDeque<String>,
+    event_storage: EventQueue,
+    // Events to send right away, at most one batch.  Kept in `urgent_file`.
+    urgent_events: Vec<Event>,
+    urgent_file: String,
     event_info: EventInfo,
-    client_id: String,
-    analytics_property: String
+    client_ids: ClientIdStore,
+    analytics_property: String,
+    stats: Stats,
+    validation_reports: VecDeque<HitReport>,
+    oversize_policy: OversizePolicy,
+    stale_hit_policy: StaleHitPolicy,
+    ingestion: IngestionFilter,
+    scrubber: Scrubber,
+    privacy: PrivacySettings,
+    sessions: SessionTracker,
+    // Dropped event counts already sent as synthetic events, by reason.
+    dropped_reported: BTreeMap<&'static str, u64>,
+    upload_file: String,
+    upload_enabled: bool,
+    // False once stop_collecting has been called.
+    collecting: bool,
+    storage: Storage,
+    clock: Arc<Clock>,
 }
 
 impl Events {
-    pub fn new(event_info: EventInfo, analytics_property: String) -> Events {
-        Events {
-            event_storage: VecDeque::with_capacity(20),
-            event_info: event_info,
-            client_id: get_client_id(),
-            analytics_property: analytics_property
+    /// Events for a controller keeping its files in `storage`, set up as `cfg` says.
+    pub fn new(event_info: EventInfo,
+               analytics_property: String,
+               storage: &Storage,
+               cfg: &mut Config,
+               clock: Arc<Clock>)
+               -> Events {
+        let privacy = PrivacySettings::from_config(cfg, &event_info.app_update_channel);
+        let (sessions, interrupted) = session::open_default(storage, cfg);
+        let upload_file = storage.path(UPLOAD_FILE);
+        let urgent_file = storage.path(URGENT_FILE);
+        let mut events = Events {
+            event_storage: EventQueue::new(QueueLimits::from_config(cfg, storage)),
+            urgent_events: read_events(&urgent_file),
+            urgent_file: urgent_file,
+            event_info: limit_event_info(event_info),
+            client_ids: open_default(storage, cfg, clock.now_secs()),
+            analytics_property: analytics_property,
+            stats: Stats::new(),
+            validation_reports: VecDeque::new(),
+            oversize_policy: OversizePolicy::from_config(cfg),
+            stale_hit_policy: StaleHitPolicy::from_config(cfg),
+            ingestion: IngestionFilter::from_config(cfg),
+            scrubber: Scrubber::from_config(cfg),
+            privacy: privacy,
+            sessions: sessions,
+            dropped_reported: BTreeMap::new(),
+            upload_enabled: read_upload_enabled(&upload_file),
+            upload_file: upload_file,
+            collecting: true,
+            storage: storage.clone(),
+            clock: clock,
+        };
+        if let Some(session) = interrupted {
+            events.queue_session_end(session, "interrupted");
         }
+        events
+    }
+
+    /// The current time in the format of `Event::timestamp`.
+    pub fn time_string(&self) -> String {
+        format_timestamp(self.clock.now_millis())
+    }
+
+    pub fn clock(&self) -> &Arc<Clock> {
+        &self.clock
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    pub fn upload_enabled(&self) -> bool {
+        self.upload_enabled
+    }
+
+    /// Turns uploading on or off and remembers the choice.  Turning it off
+    /// also deletes the queued events.  Returns true if the setting changed.
+    pub fn set_upload_enabled(&mut self, enabled: bool) -> bool {
+        write_upload_enabled(&self.upload_file, enabled);
+        if !enabled {
+            self.clear();
+        }
+        let changed = self.upload_enabled != enabled;
+        self.upload_enabled = enabled;
+        changed
+    }
+
+    /// Deletes the queued events and ignores any recorded afterwards.
+    pub fn stop_collecting(&mut self) {
+        self.clear();
+        self.collecting = false;
+    }
+
+    fn clear(&mut self) {
+        self.event_storage.clear();
+        self.urgent_events.clear();
+        write_events(&self.urgent_file, &self.urgent_events);
+        self.validation_reports.clear();
+    }
+
+    pub fn client_id(&self) -> &str {
+        self.client_ids.id()
+    }
+
+    /// Replaces the client id with a new random one, e.g. on a factory
+    /// reset.  Returns the new id.
+    pub fn reset_client_id(&mut self) -> String {
+        self.client_ids.reset(self.clock.now_secs())
+    }
+
+    /// Uses the application's own stable device id as the client id.
+    pub fn set_client_id(&mut self, id: &str) -> Result<(), String> {
+        self.client_ids.inject(id, self.clock.now_secs())
+    }
+
+    /// Replaces a generated client id that is older than the configured
+    /// "clientIdRotation".  Returns true if it changed.
+    pub fn rotate_client_id_if_due(&mut self) -> bool {
+        self.client_ids.rotate_if_due(self.clock.now_secs())
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    /// Keeps the reports of a dry run for `take_validation_reports`.  Only the
+    /// most recent MAX_VALIDATION_REPORTS are kept.
+    pub fn add_validation_reports(&mut self, reports: Vec<HitReport>) {
+        for report in reports {
+            self.stats.record_validation(report.valid);
+            if self.validation_reports.len() == MAX_VALIDATION_REPORTS {
+                self.validation_reports.pop_front();
+            }
+            self.validation_reports.push_back(report);
+        }
+    }
+
+    pub fn take_validation_reports(&mut self) -> Vec<HitReport> {
+        self.validation_reports.drain(..).collect()
+    }
+
+    pub fn privacy(&self) -> &PrivacySettings {
+        &self.privacy
+    }
+
+    /// Replaces the privacy parameters sent with events recorded from now on.
+    /// Events already recorded keep the parameters they were recorded with.
+    pub fn set_privacy(&mut self, privacy: PrivacySettings) {
+        self.privacy = privacy;
+    }
+
+    /// Starts a new session, ending the current one.
+    pub fn start_session(&mut self) {
+        if let Some(ended) = self.sessions.start(self.clock.now_secs()) {
+            self.queue_session_end(ended, "ended");
+        }
+    }
+
+    pub fn end_session(&mut self) {
+        if let Some(ended) = self.sessions.end(self.clock.now_secs()) {
+            self.queue_session_end(ended, "ended");
+        }
+    }
+
+    /// Ends the current session if it has been idle for "sessionTimeout".
+    pub fn end_idle_session(&mut self) {
+        if let Some(expired) = self.sessions.expire(self.clock.now_secs()) {
+            self.queue_session_end(expired, "timeout");
+        }
+    }
+
+    // Queues the event that reports the end and duration of a session.
+    // Nothing is sent for a session without hits.
+    fn queue_session_end(&mut self, session: Session, reason: &str) {
+        if !self.upload_enabled || !self.collecting || session.sequence == 0 {
+            return;
+        }
+        let event = Event {
+            category: INTERNAL_CATEGORY.to_string(),
+            action: SESSION_DURATION_ACTION.to_string(),
+            label: reason.to_string(),
+            value: session.duration(),
+            floating_point_value: None,
+            timestamp: self.time_string(),
+            created: self.clock.now_millis(),
+            client_id: self.client_ids.id().to_string(),
+            privacy: self.privacy.clone(),
+            session: Some(session.end_info()),
+        };
+        self.queue_event(event);
+    }
+
+    /// Installs a callback that scrubs event fields after the configured
+    /// detectors and patterns, or removes it.
+    pub fn set_scrub_callback(&mut self, callback: Option<ScrubCallback>) {
+        self.scrubber.set_callback(callback);
+    }
+
+    // Removes personal data from an event field and checks its length.
+    fn clean_field(&mut self, parameter: &'static str, value: &str) -> Result<String, EventError> {
+        let (scrubbed, redactions) = self.scrubber.scrub(value);
+        if redactions > 0 {
+            logger().log(LogLevelFilter::Debug,
+                         format!("Redacted {} value(s) in {}", redactions, parameter).as_str());
+            self.stats.redactions += redactions;
+        }
+        check_field(parameter, &scrubbed, self.oversize_policy)
     }
 
     pub fn insert_event(&mut self,
@@ -53,62 +366,175 @@ impl Events {
                         event_action: &str,
                         event_label: &str,
                         event_value: u64)
-                        -> bool {
-
-        let event_string = format!("v=1&t=event&tid={0}&cid={1}&ec={2}&ea={3}&el={4}&ev={5}&an={6}&av={7}&ul={8}&cd1={9}&cd2={10}&cd3={11}&cd4={12}&cd5={13}&cd6={14}&cd7={15}",
-                                   self.analytics_property.clone(),
-                                   self.encode_value(self.client_id.clone()),
-                                   self.encode_value(event_category.to_string()),
-                                   self.encode_value(event_action.to_string()),
-                                   self.encode_value(event_label.to_string()),
-                                   event_value,
-                                   self.encode_value(self.event_info.app_name.clone()),
-                                   self.encode_value(self.event_info.app_version.clone()),
-                                   self.encode_value(self.event_info.locale.clone()),
-                                   self.encode_value(self.event_info.os.clone()),
-                                   self.encode_value(self.event_info.os_version.clone()),
-                                   self.encode_value(self.event_info.device.clone()),
-                                   self.encode_value(self.event_info.arch.clone()),
-                                   self.encode_value(self.event_info.app_platform.clone()),
-                                   self.encode_value(self.client_id.clone()),
-                                   self.encode_value(get_time_string()));
+                        -> Result<(), EventError> {
+        self.insert_event_with_priority(event_category,
+                                        event_action,
+                                        event_label,
+                                        event_value,
+                                        Priority::Normal)
+    }
+
+    pub fn insert_event_with_priority(&mut self,
+                                      event_category: &str,
+                                      event_action: &str,
+                                      event_label: &str,
+                                      event_value: u64,
+                                      priority: Priority)
+                                      -> Result<(), EventError> {
+        let event = Event {
+            category: try!(self.clean_field("ec", event_category)),
+            action: try!(self.clean_field("ea", event_action)),
+            label: try!(self.clean_field("el", event_label)),
+            value: event_value,
+            floating_point_value: None,
+            timestamp: self.time_string(),
+            created: self.clock.now_millis(),
+            client_id: self.client_ids.id().to_string(),
+            privacy: self.privacy.clone(),
+            session: None,
+        };
+        self.push_event(event, priority)
+    }
+
+    pub fn insert_floating_point_event(&mut self,
+                                       event_category: &str,
+                                       event_action: &str,
+                                       event_label: &str,
+                                       event_value: f64)
+                                       -> Result<(), EventError> {
+        let event = Event {
+            category: try!(self.clean_field("ec", event_category)),
+            action: try!(self.clean_field("ea", event_action)),
+            label: try!(self.clean_field("el", event_label)),
+            value: 1,
+            floating_point_value: Some(event_value),
+            timestamp: self.time_string(),
+            created: self.clock.now_millis(),
+            client_id: self.client_ids.id().to_string(),
+            privacy: self.privacy.clone(),
+            session: None,
+        };
+        self.push_event(event, Priority::Normal)
+    }
+
+    fn push_event(&mut self, mut event: Event, priority: Priority) -> Result<(), EventError> {
+        if !self.upload_enabled || !self.collecting {
+            logger().log(LogLevelFilter::Debug, "Not collecting, event ignored");
+            return Ok(());
+        }
+        match self.ingestion.admit(self.client_ids.id(), &event, self.clock.instant()) {
+            Admission::Accepted => {}
+            Admission::SampledOut => {
+                self.stats.sampled_out += 1;
+                return Ok(());
+            }
+            Admission::RateLimited => {
+                self.stats.rate_limited += 1;
+                return Ok(());
+            }
+        }
+        self.end_idle_session();
+        // The hit is checked before it counts in the session.
+        event.session = self.sessions.peek_hit();
+        let length = self.format_hit(&event).len();
+        // Leave room for the queue time added when the hit is sent.
+        if length + MAX_QUEUE_TIME_BYTES > MAX_HIT_BYTES {
+            return Err(EventError::HitTooLong {
+                length: length + MAX_QUEUE_TIME_BYTES,
+                limit: MAX_HIT_BYTES,
+            });
+        }
+        event.session = self.sessions.next_hit(self.clock.now_secs());
         logger().log(LogLevelFilter::Debug,
-                     format!("Inserted event: {}", event_string).as_str());
-        self.event_storage.push_back(event_string);
-
-        true
-    }
-        pub fn insert_floating_point_event(&mut self,
-                                           event_category: &str,
-                                           event_action: &str,
-                                           event_label: &str,
-                                           event_value: f64)
-                                           -> bool {
-
-            let event_string = format!("v=1&t=event&tid={0}&cid={1}&ec={2}&ea={3}&el={4}&ev={5}&an={6}&av={7}&ul={8}&cd1={9}&cd2={10}&cd3={11}&cd4={12}&cd5={13}&cd6={14}&cd7={15}&cd8={16:.3}",
-                                       self.analytics_property.clone(),
-                                       self.encode_value(self.client_id.clone()),
-                                       self.encode_value(event_category.to_string()),
-                                       self.encode_value(event_action.to_string()),
-                                       self.encode_value(event_label.to_string()),
-                                       1,
-                                       self.encode_value(self.event_info.app_name.clone()),
-                                       self.encode_value(self.event_info.app_version.clone()),
-                                       self.encode_value(self.event_info.locale.clone()),
-                                       self.encode_value(self.event_info.os.clone()),
-                                       self.encode_value(self.event_info.os_version.clone()),
-                                       self.encode_value(self.event_info.device.clone()),
-                                       self.encode_value(self.event_info.arch.clone()),
-                                       self.encode_value(self.event_info.app_platform.clone()),
-                                       self.encode_value(self.client_id.clone()),
-                                       self.encode_value(get_time_string()),
-                                       event_value);
-            logger().log(LogLevelFilter::Debug,
-                         format!("Inserted event: {}", event_string).as_str());
-            self.event_storage.push_back(event_string);
+                     format!("Inserted event: {}", self.format_hit(&event)).as_str());
+        // Past one batch, urgent events wait for the next send like the rest.
+        if priority == Priority::Urgent && self.urgent_events.len() < MAX_EVENT_SIZE {
+            self.urgent_events.push(event);
+            write_events(&self.urgent_file, &self.urgent_events);
+        } else {
+            self.queue_event(event);
+        }
 
-            true
+        Ok(())
     }
+
+    fn queue_event(&mut self, event: Event) {
+        let dropped = self.event_storage.push_back(event);
+        if dropped > 0 {
+            logger().log(LogLevelFilter::Warn,
+                         format!("Event queue is full, dropped {} events", dropped).as_str());
+            self.stats.overflow_dropped += dropped;
+        }
+    }
+
+    /// Queues a synthetic event for each reason events were dropped since the
+    /// last report, with the number dropped as its value.  Events dropped
+    /// because the queue was full are always reported; sampled out and rate
+    /// limited ones only if "reportDropped" is set.  These events are not
+    /// sampled or rate limited.
+    pub fn report_dropped_events(&mut self) {
+        if !self.upload_enabled || !self.collecting {
+            return;
+        }
+        let mut counts = vec![("overflow", self.stats.overflow_dropped)];
+        if self.ingestion.reports_dropped() {
+            counts.push(("sampled-out", self.stats.sampled_out));
+            counts.push(("rate-limited", self.stats.rate_limited));
+        }
+        for (reason, count) in counts {
+            let reported = self.dropped_reported.get(reason).cloned().unwrap_or(0);
+            if count > reported {
+                self.queue_event(Event {
+                    category: INTERNAL_CATEGORY.to_string(),
+                    action: DROPPED_ACTION.to_string(),
+                    label: reason.to_string(),
+                    value: count - reported,
+                    floating_point_value: None,
+                    timestamp: self.time_string(),
+                    created: self.clock.now_millis(),
+                    client_id: self.client_ids.id().to_string(),
+                    privacy: self.privacy.clone(),
+                    session: None,
+                });
+                self.dropped_reported.insert(reason, count);
+            }
+        }
+    }
+
+    // Formats an event as a Google Analytics Measurement Protocol hit.
+    fn format_hit(&self, event: &Event) -> String {
+        let mut hit = format!("v=1&t=event&tid={0}&cid={1}&ec={2}&ea={3}&el={4}&ev={5}&an={6}&av={7}&ul={8}&cd1={9}&cd2={10}&cd3={11}&cd4={12}&cd5={13}&cd6={14}&cd7={15}",
+                              self.analytics_property.clone(),
+                              self.encode_value(event.client_id.clone()),
+                              self.encode_value(event.category.clone()),
+                              self.encode_value(event.action.clone()),
+                              self.encode_value(event.label.clone()),
+                              event.value,
+                              self.encode_value(self.event_info.app_name.clone()),
+                              self.encode_value(self.event_info.app_version.clone()),
+                              self.encode_value(self.event_info.locale.clone()),
+                              self.encode_value(self.event_info.os.clone()),
+                              self.encode_value(self.event_info.os_version.clone()),
+                              self.encode_value(self.event_info.device.clone()),
+                              self.encode_value(self.event_info.arch.clone()),
+                              self.encode_value(self.event_info.app_platform.clone()),
+                              self.encode_value(event.client_id.clone()),
+                              self.encode_value(event.timestamp.clone()));
+        hit.push_str(&event.privacy.to_query(|value| self.encode_value(value)));
+        if let Some(value) = event.floating_point_value {
+            hit.push_str(&format!("&cd8={:.3}", value));
+        }
+        if let Some(ref session) = event.session {
+            if let Some(control) = session.control {
+                hit.push_str(&format!("&sc={}", control.as_str()));
+            }
+            hit.push_str(&format!("&cd9={}&cd10={}",
+                                  self.encode_value(session.id.clone()),
+                                  session.sequence));
+        }
+        hit
+    }
+
     fn encode_value(&self, value: String) -> String {
         let mut value_encoded = String::new();
         let value_vec = value.into_bytes();
@@ -131,77 +557,109 @@ impl Events {
         self.event_storage.len() >= MAX_EVENT_SIZE
     }
 
+    /// The number of events waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.event_storage.len()
+    }
+
     pub fn is_empty(&mut self) -> bool {
         self.event_storage.is_empty()
     }
 
+    /// Removes up to MAX_EVENT_SIZE events (one batch) from the front of the queue.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.event_storage.take(MAX_EVENT_SIZE)
+    }
+
+    /// The number of urgent events waiting to be sent.
+    pub fn urgent_len(&self) -> usize {
+        self.urgent_events.len()
+    }
+
+    /// The urgent events waiting to be sent.
+    pub fn urgent_events(&self) -> &[Event] {
+        &self.urgent_events
+    }
+
+    /// Removes the urgent events, in memory and on disk.
+    pub fn take_urgent_events(&mut self) -> Vec<Event> {
+        let urgent = self.urgent_events.drain(..).collect();
+        write_events(&self.urgent_file, &self.urgent_events);
+        urgent
+    }
+
     pub fn get_events_as_body(&mut self) -> String {
-        let mut body = String::new();
-        let mut i: usize = 0;
-        while i < MAX_EVENT_SIZE {
-            let val: Option<String> = self.event_storage.pop_front();
-            match val {
-                Some(v) => {
-                    body.push_str(&v);
-                    body.push_str("\n");
-                }
-                None => {
-                    break;
-                }
-            }
-            i = i + 1;
-        }
-        body
+        let events = self.take_events();
+        self.format_body(&events)
     }
-}
 
-#[cfg(not(test))]
-fn get_client_id() -> String {
-    let mut cid = String::new();
-    let mut cfg = Config::new();
-    if cfg.init("cid.dat") {
-        let val: Option<Value> = cfg.get(KEY_CID);
-        match val {
-            Some(_) => cid.push_str(&cfg.get_string(KEY_CID).to_string()),
-            None => panic!("Error: no cid written"),
-        }
-    } else {
-        cid.push_str(&Uuid::new_v4().to_hyphenated_string().to_string());
-        let json = format!("{{\"{0}\":\"{1}\"}}", KEY_CID, cid);
-        cfg.create_and_write_json("cid.dat", &json);
+    /// The events as a queued Google Analytics batch, each hit with its
+    /// creation time.  The queue time is added when the batch is sent.
+    pub fn format_batch(&self, events: &[Event]) -> Value {
+        let hits: Vec<(String, i64)> = events.iter()
+            .map(|event| (self.format_hit(event), event.created))
+            .collect();
+        batch_to_value(&hits)
     }
-    cid
-}
 
-#[cfg(test)]
-fn get_client_id() -> String {
-    "9eccb690-93aa-4513-835a-9a4f0f0e2a71".to_string()
-}
+    /// What happens to hits queued for longer than Google Analytics accepts.
+    pub fn stale_hit_policy(&self) -> StaleHitPolicy {
+        self.stale_hit_policy
+    }
 
+    pub fn format_body(&self, events: &[Event]) -> String {
+        let mut body = String::new();
+        for event in events {
+            body.push_str(&self.format_hit(event));
+            body.push_str("\n");
+        }
+        body
+    }
 
-#[cfg(not(test))]
-fn get_time_string() -> String {
-    let ts = time::now_utc();
-    let time_string = format!("{0:4}-{1:02}-{2:02} {3:02}:{4:02}:{5:02}",
-                              ts.tm_year + 1900,
-                              ts.tm_mon + 1,
-                              ts.tm_mday,
-                              ts.tm_hour,
-                              ts.tm_min,
-                              ts.tm_sec);
-    time_string
+    /// Wraps a batch of events in a Telemetry ping identified by `doc_id`.
+    /// The ping is sent under the client id of the first event; the caller
+    /// splits batches whose events were recorded under different ids.
+    pub fn format_ping(&self, doc_id: &str, config: &TelemetryConfig, events: &[Event]) -> Value {
+        let client_id = match events.first() {
+            Some(event) => &event.client_id,
+            None => self.client_ids.id(),
+        };
+        telemetry::build_ping(config,
+                              doc_id,
+                              client_id,
+                              &self.event_info,
+                              events)
+    }
 }
 
-#[cfg(test)]
-fn get_time_string() -> String {
-    "2016-05-25 22:36:57".to_string()
+// The EventInfo fields are sent with every hit, so they are truncated to the
+// Measurement Protocol limits once, up front.
+fn limit_event_info(event_info: EventInfo) -> EventInfo {
+    let limit = |parameter: &'static str, value: String| {
+        check_field(parameter, &value, OversizePolicy::Truncate).unwrap_or(value)
+    };
+    EventInfo {
+        locale: limit("ul", event_info.locale),
+        os: limit("cd1", event_info.os),
+        os_version: limit("cd2", event_info.os_version),
+        device: limit("cd3", event_info.device),
+        arch: limit("cd4", event_info.arch),
+        app_name: limit("an", event_info.app_name),
+        app_version: limit("av", event_info.app_version),
+        app_update_channel: event_info.app_update_channel,
+        app_platform: limit("cd5", event_info.app_platform),
+    }
 }
 
 #[cfg(not(feature = "integration"))]
 #[cfg(test)]
 describe! events_functionality {
     before_each {
+        use clock::ManualClock;
+        use consent::UPLOAD_FILE;
         use controller::EventInfo;
+        use std::sync::Arc;
+        use storage::TestStorage;
 
         let event_info = EventInfo::new(
                     "en-us",
@@ -213,11 +671,18 @@ describe! events_functionality {
                     "default",
                     "rust test",
                     "arm");
-        let mut ev = Events::new(event_info, "CD_ap".to_string());
-        ev.client_id = "9eccb690-93aa-4513-835a-9a4f0f0e2a71".to_string();
+        let clock = Arc::new(ManualClock::new(1464215817000));
+        let test_storage = TestStorage::new("test_events");
+        let storage = test_storage.storage().clone();
+        let mut ev = Events::new(event_info,
+                                 "CD_ap".to_string(),
+                                 &storage,
+                                 &mut Config::load(&storage),
+                                 clock.clone());
+        ev.client_ids = ClientIdStore::in_memory("9eccb690-93aa-4513-835a-9a4f0f0e2a71", 0);
     }
     it "should insert an event" {
-        ev.insert_event("category", "action", "label", 1);
+        ev.insert_event("category", "action", "label", 1).unwrap();
         assert_eq!(ev.event_storage.len(), 1);
     }
 
@@ -225,38 +690,232 @@ describe! events_functionality {
         let formatted_event = "v=1&t=event&tid=CD_ap&cid=9eccb690-93aa-4513-835a-9a4f0f0e2a71&ec=category&ea=action\
                                 &el=label&ev=1&an=iot_app&av=1.0&ul=en-us&cd1=linux&cd2=1.2&cd3=RPi%2F2&cd4=arm&cd5=rust%20test&cd6=9eccb690-93aa-4513-835a-9a4f0f0e2a71\
                                 &cd7=2016-05-25%2022:36:57";
-        ev.insert_event("category", "action", "label", 1);
-        assert_eq!(formatted_event, ev.event_storage.pop_front().unwrap());
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        let event = ev.event_storage.pop_front().unwrap();
+        assert_eq!(formatted_event, ev.format_hit(&event));
     }
 
     it "should return true if there are more than MAX_EVENT_SIZE" {
         for _ in 0..21 {
-            ev.insert_event("category", "action", "label", 1);
+            ev.insert_event("category", "action", "label", 1).unwrap();
         }
         assert_eq!(ev.is_time_to_send(), true);
     }
 
     it "should return true if there are exactly MAX_EVENT_SIZE" {
         for _ in 0..20 {
-            ev.insert_event("category", "action", "label", 1);
+            ev.insert_event("category", "action", "label", 1).unwrap();
         }
         assert_eq!(ev.is_time_to_send(), true);
     }
 
     it "should return true if there are less than MAX_EVENT_SIZE" {
         for _ in 0..19 {
-            ev.insert_event("category", "action", "label", 1);
+            ev.insert_event("category", "action", "label", 1).unwrap();
         }
         assert_eq!(ev.is_time_to_send(), false);
     }
 
+    it "should keep urgent events apart from the batch, on disk" {
+        ev.insert_event_with_priority("crash", "signal", "SIGSEGV", 11, Priority::Urgent).unwrap();
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        assert_eq!((ev.urgent_len(), ev.len()), (1, 1));
+        assert_eq!(read_events(&ev.urgent_file), ev.urgent_events().to_vec());
+        let urgent = ev.take_urgent_events();
+        assert_eq!(urgent[0].category, "crash");
+        assert_eq!(ev.urgent_len(), 0);
+        assert!(read_events(&ev.urgent_file).is_empty());
+    }
+
     it "is_empty should return false if there are events" {
         for _ in 0..19 {
-            ev.insert_event("category", "action", "label", 1);
+            ev.insert_event("category", "action", "label", 1).unwrap();
+        }
+        assert_eq!(ev.is_empty(), false);
+    }
+
+    it "should reject an over-long category when asked to" {
+        use std::iter;
+        use validation::{EventError, OversizePolicy};
+
+        ev.oversize_policy = OversizePolicy::Reject;
+        let category: String = iter::repeat("c").take(200).collect();
+        assert_eq!(ev.insert_event(&category, "action", "label", 1),
+                   Err(EventError::FieldTooLong { parameter: "ec", length: 200, limit: 150 }));
+        assert_eq!(ev.is_empty(), true);
+    }
+
+    it "should truncate an over-long label by default" {
+        use std::iter;
+
+        let label: String = iter::repeat("l").take(600).collect();
+        ev.insert_event("category", "action", &label, 1).unwrap();
+        assert_eq!(ev.event_storage.pop_front().unwrap().label.len(), 500);
+    }
+
+    it "should scrub fields before queueing the event" {
+        use scrubber::Scrubber;
+
+        ev.scrubber = Scrubber::with_all_detectors();
+        ev.insert_event("category", "open /home/alice/notes", "alice@example.com", 1).unwrap();
+        let event = ev.event_storage.pop_front().unwrap();
+        assert_eq!(event.action, "open [home]/notes");
+        assert_eq!(event.label, "[email]");
+        assert_eq!(ev.stats().redactions, 2);
+    }
+
+    it "should mark the hits of a session and report its duration" {
+        ev.start_session();
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        ev.end_session();
+        assert_eq!(ev.event_storage.len(), 3);
+
+        let events = ev.event_storage.take(3);
+        let id = events[0].session.clone().unwrap().id;
+        assert!(ev.format_hit(&events[0]).ends_with(&format!("&sc=start&cd9={}&cd10=1", id)));
+        assert!(ev.format_hit(&events[1]).ends_with(&format!("&cd9={}&cd10=2", id)));
+        let end = events[2].clone();
+        assert_eq!((end.action.as_str(), end.label.as_str()), ("session-duration", "ended"));
+        assert!(ev.format_hit(&end).ends_with(&format!("&sc=end&cd9={}&cd10=3", id)));
+    }
+
+    it "should not count a rejected hit in the session" {
+        use session::SessionControl;
+        use std::iter;
+        use validation::EventError;
+
+        ev.start_session();
+        let app_name = ev.event_info.app_name.clone();
+        ev.event_info.app_name = iter::repeat("a").take(8192).collect();
+        match ev.insert_event("category", "action", "label", 1) {
+            Err(EventError::HitTooLong { .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        ev.event_info.app_name = app_name;
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        let session = ev.event_storage.pop_front().unwrap().session.unwrap();
+        assert_eq!((session.sequence, session.control), (1, Some(SessionControl::Start)));
+    }
+
+    it "should send events under the client id they were recorded with" {
+        ev.insert_event("category", "action", "before", 1).unwrap();
+        let new_id = ev.reset_client_id();
+        ev.insert_event("category", "action", "after", 1).unwrap();
+        let before = ev.event_storage.pop_front().unwrap();
+        let after = ev.event_storage.pop_front().unwrap();
+        assert!(ev.format_hit(&before).contains("&cid=9eccb690-93aa-4513-835a-9a4f0f0e2a71&"));
+        assert!(ev.format_hit(&after).contains(&format!("&cid={}&", new_id)));
+        assert_eq!(Event::from_value(&before.to_value()), Some(before));
+    }
+
+    it "should reject a stored event without a client id, creation time or privacy" {
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        let value = ev.event_storage.pop_front().unwrap().to_value();
+        for key in &["clientId", "created", "privacy"] {
+            let mut incomplete = value.clone();
+            incomplete.as_object_mut().unwrap().remove(*key);
+            assert_eq!(Event::from_value(&incomplete), None);
+        }
+    }
+
+    it "should add the privacy parameters to every hit" {
+        use privacy::PrivacySettings;
+
+        ev.set_privacy(PrivacySettings {
+            anonymize_ip: true,
+            non_personalized_ads: false,
+            data_source: Some("iot app".to_string()),
+        });
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        let event = ev.event_storage.pop_front().unwrap();
+        assert!(ev.format_hit(&event).ends_with("&cd7=2016-05-25%2022:36:57&aip=1&ds=iot%20app"));
+    }
+
+    it "should keep the privacy parameters an event was recorded with" {
+        use privacy::PrivacySettings;
+
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        ev.set_privacy(PrivacySettings {
+            anonymize_ip: true,
+            non_personalized_ads: true,
+            data_source: None,
+        });
+        let event = ev.event_storage.pop_front().unwrap();
+        assert!(ev.format_hit(&event).ends_with("&cd7=2016-05-25%2022:36:57"));
+        assert_eq!(Event::from_value(&event.to_value()), Some(event));
+    }
+
+    it "should count and report sampled out events" {
+        use config::Config;
+        use sampling::IngestionFilter;
+
+        let mut cfg = Config::new();
+        cfg.parse_json(r#"{ "sampling": [ { "category": "chatty", "rate": 0 } ],
+                            "reportDropped": true }"#.to_string());
+        ev.ingestion = IngestionFilter::from_parsed_config(&mut cfg).unwrap();
+        ev.insert_event("chatty", "action", "label", 1).unwrap();
+        ev.insert_event("chatty", "action", "label", 1).unwrap();
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        assert_eq!(ev.stats().sampled_out, 2);
+        assert_eq!(ev.event_storage.len(), 1);
+
+        ev.report_dropped_events();
+        ev.report_dropped_events();
+        let events = ev.event_storage.take(2);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].label, "sampled-out");
+        assert_eq!(events[1].value, 2);
+    }
+
+    it "should count and report events dropped from a full queue" {
+        use event_queue::{EventQueue, QueueLimits};
+
+        let mut limits = QueueLimits::default();
+        limits.max_events = 2;
+        ev.event_storage = EventQueue::new(limits);
+        for _ in 0..5 {
+            ev.insert_event("category", "action", "label", 1).unwrap();
         }
+        assert_eq!(ev.stats().overflow_dropped, 3);
+
+        ev.report_dropped_events();
+        let report = ev.take_events().pop().unwrap();
+        assert_eq!(report.category, "metrics-controller");
+        assert_eq!(report.label, "overflow");
+        // The report pushed out one more event, which is reported next time.
+        assert_eq!(report.value, 3);
+        assert_eq!(ev.stats().overflow_dropped, 4);
+    }
+
+    it "should delete queued events and stop collecting when upload is disabled" {
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        assert!(ev.set_upload_enabled(false));
+        assert_eq!(ev.is_empty(), true);
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        assert_eq!(ev.is_empty(), true);
+
+        assert!(ev.set_upload_enabled(true));
+        ev.insert_event("category", "action", "label", 1).unwrap();
         assert_eq!(ev.is_empty(), false);
     }
 
+    it "should keep its files in its storage directory" {
+        let other_storage = TestStorage::new("test_events_other");
+        let info = EventInfo::new("en-us", "linux", "1.2", "RPi/2", "other_app", "1.0",
+                                  "default", "rust test", "arm");
+        let mut other = Events::new(info,
+                                    "CD_other".to_string(),
+                                    other_storage.storage(),
+                                    &mut Config::load(other_storage.storage()),
+                                    clock.clone());
+        assert!(other.set_upload_enabled(false));
+        assert!(other_storage.storage().dir().join(UPLOAD_FILE).exists());
+        assert!(!ev.storage().dir().join(UPLOAD_FILE).exists());
+        assert!(!other.upload_enabled());
+        assert!(ev.upload_enabled());
+    }
+
     it "is_empty should return true if storage is empty" {
         assert_eq!(ev.is_empty(), true);
     }
@@ -265,7 +924,7 @@ describe! events_functionality {
         let formatted_body = "v=1&t=event&tid=CD_ap&cid=9eccb690-93aa-4513-835a-9a4f0f0e2a71&ec=category&ea=action\
                                 &el=label&ev=1&an=iot_app&av=1.0&ul=en-us&cd1=linux&cd2=1.2&cd3=RPi%2F2&cd4=arm&cd5=rust%20test&cd6=9eccb690-93aa-4513-835a-9a4f0f0e2a71\
                                 &cd7=2016-05-25%2022:36:57\n";
-        ev.insert_event("category", "action", "label", 1);
+        ev.insert_event("category", "action", "label", 1).unwrap();
         let body = ev.get_events_as_body();
         assert_eq!(body, formatted_body);
     }
@@ -277,8 +936,8 @@ describe! events_functionality {
                               v=1&t=event&tid=CD_ap&cid=9eccb690-93aa-4513-835a-9a4f0f0e2a71&ec=category&ea=action\
                                   &el=label&ev=1&an=iot_app&av=1.0&ul=en-us&cd1=linux&cd2=1.2&cd3=RPi%2F2\
                                   &cd4=arm&cd5=rust%20test&cd6=9eccb690-93aa-4513-835a-9a4f0f0e2a71&cd7=2016-05-25%2022:36:57\n";
-        ev.insert_event("category", "action", "label", 1);
-        ev.insert_event("category", "action", "label", 1);
+        ev.insert_event("category", "action", "label", 1).unwrap();
+        ev.insert_event("category", "action", "label", 1).unwrap();
         let body = ev.get_events_as_body();
         assert_eq!(body, formatted_body);
     }
@@ -287,7 +946,7 @@ describe! events_functionality {
        let formatted_body = "v=1&t=event&tid=CD_ap&cid=9eccb690-93aa-4513-835a-9a4f0f0e2a71&ec=category&ea=action\
                                 &el=label&ev=1&an=iot_app&av=1.0&ul=en-us&cd1=linux&cd2=1.2&cd3=RPi%2F2&cd4=arm&cd5=rust%20test&cd6=9eccb690-93aa-4513-835a-9a4f0f0e2a71\
                                 &cd7=2016-05-25%2022:36:57&cd8=1.000\n";
-        ev.insert_floating_point_event("category", "action", "label", 1.0);
+        ev.insert_floating_point_event("category", "action", "label", 1.0).unwrap();
         let body = ev.get_events_as_body();
         assert_eq!(body, formatted_body);
    }
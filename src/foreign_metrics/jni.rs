@@ -0,0 +1,340 @@
+// The calls of the C interface for Java, as the native methods of
+// org.mozilla.metrics.MetricsController (java/org/mozilla/metrics).  The
+// controller handle is passed as a long, 0 for none, and the calls return the
+// values of MetricsStatus.
+//
+// Only the few parts of jni.h these calls use are declared here.
+
+use controller::{ControllerOptions, EventInfo, MetricsController};
+use events::Priority;
+use log::LogLevelFilter;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use super::{Foreign, MetricsStatus, c_to_string, catch_panic, from_handle, logger};
+use super::{metrics_last_error, set_last_error, status};
+
+#[allow(non_camel_case_types)]
+pub type jint = i32;
+#[allow(non_camel_case_types)]
+pub type jlong = i64;
+#[allow(non_camel_case_types)]
+pub type jboolean = u8;
+#[allow(non_camel_case_types)]
+pub type jdouble = f64;
+#[allow(non_camel_case_types)]
+pub enum _jobject {}
+#[allow(non_camel_case_types)]
+pub type jobject = *mut _jobject;
+#[allow(non_camel_case_types)]
+pub type jclass = jobject;
+#[allow(non_camel_case_types)]
+pub type jstring = jobject;
+
+pub type JNIEnv = *const JNINativeInterface;
+
+// Slots of the function table before NewStringUTF.
+const SLOTS_BEFORE_NEW_STRING_UTF: usize = 167;
+
+/// The start of the JNI function table, up to the functions used here.  The
+/// slots keep their order from jni.h.
+#[repr(C)]
+#[allow(non_snake_case)]
+pub struct JNINativeInterface {
+    pub unused: [*mut c_void; SLOTS_BEFORE_NEW_STRING_UTF],
+    pub NewStringUTF: unsafe extern "system" fn(env: *mut JNIEnv, utf: *const c_char) -> jstring,
+    pub GetStringUTFLength: *mut c_void,
+    pub GetStringUTFChars: unsafe extern "system" fn(env: *mut JNIEnv,
+                                                     string: jstring,
+                                                     is_copy: *mut jboolean)
+                                                     -> *const c_char,
+    pub ReleaseStringUTFChars: unsafe extern "system" fn(env: *mut JNIEnv,
+                                                         string: jstring,
+                                                         utf: *const c_char),
+}
+
+// Copies the Java string argument `name`.  Java passes the strings as
+// modified UTF-8, which differs from UTF-8 only for NUL and characters
+// outside the BMP; those are replaced with U+FFFD.
+fn java_to_string(env: *mut JNIEnv, string: jstring, name: &str) -> Result<String, MetricsStatus> {
+    if string.is_null() {
+        set_last_error(&format!("{} is null", name));
+        return Err(MetricsStatus::MetricsInvalidArgument);
+    }
+    unsafe {
+        let chars = ((**env).GetStringUTFChars)(env, string, ptr::null_mut());
+        if chars.is_null() {
+            // The JVM has thrown an OutOfMemoryError.
+            set_last_error(&format!("Could not read {}", name));
+            return Err(MetricsStatus::MetricsInvalidArgument);
+        }
+        let result = c_to_string(chars, name);
+        ((**env).ReleaseStringUTFChars)(env, string, chars);
+        result
+    }
+}
+
+fn record_event(env: *mut JNIEnv,
+                handle: jlong,
+                event_category: jstring,
+                event_action: jstring,
+                event_label: jstring,
+                event_value: jlong,
+                priority: Priority)
+                -> jint {
+    status(|| {
+        let foreign = try!(from_handle(handle as *mut Foreign));
+        let event_category = try!(java_to_string(env, event_category, "category"));
+        let event_action = try!(java_to_string(env, event_action, "action"));
+        let event_label = try!(java_to_string(env, event_label, "label"));
+        if event_value < 0 {
+            set_last_error(&format!("event_value is negative: {}", event_value));
+            return Err(MetricsStatus::MetricsInvalidArgument);
+        }
+        Ok(foreign.record_event(&event_category,
+                                &event_action,
+                                &event_label,
+                                event_value as u64,
+                                priority))
+    }) as jint
+}
+
+/// Creates a controller, as `init_metrics` does.  Returns its handle, or 0 on
+/// failure.
+#[no_mangle]
+pub extern "system" fn Java_org_mozilla_metrics_MetricsController_nativeInit(
+    env: *mut JNIEnv,
+    _class: jclass,
+    app_name: jstring,
+    app_version: jstring,
+    app_update_channel: jstring,
+    app_platform: jstring,
+    locale: jstring,
+    device: jstring,
+    arch: jstring,
+    os: jstring,
+    os_version: jstring,
+    storage_dir: jstring)
+    -> jlong {
+    let init = || -> Result<Foreign, MetricsStatus> {
+        let app_name = try!(java_to_string(env, app_name, "appName"));
+        let app_version = try!(java_to_string(env, app_version, "appVersion"));
+        let app_update_channel = try!(java_to_string(env, app_update_channel, "appUpdateChannel"));
+        let app_platform = try!(java_to_string(env, app_platform, "appPlatform"));
+        let locale = try!(java_to_string(env, locale, "locale"));
+        let device = try!(java_to_string(env, device, "device"));
+        let arch = try!(java_to_string(env, arch, "arch"));
+        let os = try!(java_to_string(env, os, "os"));
+        let os_version = try!(java_to_string(env, os_version, "osVersion"));
+        let event_info = EventInfo::new(&locale,
+                                        &os,
+                                        &os_version,
+                                        &device,
+                                        &app_name,
+                                        &app_version,
+                                        &app_update_channel,
+                                        &app_platform,
+                                        &arch);
+        let mut options = ControllerOptions::new();
+        if !storage_dir.is_null() {
+            options.storage_dir = Some(try!(java_to_string(env, storage_dir, "storageDir")));
+        }
+        Ok(Foreign::new(MetricsController::with_options(event_info, options)))
+    };
+    catch_panic(0, || {
+        match init() {
+            Ok(foreign) => {
+                logger().log(LogLevelFilter::Info, "Initialized Metrics Library for Java.");
+                Box::into_raw(Box::new(foreign)) as jlong
+            }
+            Err(_) => 0,
+        }
+    })
+}
+
+/// Records an event, as `record_event` does.
+#[no_mangle]
+pub extern "system" fn Java_org_mozilla_metrics_MetricsController_nativeRecordEvent(
+    env: *mut JNIEnv,
+    _class: jclass,
+    handle: jlong,
+    category: jstring,
+    action: jstring,
+    label: jstring,
+    value: jlong)
+    -> jint {
+    record_event(env, handle, category, action, label, value, Priority::Normal)
+}
+
+/// Records an event that is sent right away, as
+/// `MetricsController::record_event_with_priority` does.
+#[no_mangle]
+pub extern "system" fn Java_org_mozilla_metrics_MetricsController_nativeRecordUrgentEvent(
+    env: *mut JNIEnv,
+    _class: jclass,
+    handle: jlong,
+    category: jstring,
+    action: jstring,
+    label: jstring,
+    value: jlong)
+    -> jint {
+    record_event(env, handle, category, action, label, value, Priority::Urgent)
+}
+
+/// Records an event with a floating point value, as
+/// `record_floating_point_event` does.
+#[no_mangle]
+pub extern "system" fn Java_org_mozilla_metrics_MetricsController_nativeRecordFloatingPointEvent(
+    env: *mut JNIEnv,
+    _class: jclass,
+    handle: jlong,
+    category: jstring,
+    action: jstring,
+    label: jstring,
+    value: jdouble)
+    -> jint {
+    status(|| {
+        let foreign = try!(from_handle(handle as *mut Foreign));
+        let category = try!(java_to_string(env, category, "category"));
+        let action = try!(java_to_string(env, action, "action"));
+        let label = try!(java_to_string(env, label, "label"));
+        Ok(foreign.record_floating_point_event(&category, &action, &label, value))
+    }) as jint
+}
+
+/// Turns uploading on or off, as `metrics_set_upload_enabled` does.
+#[no_mangle]
+pub extern "system" fn Java_org_mozilla_metrics_MetricsController_nativeSetUploadEnabled(
+    _env: *mut JNIEnv,
+    _class: jclass,
+    handle: jlong,
+    enabled: jboolean)
+    -> jint {
+    status(|| {
+        try!(from_handle(handle as *mut Foreign)).set_upload_enabled(enabled != 0);
+        Ok(MetricsStatus::MetricsOk)
+    }) as jint
+}
+
+/// Sends everything recorded so far, as `metrics_flush` does.
+#[no_mangle]
+pub extern "system" fn Java_org_mozilla_metrics_MetricsController_nativeFlush(
+    _env: *mut JNIEnv,
+    _class: jclass,
+    handle: jlong)
+    -> jint {
+    super::metrics_flush(handle as *mut Foreign) as jint
+}
+
+/// Stops and frees the controller, as `metrics_shutdown` does.
+#[no_mangle]
+pub extern "system" fn Java_org_mozilla_metrics_MetricsController_nativeShutdown(
+    _env: *mut JNIEnv,
+    _class: jclass,
+    handle: jlong)
+    -> jint {
+    super::metrics_shutdown(handle as *mut Foreign) as jint
+}
+
+/// The description of the last error on the calling thread.
+#[no_mangle]
+pub extern "system" fn Java_org_mozilla_metrics_MetricsController_nativeLastError(
+    env: *mut JNIEnv,
+    _class: jclass)
+    -> jstring {
+    catch_panic(ptr::null_mut(), || {
+        let message = unsafe { CStr::from_ptr(metrics_last_error()) };
+        unsafe { ((**env).NewStringUTF)(env, message.as_ptr()) }
+    })
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! jni {
+    before_each {
+        use std::ffi::CString;
+
+        // A JNIEnv without a JVM.  Its strings are C strings.
+        unsafe extern "system" fn new_string_utf(_env: *mut JNIEnv, utf: *const c_char) -> jstring {
+            CString::new(CStr::from_ptr(utf).to_bytes()).unwrap().into_raw() as jstring
+        }
+        unsafe extern "system" fn get_string_utf_chars(_env: *mut JNIEnv,
+                                                       string: jstring,
+                                                       _is_copy: *mut jboolean)
+                                                       -> *const c_char {
+            string as *const c_char
+        }
+        unsafe extern "system" fn release_string_utf_chars(_env: *mut JNIEnv,
+                                                           _string: jstring,
+                                                           _utf: *const c_char) {
+        }
+        let table = JNINativeInterface {
+            unused: [ptr::null_mut(); SLOTS_BEFORE_NEW_STRING_UTF],
+            NewStringUTF: new_string_utf,
+            GetStringUTFLength: ptr::null_mut(),
+            GetStringUTFChars: get_string_utf_chars,
+            ReleaseStringUTFChars: release_string_utf_chars,
+        };
+        let mut interface: JNIEnv = &table;
+        let env: *mut JNIEnv = &mut interface;
+        let class: jclass = ptr::null_mut();
+
+        let strings: Vec<CString> = ["app", "1.0", "default", "java", "en-us", "pi", "arm", "linux",
+                                     "4.4", "eng", "click", "button", "test_jni_storage"]
+            .iter()
+            .map(|s| CString::new(*s).unwrap())
+            .collect();
+        let java = |i: usize| strings[i].as_ptr() as jstring;
+        let last_error = || unsafe {
+            let message = Java_org_mozilla_metrics_MetricsController_nativeLastError(env, class);
+            CString::from_raw(message as *mut c_char).into_string().unwrap()
+        };
+    }
+
+    it "should record events through a handle" {
+        use std::fs;
+
+        let handle = Java_org_mozilla_metrics_MetricsController_nativeInit(
+            env, class, java(0), java(1), java(2), java(3), java(4), java(5), java(6), java(7),
+            java(8), java(12));
+        assert!(handle != 0);
+        // The events are ignored, so the urgent one does not make the worker
+        // send anything.
+        assert_eq!(Java_org_mozilla_metrics_MetricsController_nativeSetUploadEnabled(
+                       env, class, handle, 0),
+                   MetricsStatus::MetricsOk as jint);
+        assert_eq!(Java_org_mozilla_metrics_MetricsController_nativeRecordEvent(
+                       env, class, handle, java(9), java(10), java(11), 1),
+                   MetricsStatus::MetricsOk as jint);
+        assert_eq!(Java_org_mozilla_metrics_MetricsController_nativeRecordUrgentEvent(
+                       env, class, handle, java(9), java(10), java(11), 1),
+                   MetricsStatus::MetricsOk as jint);
+        assert_eq!(Java_org_mozilla_metrics_MetricsController_nativeRecordFloatingPointEvent(
+                       env, class, handle, java(9), java(10), java(11), 0.5),
+                   MetricsStatus::MetricsOk as jint);
+        assert_eq!(Java_org_mozilla_metrics_MetricsController_nativeRecordEvent(
+                       env, class, handle, java(9), java(10), java(11), -1),
+                   MetricsStatus::MetricsInvalidArgument as jint);
+        assert_eq!(last_error(), "event_value is negative: -1");
+        assert_eq!(Java_org_mozilla_metrics_MetricsController_nativeSetUploadEnabled(
+                       env, class, handle, 1),
+                   MetricsStatus::MetricsOk as jint);
+        assert_eq!(Java_org_mozilla_metrics_MetricsController_nativeShutdown(env, class, handle),
+                   MetricsStatus::MetricsOk as jint);
+        let _ = fs::remove_dir_all("test_jni_storage");
+    }
+
+    it "should report invalid arguments" {
+        assert_eq!(Java_org_mozilla_metrics_MetricsController_nativeInit(
+                       env, class, java(0), java(1), java(2), java(3), java(4), ptr::null_mut(),
+                       java(6), java(7), java(8), ptr::null_mut()),
+                   0);
+        assert_eq!(last_error(), "device is null");
+
+        assert_eq!(Java_org_mozilla_metrics_MetricsController_nativeRecordEvent(
+                       env, class, 0, java(9), java(10), java(11), 1),
+                   MetricsStatus::MetricsNullHandle as jint);
+        assert_eq!(Java_org_mozilla_metrics_MetricsController_nativeFlush(env, class, 0),
+                   MetricsStatus::MetricsNullHandle as jint);
+    }
+}
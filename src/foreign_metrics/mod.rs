@@ -0,0 +1,521 @@
+// This file is specifically for use from non-Rust applications.  It is very
+// similar to the MetricsController object except that the interface is
+// flattened out to match a C API calling standard: init_metrics returns an
+// opaque handle to a controller, which every other call takes.
+//
+// No call trusts its arguments: NULL pointers are reported as errors, strings
+// that are not UTF-8 (e.g. Latin-1) are converted lossily, and a panic is
+// caught and reported as MetricsPanic instead of unwinding into the caller.
+//
+// The jni module exports the same calls to Java.
+//
+
+use controller::{ControllerOptions, EventInfo, MetricsController};
+use events::Priority;
+use validation::EventError;
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use std::cell::RefCell;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::str::from_utf8;
+
+pub mod jni;
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+/// What a call returns.  `metrics_last_error` describes any status other
+/// than `MetricsOk`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricsStatus {
+    MetricsOk = 0,
+    /// The handle is NULL.
+    MetricsNullHandle = 1,
+    /// A field of the event is longer than the Measurement Protocol allows.
+    MetricsFieldTooLong = 2,
+    /// The event is longer than the Measurement Protocol allows.
+    MetricsHitTooLong = 3,
+    /// Some events could not be sent.  They are kept for the next send.
+    MetricsNotSent = 4,
+    /// A string argument is NULL.
+    MetricsInvalidArgument = 5,
+    /// The library failed unexpectedly.  The controller may still work.
+    MetricsPanic = 6,
+}
+
+impl<'a> From<&'a EventError> for MetricsStatus {
+    fn from(error: &EventError) -> MetricsStatus {
+        match *error {
+            EventError::FieldTooLong { .. } => MetricsStatus::MetricsFieldTooLong,
+            EventError::HitTooLong { .. } => MetricsStatus::MetricsHitTooLong,
+        }
+    }
+}
+
+// Message describing the last failed call on each thread, for
+// metrics_last_error.
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::new("").unwrap());
+}
+
+fn set_last_error(message: &str) {
+    logger().log(LogLevelFilter::Error, message);
+    // A message with an interior NUL is cut there.
+    let message = message.split('\0').next().unwrap_or("");
+    LAST_ERROR.with(|last_error| {
+        *last_error.borrow_mut() = CString::new(message).unwrap();
+    });
+}
+
+/// Returns a description of the last error on the calling thread, e.g. why
+/// `record_event` did not return `MetricsOk`, or an empty string.  The string
+/// belongs to the library and stays valid until the next failing call on the
+/// same thread.
+#[no_mangle]
+pub extern "C" fn metrics_last_error() -> *const c_char {
+    catch_panic(b"\0".as_ptr() as *const c_char,
+                || LAST_ERROR.with(|last_error| last_error.borrow().as_ptr()))
+}
+
+/// Initializes a controller for the Metrics Libary and returns a handle to
+/// it.  Caller passes information about their application and environment.
+/// This information will be associated with the metrics data recorded by the
+/// `record_event` function.
+///
+/// *storage_dir* is where metricsconfig.json is read from and the library
+/// keeps its files (client id, saved events, ...), or NULL for the current
+/// directory.  Components in the same process each need their own directory.
+///
+/// Note that it is mandatory to call init_metrics before calling record_event,
+/// and to pass the returned handle to it.  `metrics_shutdown` frees the
+/// controller; init_metrics can then be called again.
+///
+/// # Examples
+///
+/// ```ignore
+/// let handle = init_metrics("myapp",
+///         "1.0",
+///         "default",
+///         "20160303",
+///         "c",
+///         "en-us",
+///         "pi",
+///         "LAMP",
+///         "linux",
+///         "redhat 1.0",
+///         "/var/lib/myapp/metrics");
+/// ```
+#[no_mangle]
+pub extern "C" fn init_metrics(app_name: *const c_char,
+                               app_version: *const c_char,
+                               app_update_channel: *const c_char,
+                               app_platform: *const c_char,
+                               locale: *const c_char,
+                               device: *const c_char,
+                               arch: *const c_char,
+                               os: *const c_char,
+                               os_version: *const c_char,
+                               storage_dir: *const c_char)
+                               -> *mut Foreign {
+    catch_panic(ptr::null_mut(), || {
+        match init(app_name,
+                   app_version,
+                   app_update_channel,
+                   app_platform,
+                   locale,
+                   device,
+                   arch,
+                   os,
+                   os_version,
+                   storage_dir) {
+            Ok(foreign) => {
+                logger().log(LogLevelFilter::Info, "Initialized Metrics Library.");
+                Box::into_raw(Box::new(foreign))
+            }
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+fn init(app_name: *const c_char,
+        app_version: *const c_char,
+        app_update_channel: *const c_char,
+        app_platform: *const c_char,
+        locale: *const c_char,
+        device: *const c_char,
+        arch: *const c_char,
+        os: *const c_char,
+        os_version: *const c_char,
+        storage_dir: *const c_char)
+        -> Result<Foreign, MetricsStatus> {
+    let app_name = try!(c_to_string(app_name, "app_name"));
+    let app_version = try!(c_to_string(app_version, "app_version"));
+    let app_update_channel = try!(c_to_string(app_update_channel, "app_update_channel"));
+    let app_platform = try!(c_to_string(app_platform, "app_platform"));
+    let locale = try!(c_to_string(locale, "locale"));
+    let device = try!(c_to_string(device, "device"));
+    let arch = try!(c_to_string(arch, "arch"));
+    let os = try!(c_to_string(os, "os"));
+    let os_version = try!(c_to_string(os_version, "os_version"));
+    let ev: EventInfo = EventInfo::new(&locale,
+                                       &os,
+                                       &os_version,
+                                       &device,
+                                       &app_name,
+                                       &app_version,
+                                       &app_update_channel,
+                                       &app_platform,
+                                       &arch);
+    let mut options = ControllerOptions::new();
+    if !storage_dir.is_null() {
+        options.storage_dir = Some(try!(c_to_string(storage_dir, "storage_dir")));
+    }
+    Ok(Foreign::new(MetricsController::with_options(ev, options)))
+}
+
+/// Constructs a new event which is batched and sent to the Google Analytics
+/// server.
+///
+/// Params:
+///
+/// *handle* -- The controller returned by `init_metrics`.
+///
+/// *event_category* -- Category of the event. For example, &apos;eng&apos; or &apos;user&apos;
+///
+/// *event_action* -- Action that the user took or what happened to trigger. For example, &apos;open-app&apos;
+///
+/// *event_label* -- Description of what the metric is. For example, &apos;memory&apos;
+///
+/// *event_value* -- Numeric value of the metric.  It must not be negative.
+///
+/// Returns:
+///
+/// *MetricsOk* - Success
+///
+/// *MetricsInvalidArgument* - A string is NULL or `event_value` is negative.
+///
+/// Another status - Error, unable to record the event.  `metrics_last_error`
+/// says why, e.g. that a field is longer than the Measurement Protocol allows.
+#[no_mangle]
+pub extern "C" fn record_event(handle: *mut Foreign,
+                               event_category: *const c_char,
+                               event_action: *const c_char,
+                               event_label: *const c_char,
+                               event_value: i32)
+                               -> MetricsStatus {
+    status(|| {
+        let foreign = try!(from_handle(handle));
+        let event_category = try!(c_to_string(event_category, "event_category"));
+        let event_action = try!(c_to_string(event_action, "event_action"));
+        let event_label = try!(c_to_string(event_label, "event_label"));
+        if event_value < 0 {
+            set_last_error(&format!("event_value is negative: {}", event_value));
+            return Err(MetricsStatus::MetricsInvalidArgument);
+        }
+        Ok(foreign.record_event(&event_category,
+                                &event_action,
+                                &event_label,
+                                event_value as u64,
+                                Priority::Normal))
+    })
+}
+/// Constructs a new event which is batched and sent to the Google Analytics
+/// server.
+///
+/// Params:
+///
+/// *handle* -- The controller returned by `init_metrics`.
+///
+/// *event_category* -- Category of the event. For example, &apos;eng&apos; or &apos;user&apos;
+///
+/// *event_action* -- Action that the user took or what happened to trigger. For example, &apos;open-app&apos;
+///
+/// *event_label* -- Description of what the metric is. For example, &apos;memory&apos;
+///
+/// *event_value* -- Numeric value of the metric, which is a floating point.
+///
+/// Returns:
+///
+/// *MetricsOk* - Success
+///
+/// Another status - Error, unable to record the event.  `metrics_last_error`
+/// says why.
+#[no_mangle]
+pub extern "C" fn record_floating_point_event(handle: *mut Foreign,
+                                              event_category: *const c_char,
+                                              event_action: *const c_char,
+                                              event_label: *const c_char,
+                                              event_value: f32)
+                                              -> MetricsStatus {
+    status(|| {
+        let foreign = try!(from_handle(handle));
+        let event_category = try!(c_to_string(event_category, "event_category"));
+        let event_action = try!(c_to_string(event_action, "event_action"));
+        let event_label = try!(c_to_string(event_label, "event_label"));
+
+        Ok(foreign.record_floating_point_event(&event_category,
+                                               &event_action,
+                                               &event_label,
+                                               event_value as f64))
+    })
+}
+
+/// Turns uploading on or off, as `MetricsController::set_upload_enabled`
+/// does.  The setting is kept across restarts.  Disabling upload deletes the
+/// events that have not been sent yet and ignores events recorded afterwards.
+///
+/// Returns:
+///
+/// *MetricsOk* - Success
+///
+/// *MetricsNullHandle* - Error, `metrics_last_error` says why
+#[no_mangle]
+pub extern "C" fn metrics_set_upload_enabled(handle: *mut Foreign,
+                                             enabled: bool)
+                                             -> MetricsStatus {
+    status(|| {
+        try!(from_handle(handle)).set_upload_enabled(enabled);
+        Ok(MetricsStatus::MetricsOk)
+    })
+}
+
+/// Sends everything recorded so far and waits until it has been sent, e.g.
+/// before the application exits.  The network settings still apply.
+///
+/// Returns:
+///
+/// *MetricsOk* - Success
+///
+/// Another status - Error, e.g. *MetricsNotSent* when the network did not
+/// allow sending.  `metrics_last_error` says why.
+#[no_mangle]
+pub extern "C" fn metrics_flush(handle: *mut Foreign) -> MetricsStatus {
+    status(|| Ok(try!(from_handle(handle)).flush()))
+}
+
+/// Stops the controller's worker, waits for it to finish and frees the
+/// controller.  The handle must not be used afterwards.  Call `metrics_flush`
+/// first to send what has been recorded.
+///
+/// Returns:
+///
+/// *MetricsOk* - Success
+///
+/// *MetricsNullHandle* - Error, `metrics_last_error` says why
+#[no_mangle]
+pub extern "C" fn metrics_shutdown(handle: *mut Foreign) -> MetricsStatus {
+    status(|| {
+        try!(from_handle(handle));
+        // Freed even if shutting down panics.
+        let foreign = unsafe { Box::from_raw(handle) };
+        foreign.controller().shutdown();
+        logger().log(LogLevelFilter::Info, "Shut down Metrics Library.");
+        Ok(MetricsStatus::MetricsOk)
+    })
+}
+
+// Runs `f`, turning a panic into `default` (with the last error set) so that
+// it does not unwind into the caller.
+fn catch_panic<T, F: FnOnce() -> T>(default: T, f: F) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(cause) => {
+            let message = match cause.downcast_ref::<&'static str>() {
+                Some(message) => message.to_string(),
+                None => {
+                    match cause.downcast_ref::<String>() {
+                        Some(message) => message.clone(),
+                        None => "unknown cause".to_string(),
+                    }
+                }
+            };
+            set_last_error(&format!("Internal error: {}", message));
+            default
+        }
+    }
+}
+
+// The status of a call whose checks fail with an error status.
+fn status<F>(f: F) -> MetricsStatus
+    where F: FnOnce() -> Result<MetricsStatus, MetricsStatus>
+{
+    catch_panic(MetricsStatus::MetricsPanic, || {
+        match f() {
+            Ok(status) | Err(status) => status,
+        }
+    })
+}
+
+// The controller behind a handle from init_metrics, or MetricsNullHandle
+// (with the last error set) for a null handle.
+fn from_handle<'a>(handle: *mut Foreign) -> Result<&'a Foreign, MetricsStatus> {
+    if handle.is_null() {
+        set_last_error("null handle, init_metrics has not returned a controller");
+        return Err(MetricsStatus::MetricsNullHandle);
+    }
+    unsafe { Ok(&*handle) }
+}
+
+// Copies the string argument `name`.  Invalid UTF-8 sequences are replaced
+// with U+FFFD.
+fn c_to_string(cstr: *const c_char, name: &str) -> Result<String, MetricsStatus> {
+    if cstr.is_null() {
+        set_last_error(&format!("{} is NULL", name));
+        return Err(MetricsStatus::MetricsInvalidArgument);
+    }
+    let bytes = unsafe { CStr::from_ptr(cstr).to_bytes() };
+    match from_utf8(bytes) {
+        Ok(str) => Ok(str.to_string()),
+        Err(_) => {
+            logger().log(LogLevelFilter::Warn,
+                         format!("{} is not UTF-8, replacing the invalid bytes", name).as_str());
+            Ok(String::from_utf8_lossy(bytes).into_owned())
+        }
+    }
+}
+
+/// A controller created by `init_metrics`.  C callers only see a pointer to
+/// it.
+pub struct Foreign {
+    controller: Mutex<MetricsController>,
+}
+
+impl Foreign {
+    pub fn new(controller: MetricsController) -> Foreign {
+        Foreign { controller: Mutex::new(controller) }
+    }
+
+    // The controller, even if an earlier call panicked while holding it.
+    fn controller(&self) -> MutexGuard<MetricsController> {
+        self.controller.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn set_upload_enabled(&self, enabled: bool) {
+        self.controller().set_upload_enabled(enabled);
+    }
+
+    pub fn flush(&self) -> MetricsStatus {
+        // Other calls on the handle go on while the worker sends.
+        let flusher = self.controller().flusher();
+        if flusher.flush() {
+            MetricsStatus::MetricsOk
+        } else {
+            set_last_error("Could not send every event, they are kept for the next send");
+            MetricsStatus::MetricsNotSent
+        }
+    }
+
+    pub fn record_event(&self,
+                        event_category: &str,
+                        event_action: &str,
+                        event_label: &str,
+                        event_value: u64,
+                        priority: Priority)
+                        -> MetricsStatus {
+        let mut controller = self.controller();
+        match controller.record_event_with_priority(event_category,
+                                                    event_action,
+                                                    event_label,
+                                                    event_value,
+                                                    priority) {
+            Ok(()) => {
+                logger().log(LogLevelFilter::Info, "Recorded event");
+                MetricsStatus::MetricsOk
+            }
+            Err(e) => {
+                set_last_error(&format!("Could not record event: {}", e));
+                MetricsStatus::from(&e)
+            }
+        }
+    }
+
+    pub fn record_floating_point_event(&self,
+                                       event_category: &str,
+                                       event_action: &str,
+                                       event_label: &str,
+                                       event_value: f64)
+                                       -> MetricsStatus {
+        let mut controller = self.controller();
+        match controller.record_floating_point_event(event_category,
+                                                     event_action,
+                                                     event_label,
+                                                     event_value) {
+            Ok(()) => {
+                logger().log(LogLevelFilter::Info, "Recorded floating point event");
+                MetricsStatus::MetricsOk
+            }
+            Err(e) => {
+                set_last_error(&format!("Could not record floating point event: {}", e));
+                MetricsStatus::from(&e)
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! foreign_metrics {
+    it "should be declared in the generated header" {
+        // Run tools/gen_header.py when this fails.
+        let header = include_str!("../../include/metrics_controller.h");
+        let source = include_str!("mod.rs");
+        for line in source.lines().filter(|line| line.starts_with("pub extern \"C\" fn ")) {
+            let name = line["pub extern \"C\" fn ".len()..].split('(').next().unwrap();
+            assert!(header.contains(&format!(" {}(", name)), "{} is not in the header", name);
+        }
+        let last_status = format!("METRICS_PANIC = {},", MetricsStatus::MetricsPanic as i32);
+        assert!(header.contains(&last_status));
+    }
+
+    it "should reject a null handle" {
+        assert_eq!(metrics_flush(ptr::null_mut()), MetricsStatus::MetricsNullHandle);
+        assert_eq!(metrics_shutdown(ptr::null_mut()), MetricsStatus::MetricsNullHandle);
+        let error = unsafe { CStr::from_ptr(metrics_last_error()) };
+        assert!(error.to_str().unwrap().starts_with("null handle"));
+    }
+
+    it "should reject null strings" {
+        let app = CString::new("app").unwrap();
+        let app = app.as_ptr();
+        assert!(init_metrics(app, app, app, app, app, app, ptr::null(), app, app, ptr::null())
+            .is_null());
+        let error = unsafe { CStr::from_ptr(metrics_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "arch is NULL");
+        assert_eq!(c_to_string(ptr::null(), "event_label"),
+                   Err(MetricsStatus::MetricsInvalidArgument));
+    }
+
+    it "should reject a negative event value" {
+        use storage::TestStorage;
+
+        let test_storage = TestStorage::new("test_foreign_metrics");
+        let dir = CString::new(test_storage.storage().dir().to_str().unwrap()).unwrap();
+        let app = CString::new("app").unwrap();
+        let app = app.as_ptr();
+        let handle = init_metrics(app, app, app, app, app, app, app, app, app, dir.as_ptr());
+        assert!(!handle.is_null());
+        assert_eq!(record_event(handle, app, app, app, -1), MetricsStatus::MetricsInvalidArgument);
+        let error = unsafe { CStr::from_ptr(metrics_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "event_value is negative: -1");
+        assert_eq!(metrics_shutdown(handle), MetricsStatus::MetricsOk);
+    }
+
+    it "should replace what is not UTF-8" {
+        // "Café" in Latin-1.
+        let latin1 = CString::new(vec![b'C', b'a', b'f', 0xe9]).unwrap();
+        assert_eq!(c_to_string(latin1.as_ptr(), "event_label"), Ok("Caf\u{fffd}".to_string()));
+    }
+
+    it "should not let a panic unwind into the caller" {
+        assert_eq!(status(|| panic!("boom")), MetricsStatus::MetricsPanic);
+        let error = unsafe { CStr::from_ptr(metrics_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "Internal error: boom");
+    }
+}
@@ -17,8 +17,17 @@ extern crate log;
 extern crate lazy_static;
 
 pub mod controller;
-pub use controller::MetricsController;
-pub use foreign_metrics::Foreign;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use controller::{ControllerOptions, EventInfo, MetricsController};
+pub use events::Priority;
+pub use metrics_worker::Flusher;
+pub use foreign_metrics::{Foreign, MetricsStatus};
+pub use network::{NetworkPolicy, NetworkState};
+pub use observer::{WorkerEvent, WorkerObserver};
+pub use privacy::PrivacySettings;
+pub use scrubber::ScrubCallback;
+pub use stats::Stats;
+pub use validation::{EventError, HitMessage, HitReport};
 pub mod foreign_metrics;
 mod logger;
 mod metrics_worker;
@@ -26,5 +35,24 @@ mod metrics_worker;
 mod config;
 #[cfg(feature = "integration")]
 pub mod config;
+mod client_id;
+mod clock;
+mod compression;
+mod consent;
+mod connector;
+mod event_queue;
 mod events;
+mod network;
+mod observer;
+mod privacy;
+mod queue_time;
+mod sampling;
+mod scheduler;
+mod scrubber;
+mod session;
+mod sinks;
+mod stats;
+mod storage;
+mod telemetry;
 mod transmitter;
+mod validation;
@@ -2,30 +2,28 @@ extern crate env_logger;
 
 use log::{LogRecord, LogLevelFilter};
 use self::env_logger::LogBuilder;
-use std::collections::HashMap;
 use std::env;
 
 static LOG_PREFIX: &'static str = "CD-METRICS";
 
+// There is one logger for the process, not one per controller: the `log`
+// crate can only have one logger installed, which env_logger sets up from
+// CD_METRICS_LOG the first time it is asked for.  So every module of every
+// controller logs through the same MetricsLogger.
 lazy_static! {
-    // Types that can be assigned to `static` variables
-    // in this macro are limited to those that fulfill
-    // the `Sync` trait. We use 'HashMap' because it
-    // makes it easy to return the logger as a reference.
-    static ref LOG: HashMap<u32, MetricsLogger> = {
-        let mut m = HashMap::new();
+    static ref LOG: MetricsLogger = {
         let metrics_logger = MetricsLogger::new();
         metrics_logger.init();
-        m.insert(0, metrics_logger);
-        m
+        metrics_logger
     };
 }
 
 pub struct MetricsLoggerFactory;
 
 impl MetricsLoggerFactory {
+    /// The process-wide logger, shared by every controller.
     pub fn get_logger() -> &'static MetricsLogger {
-        LOG.get(&0).unwrap()
+        &*LOG
     }
 }
 
@@ -60,6 +58,7 @@ impl MetricsLogger {
         match level {
             LogLevelFilter::Info => info!("{} - {}", LOG_PREFIX, msg),
             LogLevelFilter::Debug => debug!("{} - {}", LOG_PREFIX, msg),
+            LogLevelFilter::Warn => warn!("{} - {}", LOG_PREFIX, msg),
             LogLevelFilter::Error => error!("{} - {}", LOG_PREFIX, msg),
             _ => println!("{} is not a supported log level", level),
         }
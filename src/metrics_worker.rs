@@ -1,334 +1,450 @@
-extern crate chrono;
-extern crate time;
-extern crate timer;
-extern crate serde_json;
-
+use clock::Clock;
+use config::Config;
 use log::LogLevelFilter;
 use logger::MetricsLoggerFactory;
 use logger::MetricsLogger;
 use events::Events;
+use network::{Network, NetworkPolicy, NetworkState};
+use observer::{Observers, WorkerEvent, WorkerObserver};
+use scheduler::{Schedule, Scheduler, TimerOp};
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, RecvError, RecvTimeoutError};
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
 use std::thread;
 use std::thread::JoinHandle;
-use transmitter::Transmitter;
+use sinks::Sinks;
 
 #[allow(non_upper_case_globals)]
 const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
 
-const DEFAULT_SEND: u64 = 1209600;
-const DEFAULT_SAVE: u64 = 3600;
-const DEFAULT_START: u64 = 0;
-
-pub enum TimerOp {
-    Send,
-    Save,
-    None,
-}
-
 pub enum ThreadMsg {
     Quit,
     Continue,
+    // A full batch of events is waiting.
+    QueueFull,
+    // Urgent events are waiting.
+    Urgent,
+    // The network may allow a deferred send now.
+    NetworkChanged,
+    // Delete the batches waiting to be sent and, if `deletion_request` is
+    // set, ask the backends to delete the user's data.
+    DeleteData { deletion_request: bool },
+    // Send everything now and say whether it all went.
+    Flush { done: Sender<bool> },
 }
 
-#[derive(Copy, Clone)]
-pub struct MetricsTimer {
-    send_interval: u64,
-    save_interval: u64,
-    start_time: u64,
+struct MetricsSender {
+    sender: Sender<ThreadMsg>,
 }
 
-impl MetricsTimer {
-    pub fn new() -> MetricsTimer {
-        MetricsTimer {
-            send_interval: DEFAULT_SEND,
-            save_interval: DEFAULT_SAVE,
-            start_time: DEFAULT_START,
-        }
+impl MetricsSender {
+    fn new() -> (MetricsSender, Receiver<ThreadMsg>, Sender<ThreadMsg>) {
+        let (sender, receiver) = channel();
+
+        let ms = MetricsSender { sender: sender.clone() };
+        (ms, receiver, sender.clone())
     }
+}
 
-    fn init(&mut self) {
-        self.init_from_config();
+// What the worker does between waits: sends and saves when they are due and
+// handles messages.  The worker thread runs it, or, without a thread, the
+// application through `MetricsWorker::poll`.
+struct WorkerState {
+    events: Arc<Mutex<Events>>,
+    // Shared with the MetricsWorker, which changes the policy.  The policy is
+    // asked without the events lock held.
+    network: Arc<Mutex<Network>>,
+    scheduler: Scheduler,
+    sinks: Sinks,
+    observers: Observers,
+}
 
-        if self.save_interval >= self.send_interval {
-            panic!("Fatal error.  Sending interval < Saving Interval")
+impl WorkerState {
+    fn new(events: Arc<Mutex<Events>>,
+           network: Arc<Mutex<Network>>,
+           cfg: &mut Config,
+           observers: Observers,
+           now: i64)
+           -> WorkerState {
+        let storage = events.lock().unwrap().storage().clone();
+        let mut scheduler = Scheduler::with_storage(Schedule::from_config(cfg), &storage);
+        scheduler.start(now);
+        WorkerState {
+            events: events,
+            network: network,
+            scheduler: scheduler,
+            sinks: Sinks::from_parsed_config(cfg, &storage),
+            observers: observers,
         }
     }
 
-    fn get_timer_interval(&mut self) -> i64 {
-        let now = time::get_time();
-        // This is the first time this device is starting up (ever)
-        if self.start_time == 0 {
-            self.start_time = now.sec as u64;
-            // TODO:  Write self.start_time in a separate file!!!
-            return self.save_interval as i64;
-        } else {
-            // Calculate the next interval.. either remaining time til send or
-            // time til save.
-            let secs_til_send = now.sec as u64 - self.start_time;
-            // If it's time to send in 60 seconds and save interval is 120 secs
-            // set the timer for 60 sec.  Otherwise, just set it for save interval.
-            if secs_til_send < self.save_interval {
-                return secs_til_send as i64;
-            } else {
-                return self.save_interval as i64;
+    // Runs everything that is due at `now` and returns the next deadline, or
+    // None once the worker has quit.
+    fn run(&mut self, now: i64) -> Option<i64> {
+        {
+            let mut ev_data = self.events.lock().unwrap();
+            if ev_data.urgent_len() > 0 {
+                self.scheduler.urgent(now);
+            }
+            if ev_data.is_time_to_send() {
+                self.scheduler.queue_full(now);
             }
         }
+        loop {
+            match self.scheduler.fire(now) {
+                TimerOp::None => break,
+                TimerOp::Save => {
+                    logger().log(LogLevelFilter::Debug, "TimerOp::Save");
+                    self.observers.notify(WorkerEvent::Save);
+                }
+                op => {
+                    logger().log(LogLevelFilter::Debug, format!("TimerOp::{:?}", op).as_str());
+                    let (network, retry_interval) = {
+                        let network = self.network.lock().unwrap();
+                        (network.state(), network.retry_interval())
+                    };
+                    match network {
+                        NetworkState::Unmetered => {
+                            self.send(op);
+                            self.scheduler.send_finished(now);
+                        }
+                        network => {
+                            logger().log(LogLevelFilter::Debug,
+                                         format!("Deferring send, network is {:?}", network).as_str());
+                            self.observers.notify(WorkerEvent::SendDeferred { network: network });
+                            self.scheduler.defer(now + retry_interval);
+                        }
+                    }
+                }
+            }
+        }
+        self.scheduler.next_deadline()
     }
 
-    fn get_timer_op(&mut self) -> TimerOp {
-        let now = time::get_time();
-        // This is the first time this device is starting up (ever)
-        if self.start_time == 0 {
-            return TimerOp::None;
-        } else {
-            // here if it's either time to send or time to save.
-            if now.sec as u64 - self.start_time >= self.send_interval {
-                self.start_time = 0; // so we know to start a new timer.
-                return TimerOp::Send;
+    // Sends what has been recorded, or only the urgent events, and tells the
+    // observers.  Returns true if everything was sent.  The observers are
+    // called without the events lock held.
+    fn send(&mut self, op: TimerOp) -> bool {
+        let pending = {
+            let ev_data = self.events.lock().unwrap();
+            if op == TimerOp::Urgent {
+                ev_data.urgent_len()
             } else {
-                return TimerOp::Save;
+                ev_data.urgent_len() + ev_data.len()
             }
-        }
+        };
+        self.observers.notify(WorkerEvent::SendStart { events: pending });
+        // The sinks only lock the events to take and encode them, not while
+        // they send.
+        let success = if op == TimerOp::Urgent {
+            self.sinks.dispatch_urgent(&self.events)
+        } else {
+            self.sinks.dispatch(&self.events)
+        };
+        self.observers.notify(WorkerEvent::SendResult { success: success });
+        success
     }
 
-    #[cfg(not(test))]
-    fn init_from_config(&mut self) {
-        use config::Config;
-        use self::serde_json::Value;
-
-        const KEY_START: &'static str = "startTime";
-        const KEY_SAVE: &'static str = "saveInterval";
-        const KEY_SEND: &'static str = "sendInterval";
-
-        let mut cfg = Config::new();
-        cfg.init("metricsconfig.json");
-        self.send_interval = cfg.get_u64(KEY_SEND);
-        self.save_interval = cfg.get_u64(KEY_SAVE);
-
-        // The startTime is a special case and could be empty initially.
-        let val: Option<Value> = cfg.get(KEY_START);
-        match val {
-            Some(_) => self.start_time = cfg.get_u64(KEY_START),
-            None => {
-                self.start_time = 0;
+    // Sends everything recorded so far, unless the network does not allow it.
+    fn flush(&mut self, now: i64) -> bool {
+        let network = self.network.lock().unwrap().state();
+        match network {
+            NetworkState::Unmetered => {
+                let success = self.send(TimerOp::Flush);
+                if success {
+                    self.scheduler.flushed(now);
+                }
+                success
+            }
+            network => {
+                self.observers.notify(WorkerEvent::SendDeferred { network: network });
+                false
             }
         }
     }
 
-    #[cfg(test)]
-    fn init_from_config(&mut self) {
-        self.send_interval = DEFAULT_SEND;
-        self.save_interval = DEFAULT_SAVE;
-        self.start_time = DEFAULT_START;
+    // Handles a message.  Returns false on Quit.
+    fn handle(&mut self, msg: ThreadMsg, now: i64) -> bool {
+        match msg {
+            ThreadMsg::Continue => self.observers.notify(WorkerEvent::Tick),
+            ThreadMsg::QueueFull | ThreadMsg::Urgent => {}
+            ThreadMsg::NetworkChanged => self.scheduler.network_changed(now),
+            ThreadMsg::DeleteData { deletion_request } => {
+                self.sinks.delete_pending();
+                if deletion_request {
+                    self.sinks.send_deletion_request(&self.events);
+                }
+            }
+            ThreadMsg::Flush { done } => {
+                let _ = done.send(self.flush(now));
+            }
+            ThreadMsg::Quit => {
+                self.scheduler.stop();
+                self.observers.notify(WorkerEvent::Quit);
+                return false;
+            }
+        }
+        true
     }
 }
 
-struct MetricsSender {
-    sender: Sender<ThreadMsg>,
+// The worker of an application that drives it with `poll`.
+struct PolledWorker {
+    receiver: Receiver<ThreadMsg>,
+    state: WorkerState,
+    quit: bool,
 }
 
-impl MetricsSender {
-    fn new() -> (MetricsSender, Receiver<ThreadMsg>, Sender<ThreadMsg>) {
-        let (sender, receiver) = channel();
+/// Flushes a worker without borrowing it, so that an application that shares
+/// the controller behind a lock need not hold the lock while it waits.
+#[derive(Clone)]
+pub struct Flusher {
+    sender: Sender<ThreadMsg>,
+    polled: Option<Arc<Mutex<PolledWorker>>>,
+    clock: Arc<Clock>,
+}
 
-        let ms = MetricsSender { sender: sender.clone() };
-        (ms, receiver, sender.clone())
+impl Flusher {
+    /// Sends everything recorded so far and waits until it has been sent,
+    /// like `MetricsController::flush`.
+    pub fn flush(&self) -> bool {
+        let now = self.clock.now_secs();
+        if let Some(ref polled) = self.polled {
+            let mut polled = polled.lock().unwrap();
+            return !polled.quit && polled.state.flush(now);
+        }
+        let (done, result) = channel();
+        if self.sender.send(ThreadMsg::Flush { done: done }).is_err() {
+            return false;
+        }
+        result.recv().unwrap_or(false)
     }
 }
 
 pub struct MetricsWorker {
     metrics_send: MetricsSender,
-    // Compiler bug? `join_handle` is used...
-    #[allow(dead_code)]
+    network: Arc<Mutex<Network>>,
+    observers: Observers,
+    clock: Arc<Clock>,
     join_handle: Option<JoinHandle<()>>,
+    // Shared with the Flushers, which flush it directly.
+    polled: Option<Arc<Mutex<PolledWorker>>>,
 }
 
 impl MetricsWorker {
-    pub fn new(event_mutex: Arc<Mutex<Events>>) -> MetricsWorker {
+    /// A worker with its own thread, set up as `cfg` says.
+    pub fn new(event_mutex: Arc<Mutex<Events>>, cfg: &mut Config, clock: Arc<Clock>) -> MetricsWorker {
         let (ms, receiver, sender) = MetricsSender::new();
         let event = event_mutex.clone();
+        // A clock that does not run by itself wakes the worker when it is
+        // advanced, so the worker can check whether a deadline has passed.
+        let waker = sender.clone();
+        clock.on_advance(Box::new(move || {
+            let _ = waker.send(ThreadMsg::Continue);
+        }));
+        let network = Arc::new(Mutex::new(Network::from_config(cfg)));
+        let worker_net = network.clone();
+        let mut worker_cfg = cfg.clone();
+        let observers = Observers::new();
+        let notify = observers.clone();
+        let worker_clock = clock.clone();
         MetricsWorker {
             metrics_send: ms,
+            network: network,
+            observers: observers,
+            clock: clock,
             join_handle: Some(thread::spawn(move || {
-                let mut mt = MetricsTimer::new();
-                mt.init();
-
-                let timer = timer::Timer::new();
-                let mut tt = ThreadTest::new();
+                let clock = worker_clock;
+                let mut state = WorkerState::new(event,
+                                                 worker_net,
+                                                 &mut worker_cfg,
+                                                 notify.clone(),
+                                                 clock.now_secs());
                 loop {
-                    let timer_result = mt.get_timer_op();
-                    match timer_result {
-                        TimerOp::None => {
-                            logger().log(LogLevelFilter::Debug, "TimerOp::None");
-                        }
-                        TimerOp::Send => {
-                            logger().log(LogLevelFilter::Debug, "TimerOp::Send");
-                            let mut ev_data = event.lock().unwrap();
-                            if !ev_data.is_empty() {
-                                Transmitter::new().transmit(ev_data.get_events_as_body());
-                            }
-                        }
-                        TimerOp::Save => {
-                            logger().log(LogLevelFilter::Debug, "TimerOp::Save");
-                            let mut ev_data = event.lock().unwrap();
-                            if ev_data.is_time_to_send() {
-                                Transmitter::new().transmit(ev_data.get_events_as_body());
-                            }
-                        }
-                    }
-                    let dur: i64 = mt.get_timer_interval();
-                    let tx = sender.clone();
-                    let guard = timer.schedule_with_delay(chrono::Duration::seconds(dur),
-                                                          move || {
-                                                              tx.send(ThreadMsg::Continue).unwrap();
-                                                          });
-                    // The guard variable is need to ensure that the timer does not
-                    // go out of scope.  It is a feature of the timer library to make sure that
-                    // there are not extra timers lying around.  Then this brings a warning
-                    // that guard is not used so the ignore function is essentially a no-op.
-                    guard.ignore();
+                    let deadline = match state.run(clock.now_secs()) {
+                        Some(deadline) => deadline,
+                        None => break,
+                    };
+                    notify.notify(WorkerEvent::Wait { deadline: deadline });
                     // This is a blocking call
-                    let res = receiver.recv();
+                    let res = wait(&receiver, &*clock, deadline);
                     logger().log(LogLevelFilter::Debug, "After recv");
 
-                    tt.increment();
-
                     match res {
-                        Ok(val) => {
-                            match val {
-                                ThreadMsg::Continue => continue,
-                                ThreadMsg::Quit => {
-                                    tt.write();
-                                    break;
-                                }
+                        Ok(msg) => {
+                            if !state.handle(msg, clock.now_secs()) {
+                                break;
                             }
                         }
                         Err(err) => {
-                            println!("error: {}", err);
+                            // Every sender is gone, so nothing can ask the
+                            // worker to do anything, or to quit, any more.
+                            logger().log(LogLevelFilter::Debug,
+                                         format!("Stopping the worker: {}", err).as_str());
+                            break;
                         }
                     }
                 }
             })),
+            polled: None,
         }
     }
 
-    pub fn quit(&self) {
-        self.metrics_send.sender.send(ThreadMsg::Quit).unwrap();
+    /// A worker without a thread of its own.  Nothing is saved or sent until
+    /// the application calls `poll` or `tick`.
+    pub fn polled(event_mutex: Arc<Mutex<Events>>, cfg: &mut Config, clock: Arc<Clock>) -> MetricsWorker {
+        let (ms, receiver, _) = MetricsSender::new();
+        let network = Arc::new(Mutex::new(Network::from_config(cfg)));
+        let observers = Observers::new();
+        let state = WorkerState::new(event_mutex,
+                                     network.clone(),
+                                     cfg,
+                                     observers.clone(),
+                                     clock.now_secs());
+        MetricsWorker {
+            metrics_send: ms,
+            network: network,
+            observers: observers,
+            clock: clock,
+            join_handle: None,
+            polled: Some(Arc::new(Mutex::new(PolledWorker {
+                receiver: receiver,
+                state: state,
+                quit: false,
+            }))),
+        }
     }
-}
 
-// This is a test struct used for integration tests.  It writes the result of
-// what the thread loop does to a file that is read and validated by the
-// integration test.
-struct ThreadTest {
-    timer_count: u8,
-}
-
-impl ThreadTest {
-    fn new() -> ThreadTest {
-        ThreadTest { timer_count: 0 }
+    /// Runs what is due at `now` (seconds since the epoch) and returns when
+    /// to poll again, or None once the worker has quit.  Without a worker
+    /// created by `polled`, it does nothing and returns None.
+    pub fn poll(&mut self, now: i64) -> Option<i64> {
+        let mut polled = match self.polled {
+            Some(ref polled) => polled.lock().unwrap(),
+            None => return None,
+        };
+        let polled = &mut *polled;
+        if polled.quit {
+            return None;
+        }
+        polled.state.observers.notify(WorkerEvent::Tick);
+        while let Ok(msg) = polled.receiver.try_recv() {
+            if !polled.state.handle(msg, now) {
+                polled.quit = true;
+                return None;
+            }
+        }
+        let deadline = polled.state.run(now);
+        if let Some(deadline) = deadline {
+            polled.state.observers.notify(WorkerEvent::Wait { deadline: deadline });
+        }
+        deadline
     }
 
-    fn increment(&mut self) {
-        self.timer_count = self.timer_count + 1;
+    /// `poll` at the time of the worker's clock.
+    pub fn tick(&mut self) -> Option<i64> {
+        let now = self.clock.now_secs();
+        self.poll(now)
     }
 
-    #[cfg(not(test))]
-    fn write(&mut self) {
-        use std::fs::File;
-        use std::error::Error;
-        use std::io::prelude::*;
+    pub fn quit(&self) {
+        // The worker may already have quit.
+        let _ = self.metrics_send.sender.send(ThreadMsg::Quit);
+    }
 
-        match File::create("thread.dat") {
-            Err(why) => panic!("couldn't open:{}", Error::description(&why)),
-            Ok(mut f) => {
-                let write_res = f.write(&[self.timer_count]);
-                match write_res {
-                    Ok(count) => {
-                        println!("{} bytes written", count);
-                    }
-                    Err(e) => panic!("couldn't write: {}", Error::description(&e)),
-                }
-                match f.sync_all() {
-                    Ok(_) => {
-                        drop(f);
-                    }
-                    Err(e) => panic!("couldn't flush: {}", Error::description(&e)),
-                }
-            }
+    /// Stops the worker and waits for its thread to finish, e.g. a send in
+    /// progress.
+    pub fn shutdown(&mut self) {
+        // The worker may already have quit.
+        let _ = self.metrics_send.sender.send(ThreadMsg::Quit);
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+        if let Some(ref polled) = self.polled {
+            polled.lock().unwrap().quit = true;
         }
     }
 
-    #[cfg(test)]
-    fn write(&mut self) {
-        logger().log(LogLevelFilter::Debug, "Calling the no-op ThreadTest::write");
+    /// Sends everything recorded so far and waits until it has been sent.
+    /// Returns false if something could not be sent, e.g. because the
+    /// network policy does not allow it or the worker has quit.
+    pub fn flush(&self) -> bool {
+        self.flusher().flush()
     }
-}
 
+    /// A handle that flushes this worker.
+    pub fn flusher(&self) -> Flusher {
+        Flusher {
+            sender: self.metrics_send.sender.clone(),
+            polled: self.polled.clone(),
+            clock: self.clock.clone(),
+        }
+    }
 
-#[cfg(not(feature = "integration"))]
-#[cfg(test)]
-describe! metrics_timer {
-    before_each {
-// Required to prevent 'unused import' compiler warning
-        #[allow(unused_imports)]
-        use metrics_worker::time::get_time;
-        let mut mt = MetricsTimer::new();
-        mt.send_interval= 1209600;     // Two weeks default
-        mt.save_interval= 3600;        // one hour default
-        mt.start_time= 0;
+    /// Tells the worker a full batch of events is waiting, so that it sends
+    /// it after the flush delay.
+    pub fn queue_full(&self) {
+        let _ = self.metrics_send.sender.send(ThreadMsg::QueueFull);
     }
 
-    it "should calculate the correct interval the first time" {
-        let dur: i64 = mt.get_timer_interval();
-        assert_eq!(dur, mt.save_interval as i64);
+    /// Tells the worker urgent events are waiting, so that it sends them now.
+    pub fn urgent(&self) {
+        let _ = self.metrics_send.sender.send(ThreadMsg::Urgent);
     }
 
-    it "should correctly set the start time the first time" {
-        mt.get_timer_interval();
-        assert!(mt.start_time > 0);
+    /// Replaces the policy that decides whether the worker may send.
+    pub fn set_network_policy(&self, policy: Box<NetworkPolicy>) {
+        self.network.lock().unwrap().set_policy(policy);
     }
 
-    it "should correctly return the save interval when remaining seconds greater than save interval" {
-        mt.start_time = get_time().sec as u64 - 10000;
-        let dur = mt.get_timer_interval();
-        assert_eq!(dur as u64, mt.save_interval);
+    /// Marks the current link as metered, or unmarks it.
+    pub fn set_metered(&self, metered: bool) {
+        self.network.lock().unwrap().set_metered(metered);
     }
 
-    it "should correctly return the remaining seconds until the save time" {
-        mt.start_time = get_time().sec as u64 - 400;
-        mt.save_interval = 500;
-        let dur = mt.get_timer_interval();
-        assert!(dur > 399 && dur < 401);
+    /// Tells the worker to try a deferred send again.
+    pub fn network_changed(&self) {
+        let _ = self.metrics_send.sender.send(ThreadMsg::NetworkChanged);
     }
 
-    it "should return None operation the first time it starts" {
-        let timer_result = mt.get_timer_op();
-        match timer_result {
-            TimerOp::None => assert!(true),
-            _ => assert!(false),
-        }
+    /// Calls `observer` on the worker thread for everything the worker does
+    /// from now on.  No lock of the worker or its events is held during the
+    /// call.
+    pub fn observe(&self, observer: WorkerObserver) {
+        self.observers.add(observer);
     }
 
-    it "should return Save op when it's not time to send" {
-        mt.start_time = get_time().sec as u64 - 10000;
-        let timer_result = mt.get_timer_op();
-        match timer_result {
-            TimerOp::Save => assert!(true),
-            _ => assert!(false),
-        }
+    /// Returns a receiver for what the worker does from now on.
+    pub fn events(&self) -> Receiver<WorkerEvent> {
+        self.observers.channel()
     }
 
-    it "should return Send op when it's time to send" {
-        mt.start_time = get_time().sec as u64 - 10000;
-        mt.send_interval = 100;
-        let timer_result = mt.get_timer_op();
-        match timer_result {
-            TimerOp::Send => assert!(true),
-            _ => assert!(false),
+    /// Asks the worker to delete the batches it has not sent yet.
+    pub fn delete_data(&self, deletion_request: bool) {
+        // The worker may already have quit, in which case there is nothing
+        // left for it to delete.
+        let _ = self.metrics_send.sender.send(ThreadMsg::DeleteData { deletion_request: deletion_request });
+    }
+}
+
+// Waits for a message or, failing that, until the clock reaches `deadline`,
+// which counts as a Continue.  Wakeups before the deadline are ignored.
+fn wait(receiver: &Receiver<ThreadMsg>, clock: &Clock, deadline: i64) -> Result<ThreadMsg, RecvError> {
+    loop {
+        let res = match clock.wait_until(deadline) {
+            Some(timeout) => {
+                match receiver.recv_timeout(timeout) {
+                    Ok(msg) => Ok(msg),
+                    Err(RecvTimeoutError::Timeout) => Ok(ThreadMsg::Continue),
+                    Err(RecvTimeoutError::Disconnected) => Err(RecvError),
+                }
+            }
+            None => receiver.recv(),
+        };
+        match res {
+            Ok(ThreadMsg::Continue) if clock.now_secs() < deadline => continue,
+            res => return res,
         }
     }
 }
@@ -338,8 +454,13 @@ describe! metrics_timer {
 describe! metrics_worker {
     before_each {
         use std::sync::{Arc, Mutex};
+        use std::sync::mpsc::channel;
+        use std::thread;
+        use std::time::Duration;
+        use clock::{Clock, ManualClock};
         use controller::EventInfo;
         use events::Events;
+        use storage::TestStorage;
 
         let event_info = EventInfo::new(
             "en-us",
@@ -352,7 +473,12 @@ describe! metrics_worker {
             "arm",
             "rust"
         );
-        let mw = MetricsWorker::new(Arc::new(Mutex::new(Events::new(event_info, "CD_ap".to_string()))));
+        let clock = Arc::new(ManualClock::new(1464215817000));
+        let test_storage = TestStorage::new("test_metrics_worker");
+        let storage = test_storage.storage().clone();
+        let mut cfg = Config::load(&storage);
+        let events = Events::new(event_info, "CD_ap".to_string(), &storage, &mut cfg, clock.clone());
+        let mw = MetricsWorker::new(Arc::new(Mutex::new(events)), &mut cfg, clock.clone());
     }
 
     it "should gracefully exit when quit is sent" {
@@ -360,4 +486,128 @@ describe! metrics_worker {
         mw.join_handle.unwrap().join().unwrap();
         assert!(true);
     }
+
+    it "should not panic when asked to quit twice" {
+        mw.quit();
+        mw.quit();
+        mw.join_handle.unwrap().join().unwrap();
+        mw.quit();
+    }
+
+    it "should stop when it is dropped without quitting" {
+        use clock::SystemClock;
+
+        let info = EventInfo::new("en-us", "linux", "1.2.3.", "raspberry-pi", "app", "1.0",
+                                  "default", "arm", "rust");
+        let system_clock: Arc<Clock> = Arc::new(SystemClock);
+        let events = Events::new(info, "CD_ap".to_string(), &storage, &mut cfg, system_clock.clone());
+        let mut dropped = MetricsWorker::new(Arc::new(Mutex::new(events)), &mut cfg, system_clock);
+        let join_handle = dropped.join_handle.take().unwrap();
+        drop(dropped);
+        join_handle.join().unwrap();
+        mw.quit();
+    }
+
+    it "should wait until the clock reaches the deadline" {
+        let (sender, receiver) = channel();
+        let waker = sender.clone();
+        clock.on_advance(Box::new(move || {
+            let _ = waker.send(ThreadMsg::Continue);
+        }));
+        let deadline = clock.now_secs() + 3600;
+        let advancing = clock.clone();
+        let handle = thread::spawn(move || {
+            advancing.advance(Duration::from_secs(1800));
+            advancing.advance(Duration::from_secs(1800));
+        });
+        match wait(&receiver, &*clock, deadline) {
+            Ok(ThreadMsg::Continue) => assert!(clock.now_secs() >= deadline),
+            _ => assert!(false),
+        }
+        handle.join().unwrap();
+        mw.quit();
+    }
+
+    it "should run without a thread when polled" {
+        let event_info = EventInfo::new("en-us", "linux", "1.2.3.", "raspberry-pi", "app", "1.0",
+                                        "default", "arm", "rust");
+        let mut cfg = Config::load(&storage);
+        let events = Events::new(event_info, "CD_ap".to_string(), &storage, &mut cfg, clock.clone());
+        let mut polled = MetricsWorker::polled(Arc::new(Mutex::new(events)), &mut cfg, clock.clone());
+        let observed = polled.events();
+        let start = clock.now_secs();
+
+        assert_eq!(polled.tick(), Some(start + 3600));
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(polled.poll(start + 3600), Some(start + 7200));
+        let mut seen = Vec::new();
+        while let Ok(event) = observed.try_recv() {
+            seen.push(event);
+        }
+        assert_eq!(seen,
+                   vec![WorkerEvent::Tick,
+                        WorkerEvent::Wait { deadline: start + 3600 },
+                        WorkerEvent::Tick,
+                        WorkerEvent::Save,
+                        WorkerEvent::Wait { deadline: start + 7200 }]);
+
+        polled.quit();
+        assert_eq!(polled.tick(), None);
+        assert_eq!(polled.tick(), None);
+        mw.quit();
+    }
+
+    it "should not flush on a network that does not allow it, nor after shutting down" {
+        use network::{Network, NetworkPolicy, NetworkState};
+
+        let event_info = EventInfo::new("en-us", "linux", "1.2.3.", "raspberry-pi", "app", "1.0",
+                                        "default", "arm", "rust");
+        let mut cfg = Config::load(&storage);
+        let events = Events::new(event_info, "CD_ap".to_string(), &storage, &mut cfg, clock.clone());
+        let mut offline = MetricsWorker::new(Arc::new(Mutex::new(events)), &mut cfg, clock.clone());
+        offline.set_network_policy(Box::new(|| NetworkState::Offline));
+        let observed = offline.events();
+
+        assert!(!offline.flush());
+        assert!(!offline.flusher().flush());
+        let mut deferred = false;
+        while let Ok(event) = observed.try_recv() {
+            deferred |= event == WorkerEvent::SendDeferred { network: NetworkState::Offline };
+        }
+        assert!(deferred);
+        let flusher = offline.flusher();
+        offline.shutdown();
+        assert!(offline.join_handle.is_none());
+        assert!(!offline.flush());
+        assert!(!flusher.flush());
+        mw.quit();
+    }
+
+    it "should tell observers about its cycles" {
+        let observed = mw.events();
+        let mut seen = Vec::new();
+        // The worker may not have set its first deadline yet, so keep going
+        // until a save cycle has run.
+        for _ in 0..20 {
+            clock.advance(Duration::from_secs(3600));
+            while let Ok(event) = observed.recv_timeout(Duration::from_millis(100)) {
+                seen.push(event);
+            }
+            if seen.contains(&WorkerEvent::Save) {
+                break;
+            }
+        }
+        assert!(seen.contains(&WorkerEvent::Tick));
+        assert!(seen.contains(&WorkerEvent::Save));
+
+        mw.quit();
+        let mut quit = false;
+        while let Ok(event) = observed.recv_timeout(Duration::from_secs(5)) {
+            if event == WorkerEvent::Quit {
+                quit = true;
+                break;
+            }
+        }
+        assert!(quit);
+    }
 }
@@ -0,0 +1,245 @@
+extern crate serde_json;
+
+use config::{Config, get_optional_u64};
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::serde_json::Value;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+
+// Decides whether the worker may send now.  Sends are deferred while the
+// device is offline or on a metered link (e.g. LTE), and retried every
+// "retryInterval" seconds (default 60), or as soon as the application says
+// the network changed.
+//
+// By default any link is fine.  With "interfaces" in the "network" object of
+// metricsconfig.json, sending waits until one of those interfaces is up
+// according to /sys/class/net:
+//
+//     "network": { "interfaces": ["eth0", "wlan0"], "retryInterval": 60 }
+//
+// The application can also install its own policy, or mark the link as
+// metered through `MetricsController::set_metered`.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+const KEY_NETWORK: &'static str = "network";
+const SYS_CLASS_NET: &'static str = "/sys/class/net";
+const DEFAULT_RETRY_INTERVAL: i64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetworkState {
+    /// Sending is fine.
+    Unmetered,
+    /// Connected, but only through a link the user may pay for.
+    Metered,
+    Offline,
+}
+
+/// Tells the worker what kind of link the device is on.  It is asked before
+/// every send, on the worker thread, so it should return quickly.  Recording
+/// events does not wait for it.
+pub trait NetworkPolicy: Send {
+    fn state(&self) -> NetworkState;
+}
+
+impl<F> NetworkPolicy for F
+    where F: Fn() -> NetworkState + Send
+{
+    fn state(&self) -> NetworkState {
+        self()
+    }
+}
+
+/// Sends on any link.
+pub struct AnyNetwork;
+
+impl NetworkPolicy for AnyNetwork {
+    fn state(&self) -> NetworkState {
+        NetworkState::Unmetered
+    }
+}
+
+/// Sends only while one of the given interfaces is up.  While only other
+/// interfaces are up the link counts as metered.
+pub struct InterfacePolicy {
+    interfaces: Vec<String>,
+    sys_dir: String,
+}
+
+impl InterfacePolicy {
+    pub fn new(interfaces: Vec<String>) -> InterfacePolicy {
+        InterfacePolicy::with_sys_dir(interfaces, SYS_CLASS_NET)
+    }
+
+    /// Reads the interfaces from `sys_dir` instead of /sys/class/net.
+    pub fn with_sys_dir(interfaces: Vec<String>, sys_dir: &str) -> InterfacePolicy {
+        InterfacePolicy {
+            interfaces: interfaces,
+            sys_dir: sys_dir.to_string(),
+        }
+    }
+
+    fn is_up(&self, interface: &str) -> bool {
+        let mut state = String::new();
+        match File::open(format!("{}/{}/operstate", self.sys_dir, interface)) {
+            Ok(mut file) => file.read_to_string(&mut state).is_ok() && state.trim() == "up",
+            Err(_) => false,
+        }
+    }
+}
+
+impl NetworkPolicy for InterfacePolicy {
+    fn state(&self) -> NetworkState {
+        if self.interfaces.iter().any(|interface| self.is_up(interface)) {
+            return NetworkState::Unmetered;
+        }
+        let others_up = match fs::read_dir(&self.sys_dir) {
+            Ok(entries) => {
+                entries.filter_map(|entry| entry.ok())
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .any(|name| name != "lo" && self.is_up(&name))
+            }
+            Err(_) => false,
+        };
+        if others_up {
+            NetworkState::Metered
+        } else {
+            NetworkState::Offline
+        }
+    }
+}
+
+/// The network policy in use, and the metered flag set by the application.
+pub struct Network {
+    policy: Box<NetworkPolicy>,
+    metered: bool,
+    retry_interval: i64,
+}
+
+impl Network {
+    pub fn new(policy: Box<NetworkPolicy>) -> Network {
+        Network {
+            policy: policy,
+            metered: false,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+        }
+    }
+
+    pub fn from_config(cfg: &mut Config) -> Network {
+        match Network::from_parsed_config(cfg) {
+            Ok(network) => network,
+            Err(e) => {
+                logger().log(LogLevelFilter::Error,
+                             format!("Invalid network settings, sending on any link: {}", e).as_str());
+                Network::new(Box::new(AnyNetwork))
+            }
+        }
+    }
+
+    pub fn from_parsed_config(cfg: &mut Config) -> Result<Network, String> {
+        let value = match cfg.get(KEY_NETWORK) {
+            None => return Ok(Network::new(Box::new(AnyNetwork))),
+            Some(value @ Value::Object(_)) => value,
+            Some(_) => return Err("\"network\" must be an object".to_string()),
+        };
+        let mut network = match value.find("interfaces") {
+            None => Network::new(Box::new(AnyNetwork)),
+            Some(&Value::Array(ref names)) => {
+                let mut interfaces = Vec::new();
+                for name in names {
+                    match name.as_str() {
+                        Some(name) if !name.is_empty() => interfaces.push(name.to_string()),
+                        _ => return Err("network interfaces must be names".to_string()),
+                    }
+                }
+                Network::new(Box::new(InterfacePolicy::new(interfaces)))
+            }
+            Some(_) => return Err("network interfaces must be an array".to_string()),
+        };
+        match try!(get_optional_u64(&value, "retryInterval")) {
+            Some(0) => return Err("retryInterval must be at least 1".to_string()),
+            Some(secs) => network.retry_interval = secs as i64,
+            None => {}
+        }
+        Ok(network)
+    }
+
+    pub fn state(&self) -> NetworkState {
+        if self.metered {
+            NetworkState::Metered
+        } else {
+            self.policy.state()
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: Box<NetworkPolicy>) {
+        self.policy = policy;
+    }
+
+    pub fn set_metered(&mut self, metered: bool) {
+        self.metered = metered;
+    }
+
+    /// Seconds between checks while sending is deferred.
+    pub fn retry_interval(&self) -> i64 {
+        self.retry_interval
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! network {
+    it "should send on any link by default" {
+        use storage::Storage;
+
+        let mut network = Network::from_config(&mut Config::load(&Storage::current()));
+        assert_eq!(network.state(), NetworkState::Unmetered);
+        network.set_metered(true);
+        assert_eq!(network.state(), NetworkState::Metered);
+        network.set_metered(false);
+        network.set_policy(Box::new(|| NetworkState::Offline));
+        assert_eq!(network.state(), NetworkState::Offline);
+    }
+
+    it "should wait for a configured interface" {
+        use std::io::Write;
+
+        let sys_dir = "test_sys_class_net";
+        let _ = fs::remove_dir_all(sys_dir);
+        let set_state = |interface: &str, state: &str| {
+            fs::create_dir_all(format!("{}/{}", sys_dir, interface)).unwrap();
+            File::create(format!("{}/{}/operstate", sys_dir, interface))
+                .unwrap()
+                .write_all(state.as_bytes())
+                .unwrap();
+        };
+        let policy = InterfacePolicy::with_sys_dir(vec!["wlan0".to_string()], sys_dir);
+
+        set_state("lo", "unknown\n");
+        assert_eq!(policy.state(), NetworkState::Offline);
+        set_state("wwan0", "up\n");
+        assert_eq!(policy.state(), NetworkState::Metered);
+        set_state("wlan0", "up\n");
+        assert_eq!(policy.state(), NetworkState::Unmetered);
+        set_state("wlan0", "down\n");
+        assert_eq!(policy.state(), NetworkState::Metered);
+        fs::remove_dir_all(sys_dir).unwrap();
+    }
+
+    it "should read the network settings" {
+        let mut cfg = Config::new();
+        cfg.parse_json(r#"{ "network": { "interfaces": ["wlan0"], "retryInterval": 30 } }"#
+            .to_string());
+        let network = Network::from_parsed_config(&mut cfg).unwrap();
+        assert_eq!(network.retry_interval(), 30);
+
+        cfg.parse_json(r#"{ "network": { "interfaces": "wlan0" } }"#.to_string());
+        assert!(Network::from_parsed_config(&mut cfg).is_err());
+        cfg.parse_json(r#"{ "network": { "retryInterval": 0 } }"#.to_string());
+        assert!(Network::from_parsed_config(&mut cfg).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use network::NetworkState;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+
+// Lets tests and host applications follow what the worker thread does.
+// Observers are called on the worker thread, so they should return quickly;
+// `Observers::channel` hands the events to another thread instead.
+
+/// Something the worker thread did.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WorkerEvent {
+    /// The worker is waiting until `deadline` (seconds since the epoch).
+    Wait { deadline: i64 },
+    /// The worker's timer fired.
+    Tick,
+    /// A save cycle ran.
+    Save,
+    /// The worker started sending `events` new events.
+    SendStart { events: usize },
+    /// Sending finished; `success` is false if any sink kept batches for a
+    /// later retry.
+    SendResult { success: bool },
+    /// Sending was put off because of the kind of link the device is on.
+    SendDeferred { network: NetworkState },
+    /// The worker thread is exiting.
+    Quit,
+}
+
+pub type WorkerObserver = Box<Fn(&WorkerEvent) + Send>;
+
+/// The observers of a worker, shared with its thread.
+#[derive(Clone)]
+pub struct Observers {
+    observers: Arc<Mutex<Vec<WorkerObserver>>>,
+}
+
+impl Observers {
+    pub fn new() -> Observers {
+        Observers { observers: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    pub fn add(&self, observer: WorkerObserver) {
+        self.observers.lock().unwrap().push(observer);
+    }
+
+    /// Returns a receiver for the events from now on.  Once the receiver
+    /// is dropped, the events are no longer sent.
+    pub fn channel(&self) -> Receiver<WorkerEvent> {
+        let (sender, receiver) = channel();
+        self.add(Box::new(move |event: &WorkerEvent| {
+            let _ = sender.send(event.clone());
+        }));
+        receiver
+    }
+
+    /// Calls every observer with `event`.  The list is not locked during the
+    /// calls, so an observer may add others; they are called from the next
+    /// event on.  Only the worker notifies, so calls never overlap.
+    pub fn notify(&self, event: WorkerEvent) {
+        let observers = mem::replace(&mut *self.observers.lock().unwrap(), Vec::new());
+        for observer in &observers {
+            observer(&event);
+        }
+        let mut current = self.observers.lock().unwrap();
+        let added = mem::replace(&mut *current, observers);
+        current.extend(added);
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! observers {
+    it "should pass events to callbacks and channels" {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let observers = Observers::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        observers.add(Box::new(move |_: &WorkerEvent| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        let receiver = observers.channel();
+
+        observers.notify(WorkerEvent::Tick);
+        observers.notify(WorkerEvent::SendResult { success: true });
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(receiver.try_recv(), Ok(WorkerEvent::Tick));
+        assert_eq!(receiver.try_recv(), Ok(WorkerEvent::SendResult { success: true }));
+
+        drop(receiver);
+        observers.notify(WorkerEvent::Quit);
+    }
+
+    it "should let an observer add another one" {
+        let observers = Observers::new();
+        let inner = observers.clone();
+        let (sender, receiver) = channel();
+        let sender = Mutex::new(Some(sender));
+        observers.add(Box::new(move |_: &WorkerEvent| {
+            if let Some(sender) = sender.lock().unwrap().take() {
+                inner.add(Box::new(move |event: &WorkerEvent| {
+                    let _ = sender.send(event.clone());
+                }));
+            }
+        }));
+
+        observers.notify(WorkerEvent::Tick);
+        assert!(receiver.try_recv().is_err());
+        observers.notify(WorkerEvent::Save);
+        assert_eq!(receiver.try_recv(), Ok(WorkerEvent::Save));
+    }
+}
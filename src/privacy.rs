@@ -0,0 +1,182 @@
+extern crate serde_json;
+
+use config::{Config, get_optional_bool, get_optional_string};
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::serde_json::Value;
+use self::serde_json::builder::ObjectBuilder;
+
+// Privacy-related Measurement Protocol parameters added to every hit:
+//
+//     aip=1   the last octet of the sender's IP address is removed
+//     npa=1   the hit is not used for ads personalization
+//     ds=...  the data source, e.g. "app"
+//
+// The defaults come from metricsconfig.json, optionally overridden for the
+// application's update channel:
+//
+//     "privacy": {
+//         "anonymizeIp": false,
+//         "dataSource": "app",
+//         "channels": {
+//             "release": { "anonymizeIp": true, "nonPersonalizedAds": true }
+//         }
+//     }
+//
+// The application can replace them at run time with
+// `MetricsController::set_privacy`.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+const KEY_PRIVACY: &'static str = "privacy";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PrivacySettings {
+    /// Send `aip=1`.
+    pub anonymize_ip: bool,
+    /// Send `npa=1`.
+    pub non_personalized_ads: bool,
+    /// Sent as `ds` if set.
+    pub data_source: Option<String>,
+}
+
+impl PrivacySettings {
+    /// No privacy parameters.
+    pub fn new() -> PrivacySettings {
+        PrivacySettings::default()
+    }
+
+    /// The settings for the update channel `channel`.
+    pub fn from_config(cfg: &mut Config, channel: &str) -> PrivacySettings {
+        match PrivacySettings::from_parsed_config(cfg, channel) {
+            Ok(settings) => settings,
+            Err(e) => {
+                // Sending less than the user agreed to is the safer mistake.
+                logger().log(LogLevelFilter::Error,
+                             format!("Invalid privacy settings, anonymizing IP addresses: {}", e)
+                                 .as_str());
+                PrivacySettings {
+                    anonymize_ip: true,
+                    non_personalized_ads: true,
+                    data_source: None,
+                }
+            }
+        }
+    }
+
+    pub fn from_parsed_config(cfg: &mut Config, channel: &str) -> Result<PrivacySettings, String> {
+        let value = match cfg.get(KEY_PRIVACY) {
+            None => return Ok(PrivacySettings::new()),
+            Some(value @ Value::Object(_)) => value,
+            Some(_) => return Err("\"privacy\" must be an object".to_string()),
+        };
+        let mut settings = PrivacySettings::new();
+        try!(settings.apply(&value));
+        match value.find("channels") {
+            None => {}
+            Some(&Value::Object(ref channels)) => {
+                if let Some(overrides) = channels.get(channel) {
+                    try!(settings.apply(overrides));
+                }
+            }
+            Some(_) => return Err("privacy channels must be an object".to_string()),
+        }
+        Ok(settings)
+    }
+
+    // Overrides the settings present in `value`.
+    fn apply(&mut self, value: &Value) -> Result<(), String> {
+        if let Some(anonymize_ip) = try!(get_optional_bool(value, "anonymizeIp")) {
+            self.anonymize_ip = anonymize_ip;
+        }
+        if let Some(non_personalized_ads) = try!(get_optional_bool(value, "nonPersonalizedAds")) {
+            self.non_personalized_ads = non_personalized_ads;
+        }
+        if let Some(data_source) = try!(get_optional_string(value, "dataSource")) {
+            self.data_source = if data_source.is_empty() {
+                None
+            } else {
+                Some(data_source)
+            };
+        }
+        Ok(())
+    }
+
+    /// The settings as a JSON object, in the form of the "privacy" config.
+    pub fn to_value(&self) -> Value {
+        let mut builder = ObjectBuilder::new()
+            .insert("anonymizeIp", self.anonymize_ip)
+            .insert("nonPersonalizedAds", self.non_personalized_ads);
+        if let Some(ref data_source) = self.data_source {
+            builder = builder.insert("dataSource", data_source);
+        }
+        builder.build()
+    }
+
+    pub fn from_value(value: &Value) -> Option<PrivacySettings> {
+        let mut settings = PrivacySettings::new();
+        match settings.apply(value) {
+            Ok(()) => Some(settings),
+            Err(_) => None,
+        }
+    }
+
+    /// The parameters as they are appended to a hit, each preceded by '&'.
+    /// `encode` percent-encodes the data source.
+    pub fn to_query<F>(&self, encode: F) -> String
+        where F: Fn(String) -> String
+    {
+        let mut query = String::new();
+        if self.anonymize_ip {
+            query.push_str("&aip=1");
+        }
+        if self.non_personalized_ads {
+            query.push_str("&npa=1");
+        }
+        if let Some(ref data_source) = self.data_source {
+            query.push_str("&ds=");
+            query.push_str(&encode(data_source.clone()));
+        }
+        query
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! privacy_settings {
+    before_each {
+        let mut cfg = Config::new();
+        cfg.parse_json(r#"{ "privacy": { "dataSource": "app",
+                                         "channels": { "release": { "anonymizeIp": true,
+                                                                    "nonPersonalizedAds": true },
+                                                       "nightly": { "dataSource": "" } } } }"#
+            .to_string());
+    }
+
+    it "should add nothing by default" {
+        assert_eq!(PrivacySettings::new().to_query(|s| s), "");
+    }
+
+    it "should apply the defaults and the channel overrides" {
+        let beta = PrivacySettings::from_parsed_config(&mut cfg, "beta").unwrap();
+        assert_eq!(beta.to_query(|s| s), "&ds=app");
+        let release = PrivacySettings::from_parsed_config(&mut cfg, "release").unwrap();
+        assert_eq!(release.to_query(|s| s), "&aip=1&npa=1&ds=app");
+        let nightly = PrivacySettings::from_parsed_config(&mut cfg, "nightly").unwrap();
+        assert_eq!(nightly, PrivacySettings::new());
+    }
+
+    it "should convert to and from JSON" {
+        let release = PrivacySettings::from_parsed_config(&mut cfg, "release").unwrap();
+        assert_eq!(PrivacySettings::from_value(&release.to_value()), Some(release));
+        let nightly = PrivacySettings::from_parsed_config(&mut cfg, "nightly").unwrap();
+        assert_eq!(PrivacySettings::from_value(&nightly.to_value()), Some(nightly));
+    }
+
+    it "should reject settings of the wrong type" {
+        cfg.parse_json(r#"{ "privacy": { "anonymizeIp": "yes" } }"#.to_string());
+        assert!(PrivacySettings::from_parsed_config(&mut cfg, "release").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+extern crate serde_json;
+
+use config::Config;
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::serde_json::Value;
+use self::serde_json::builder::ObjectBuilder;
+
+// Events can wait in the queue for a long time, e.g. while the device is
+// offline, and Google Analytics attributes a hit to the time it arrives
+// unless it carries the queue time: `qt`, the milliseconds between the event
+// and the moment the hit is sent.  Queued Google Analytics batches therefore
+// keep each hit with its creation time, and `qt` is added when the batch is
+// transmitted:
+//
+//     [{"hit": "v=1&t=event&...", "created": 1464215817000}, ...]
+//
+// Google Analytics may ignore hits with a queue time over four hours.
+// "staleHits" in metricsconfig.json decides what happens to them: "cap"
+// (the default) sends them with the maximum queue time, "drop" drops them
+// and counts them in the stats.  Batches queued by older versions, which are
+// plain strings, are sent as they are.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+const KEY_STALE_HITS: &'static str = "staleHits";
+/// The longest queue time Google Analytics accepts, in milliseconds.
+pub const MAX_QUEUE_TIME_MS: i64 = 4 * 60 * 60 * 1000;
+/// The most a hit grows when `qt` is added ("&qt=14400000").
+pub const MAX_QUEUE_TIME_BYTES: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StaleHitPolicy {
+    /// Send the hit with the maximum queue time.
+    Cap,
+    /// Do not send the hit.
+    Drop,
+}
+
+impl StaleHitPolicy {
+    pub fn from_name(name: &str) -> Option<StaleHitPolicy> {
+        match name {
+            "cap" => Some(StaleHitPolicy::Cap),
+            "drop" => Some(StaleHitPolicy::Drop),
+            _ => None,
+        }
+    }
+
+    pub fn from_config(cfg: &mut Config) -> StaleHitPolicy {
+        match cfg.get(KEY_STALE_HITS) {
+            None => StaleHitPolicy::Cap,
+            Some(value) => {
+                match value.as_str().and_then(StaleHitPolicy::from_name) {
+                    Some(policy) => policy,
+                    None => {
+                        logger().log(LogLevelFilter::Error,
+                                     "\"staleHits\" must be \"cap\" or \"drop\", using \"cap\"");
+                        StaleHitPolicy::Cap
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A Google Analytics batch of `(hit, created)` pairs as it is queued.
+pub fn batch_to_value(hits: &[(String, i64)]) -> Value {
+    Value::Array(hits.iter()
+        .map(|&(ref hit, created)| {
+            ObjectBuilder::new()
+                .insert("hit", hit)
+                .insert("created", created)
+                .build()
+        })
+        .collect())
+}
+
+/// The request body for a queued batch sent at `now` (in milliseconds), and
+/// the number of stale hits dropped.  None if the batch is malformed.
+pub fn batch_body(batch: &Value, now: i64, policy: StaleHitPolicy) -> Option<(String, u64)> {
+    let hits = match *batch {
+        Value::String(ref body) => return Some((body.clone(), 0)),
+        Value::Array(ref hits) => hits,
+        _ => return None,
+    };
+    let mut body = String::new();
+    let mut dropped = 0;
+    for hit in hits {
+        let (hit, created) = match (hit.find("hit").and_then(|h| h.as_str()),
+                                    hit.find("created").and_then(|c| c.as_i64())) {
+            (Some(hit), Some(created)) => (hit, created),
+            _ => return None,
+        };
+        let mut queue_time = if now > created { now - created } else { 0 };
+        if queue_time > MAX_QUEUE_TIME_MS {
+            match policy {
+                StaleHitPolicy::Cap => queue_time = MAX_QUEUE_TIME_MS,
+                StaleHitPolicy::Drop => {
+                    dropped += 1;
+                    continue;
+                }
+            }
+        }
+        body.push_str(&format!("{}&qt={}\n", hit, queue_time));
+    }
+    Some((body, dropped))
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! queue_time {
+    before_each {
+        let created = 1464215817000;
+        let batch = batch_to_value(&[("v=1&t=event".to_string(), created),
+                                     ("v=1&t=event&ec=old".to_string(), created - MAX_QUEUE_TIME_MS)]);
+    }
+
+    it "should add the time since each hit was created" {
+        assert_eq!(batch_body(&batch, created + 1500, StaleHitPolicy::Cap),
+                   Some(("v=1&t=event&qt=1500\nv=1&t=event&ec=old&qt=14400000\n".to_string(), 0)));
+    }
+
+    it "should drop hits over the limit if asked to" {
+        assert_eq!(batch_body(&batch, created + 1, StaleHitPolicy::Drop),
+                   Some(("v=1&t=event&qt=1\n".to_string(), 1)));
+    }
+
+    it "should send old batches as they are" {
+        let old = Value::String("v=1&t=event\n".to_string());
+        assert_eq!(batch_body(&old, created, StaleHitPolicy::Drop),
+                   Some(("v=1&t=event\n".to_string(), 0)));
+        assert_eq!(batch_body(&Value::U64(1), created, StaleHitPolicy::Cap), None);
+    }
+}
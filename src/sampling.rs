@@ -0,0 +1,404 @@
+extern crate rand;
+extern crate serde_json;
+
+use config::{Config, get_optional_string, get_optional_u64};
+use events::Event;
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::serde_json::Value;
+use sinks::EventRule;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+// Sampling and rate limiting of recorded events, so that a chatty subsystem
+// cannot flood the queue and push out important events.  Both are
+// configured in metricsconfig.json with the same category/action/label
+// patterns as the sink filters; for each, the first matching rule applies:
+//
+//     "sampling": [
+//         { "category": "eng-memory", "rate": 0.1 },
+//         { "category": "eng*", "action": "tick", "rate": 0.01, "by": "random" }
+//     ],
+//     "rateLimits": [
+//         { "category": "eng*", "perMinute": 60, "burst": 10 }
+//     ],
+//     "reportDropped": true
+//
+// "rate" is the fraction of matching events kept.  By default the decision is
+// made once per client id ("by": "client"), so a device either reports all of
+// its matching events or none; "by": "random" decides per event.  A rate
+// limit keeps a token bucket for each category/action pair it matches,
+// refilled at "perMinute" and holding up to "burst" tokens (by default
+// "perMinute", at least 1).  A bucket that has filled up again is forgotten,
+// and at most MAX_BUCKETS are kept; past that the least recently used one is
+// dropped, which lets its pair start over with a full burst.  The counts of
+// dropped events are in the stats; with "reportDropped" they are also sent
+// as synthetic events.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+const KEY_SAMPLING: &'static str = "sampling";
+const KEY_RATE_LIMITS: &'static str = "rateLimits";
+const KEY_REPORT_DROPPED: &'static str = "reportDropped";
+
+// Resolution of the deterministic sampling decision.
+const SAMPLING_BUCKETS: u64 = 10000;
+// Most token buckets kept for the rate limits.
+const MAX_BUCKETS: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplingMode {
+    /// Keep or drop all matching events of a client.
+    Client,
+    /// Decide for each event.
+    Random,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SamplingRule {
+    pub rule: EventRule,
+    pub rate: f64,
+    pub mode: SamplingMode,
+}
+
+impl SamplingRule {
+    fn from_value(value: &Value) -> Result<SamplingRule, String> {
+        let rate = match value.find("rate").and_then(|rate| rate.as_f64()) {
+            Some(rate) if rate >= 0.0 && rate <= 1.0 => rate,
+            _ => return Err("sampling rule needs a rate between 0 and 1".to_string()),
+        };
+        let mode = match try!(get_optional_string(value, "by")) {
+            None => SamplingMode::Client,
+            Some(ref by) if by == "client" => SamplingMode::Client,
+            Some(ref by) if by == "random" => SamplingMode::Random,
+            Some(by) => return Err(format!("unknown sampling mode {}", by)),
+        };
+        Ok(SamplingRule {
+            rule: try!(EventRule::from_value(value)),
+            rate: rate,
+            mode: mode,
+        })
+    }
+
+    fn keeps(&self, client_id: &str) -> bool {
+        match self.mode {
+            SamplingMode::Random => rand::random::<f64>() < self.rate,
+            SamplingMode::Client => {
+                // Hash the rule along with the client id so that a client
+                // sampled out by one rule is not sampled out by all of them.
+                let key = format!("{}|{:?}|{:?}|{:?}",
+                                  client_id,
+                                  self.rule.category,
+                                  self.rule.action,
+                                  self.rule.label);
+                let bucket = fnv1a(key.as_bytes()) % SAMPLING_BUCKETS;
+                (bucket as f64) < self.rate * SAMPLING_BUCKETS as f64
+            }
+        }
+    }
+}
+
+// FNV-1a, which unlike the std hashers is guaranteed to give the same result
+// across releases, so a client stays in or out of the sample after an update.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub rule: EventRule,
+    pub per_minute: u64,
+    pub burst: u64,
+}
+
+impl RateLimit {
+    fn from_value(value: &Value) -> Result<RateLimit, String> {
+        let per_minute = match try!(get_optional_u64(value, "perMinute")) {
+            Some(per_minute) => per_minute,
+            None => return Err("rate limit needs perMinute".to_string()),
+        };
+        let burst = try!(get_optional_u64(value, "burst")).unwrap_or(per_minute);
+        if burst == 0 {
+            return Err("rate limit needs a burst of at least 1".to_string());
+        }
+        Ok(RateLimit {
+            rule: try!(EventRule::from_value(value)),
+            per_minute: per_minute,
+            burst: burst,
+        })
+    }
+}
+
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket holding `capacity` tokens, refilled at `per_minute`.
+    pub fn new(capacity: u64, per_minute: u64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refill_per_sec: per_minute as f64 / 60.0,
+            last_refill: now,
+        }
+    }
+
+    /// Takes a token if one is available at `now`.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.tokens = self.tokens_at(now);
+        if now > self.last_refill {
+            self.last_refill = now;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket has filled up again by `now`, so that it is no
+    /// different from a new one.
+    pub fn is_full(&self, now: Instant) -> bool {
+        self.tokens_at(now) >= self.capacity
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        if now <= self.last_refill {
+            return self.tokens;
+        }
+        let elapsed = now.duration_since(self.last_refill);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        (self.tokens + secs * self.refill_per_sec).min(self.capacity)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Admission {
+    Accepted,
+    SampledOut,
+    RateLimited,
+}
+
+/// Decides which recorded events are queued.
+pub struct IngestionFilter {
+    sampling: Vec<SamplingRule>,
+    rate_limits: Vec<RateLimit>,
+    // Keyed by rate limit index, category and action.
+    buckets: BTreeMap<(usize, String, String), TokenBucket>,
+    report_dropped: bool,
+}
+
+impl IngestionFilter {
+    /// A filter that accepts every event.
+    pub fn new() -> IngestionFilter {
+        IngestionFilter {
+            sampling: Vec::new(),
+            rate_limits: Vec::new(),
+            buckets: BTreeMap::new(),
+            report_dropped: false,
+        }
+    }
+
+    pub fn from_config(cfg: &mut Config) -> IngestionFilter {
+        match IngestionFilter::from_parsed_config(cfg) {
+            Ok(filter) => filter,
+            Err(e) => {
+                logger().log(LogLevelFilter::Error,
+                             format!("Invalid sampling settings, keeping every event: {}", e)
+                                 .as_str());
+                IngestionFilter::new()
+            }
+        }
+    }
+
+    pub fn from_parsed_config(cfg: &mut Config) -> Result<IngestionFilter, String> {
+        let mut filter = IngestionFilter::new();
+        for value in try!(get_array(cfg, KEY_SAMPLING)) {
+            filter.sampling.push(try!(SamplingRule::from_value(&value)));
+        }
+        for value in try!(get_array(cfg, KEY_RATE_LIMITS)) {
+            filter.rate_limits.push(try!(RateLimit::from_value(&value)));
+        }
+        filter.report_dropped = match cfg.get(KEY_REPORT_DROPPED) {
+            None => false,
+            Some(Value::Bool(report)) => report,
+            Some(_) => return Err("\"reportDropped\" must be true or false".to_string()),
+        };
+        Ok(filter)
+    }
+
+    /// Whether counts of dropped events are sent as synthetic events.
+    pub fn reports_dropped(&self) -> bool {
+        self.report_dropped
+    }
+
+    pub fn admit(&mut self, client_id: &str, event: &Event, now: Instant) -> Admission {
+        if let Some(rule) = self.sampling.iter().find(|rule| rule.rule.matches(event)) {
+            if !rule.keeps(client_id) {
+                return Admission::SampledOut;
+            }
+        }
+
+        let limit = self.rate_limits
+            .iter()
+            .enumerate()
+            .find(|&(_, limit)| limit.rule.matches(event))
+            .map(|(index, limit)| (index, limit.burst, limit.per_minute));
+        if let Some((index, burst, per_minute)) = limit {
+            let key = (index, event.category.clone(), event.action.clone());
+            if !self.buckets.contains_key(&key) && self.buckets.len() >= MAX_BUCKETS {
+                self.prune_buckets(now);
+            }
+            let bucket = self.buckets
+                .entry(key)
+                .or_insert_with(|| TokenBucket::new(burst, per_minute, now));
+            if !bucket.try_take(now) {
+                return Admission::RateLimited;
+            }
+        }
+        Admission::Accepted
+    }
+
+    // Forgets the buckets that have filled up again, or else the least
+    // recently used one.
+    fn prune_buckets(&mut self, now: Instant) {
+        let full: Vec<(usize, String, String)> = self.buckets
+            .iter()
+            .filter(|&(_, bucket)| bucket.is_full(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        if full.is_empty() {
+            let oldest = self.buckets
+                .iter()
+                .min_by_key(|&(_, bucket)| bucket.last_refill)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = oldest {
+                self.buckets.remove(&key);
+            }
+        }
+        for key in full {
+            self.buckets.remove(&key);
+        }
+    }
+}
+
+fn get_array(cfg: &mut Config, key: &str) -> Result<Vec<Value>, String> {
+    match cfg.get(key) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(values)) => Ok(values),
+        Some(_) => Err(format!("\"{}\" must be an array", key)),
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! ingestion_filter {
+    before_each {
+        use events::Event;
+        use privacy::PrivacySettings;
+        use std::time::{Duration, Instant};
+
+        let event = Event {
+            category: "eng-memory".to_string(),
+            action: "sample".to_string(),
+            label: "rss".to_string(),
+            value: 1,
+            floating_point_value: None,
+            timestamp: "2016-05-25 22:36:57".to_string(),
+            created: 1464215817000,
+            client_id: "9eccb690-93aa-4513-835a-9a4f0f0e2a71".to_string(),
+            privacy: PrivacySettings::new(),
+            session: None,
+        };
+        let now = Instant::now();
+        let mut cfg = Config::new();
+    }
+
+    it "should accept everything without rules" {
+        let mut filter = IngestionFilter::new();
+        assert_eq!(filter.admit("client", &event, now), Admission::Accepted);
+    }
+
+    it "should sample the same client consistently" {
+        cfg.parse_json(r#"{ "sampling": [ { "category": "eng*", "rate": 0.5 } ] }"#.to_string());
+        let mut filter = IngestionFilter::from_parsed_config(&mut cfg).unwrap();
+        let first = filter.admit("9eccb690-93aa-4513-835a-9a4f0f0e2a71", &event, now);
+        for _ in 0..20 {
+            assert_eq!(filter.admit("9eccb690-93aa-4513-835a-9a4f0f0e2a71", &event, now), first);
+        }
+    }
+
+    it "should sample out everything at rate 0 and nothing at rate 1" {
+        cfg.parse_json(r#"{ "sampling": [ { "action": "sample", "rate": 0, "by": "random" },
+                                          { "rate": 1 } ] }"#.to_string());
+        let mut filter = IngestionFilter::from_parsed_config(&mut cfg).unwrap();
+        assert_eq!(filter.admit("client", &event, now), Admission::SampledOut);
+        let mut other = event.clone();
+        other.action = "other".to_string();
+        assert_eq!(filter.admit("client", &other, now), Admission::Accepted);
+    }
+
+    it "should rate limit each category and action" {
+        cfg.parse_json(r#"{ "rateLimits": [ { "category": "eng*", "perMinute": 60, "burst": 2 } ] }"#
+            .to_string());
+        let mut filter = IngestionFilter::from_parsed_config(&mut cfg).unwrap();
+        assert_eq!(filter.admit("client", &event, now), Admission::Accepted);
+        assert_eq!(filter.admit("client", &event, now), Admission::Accepted);
+        assert_eq!(filter.admit("client", &event, now), Admission::RateLimited);
+
+        let mut other = event.clone();
+        other.action = "other".to_string();
+        assert_eq!(filter.admit("client", &other, now), Admission::Accepted);
+
+        // One token per second comes back.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(filter.admit("client", &event, later), Admission::Accepted);
+        assert_eq!(filter.admit("client", &event, later), Admission::RateLimited);
+    }
+
+    it "should forget rate limit buckets that have filled up" {
+        cfg.parse_json(r#"{ "rateLimits": [ { "perMinute": 60, "burst": 1 } ] }"#.to_string());
+        let mut filter = IngestionFilter::from_parsed_config(&mut cfg).unwrap();
+        let mut other = event.clone();
+        for i in 0..MAX_BUCKETS {
+            other.action = format!("action-{}", i);
+            assert_eq!(filter.admit("client", &other, now), Admission::Accepted);
+        }
+        assert_eq!(filter.buckets.len(), MAX_BUCKETS);
+
+        // At the cap, the least recently used bucket makes room.
+        assert_eq!(filter.admit("client", &event, now), Admission::Accepted);
+        assert_eq!(filter.buckets.len(), MAX_BUCKETS);
+        assert_eq!(filter.admit("client", &event, now), Admission::RateLimited);
+
+        // A second later they have all filled up again.
+        let later = now + Duration::from_secs(1);
+        other.action = "another".to_string();
+        assert_eq!(filter.admit("client", &other, later), Admission::Accepted);
+        assert_eq!(filter.buckets.len(), 1);
+    }
+
+    it "should reject an invalid rate" {
+        cfg.parse_json(r#"{ "sampling": [ { "rate": 2 } ] }"#.to_string());
+        assert!(IngestionFilter::from_parsed_config(&mut cfg).is_err());
+    }
+
+    it "should reject a rate limit without a burst" {
+        cfg.parse_json(r#"{ "rateLimits": [ { "perMinute": 60, "burst": 0 } ] }"#.to_string());
+        assert!(IngestionFilter::from_parsed_config(&mut cfg).is_err());
+        cfg.parse_json(r#"{ "rateLimits": [ { "perMinute": 0 } ] }"#.to_string());
+        assert!(IngestionFilter::from_parsed_config(&mut cfg).is_err());
+    }
+}
@@ -0,0 +1,663 @@
+extern crate serde_json;
+extern crate time;
+
+use config::Config;
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::serde_json::Value;
+use storage::Storage;
+use std::cmp;
+use std::fs::File;
+use std::io::Read;
+
+// Decides when the worker saves and sends.  There are three independent
+// deadlines:
+//
+// * save, every "saveInterval" seconds (default 3600);
+// * send, "sendInterval" seconds (default 1209600, two weeks) after the last
+//   send, or after "startTime" (seconds since the epoch) for the first one.
+//   With "sendAt", e.g. "02:00", a send waits until the local clock next shows
+//   that time, so that uploads happen at night.  The time of the last send is
+//   kept in schedule.dat, so a restart does not put off the next send;
+// * flush, "flushDelay" seconds (default 60) after a full batch of events is
+//   waiting, so that a busy application does not wait for the next send.  A
+//   flush does not move the send deadline.
+//
+// The worker waits for the earliest deadline and then asks the scheduler what
+// is due.  A send the network policy does not allow is deferred: it is tried
+// again after the policy's retry interval, or as soon as the network changes.
+// Invalid settings are logged and the defaults are used instead.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+const KEY_SEND: &'static str = "sendInterval";
+const KEY_SAVE: &'static str = "saveInterval";
+const KEY_START: &'static str = "startTime";
+const KEY_SEND_AT: &'static str = "sendAt";
+const KEY_FLUSH_DELAY: &'static str = "flushDelay";
+const DEFAULT_SEND: i64 = 1209600;
+const DEFAULT_SAVE: i64 = 3600;
+const DEFAULT_FLUSH_DELAY: i64 = 60;
+const SECS_PER_DAY: i64 = 86400;
+const SCHEDULE_FILE: &'static str = "schedule.dat";
+const KEY_LAST_SEND: &'static str = "lastSend";
+
+/// A time of day on the local clock.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SendWindow {
+    // Seconds since midnight.
+    secs: i64,
+}
+
+impl SendWindow {
+    /// Parses "HH:MM", e.g. "02:00".
+    pub fn parse(text: &str) -> Option<SendWindow> {
+        let mut parts = text.splitn(2, ':');
+        match (parts.next().and_then(|h| h.parse::<i64>().ok()),
+               parts.next().and_then(|m| m.parse::<i64>().ok())) {
+            (Some(hours), Some(minutes)) if hours >= 0 && hours < 24 && minutes >= 0 &&
+                                            minutes < 60 => {
+                Some(SendWindow { secs: hours * 3600 + minutes * 60 })
+            }
+            _ => None,
+        }
+    }
+
+    /// The first time at or after `after` (seconds since the epoch) at which
+    /// a clock `utc_offset` seconds ahead of UTC shows this time of day.
+    pub fn next_at_or_after(&self, after: i64, utc_offset: i64) -> i64 {
+        let local = after + utc_offset;
+        let since_midnight = ((local % SECS_PER_DAY) + SECS_PER_DAY) % SECS_PER_DAY;
+        let mut next = local - since_midnight + self.secs;
+        if next < local {
+            next += SECS_PER_DAY;
+        }
+        next - utc_offset
+    }
+}
+
+/// The scheduling settings from metricsconfig.json.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    /// Seconds between saves.
+    pub save_interval: i64,
+    /// Seconds between sends.
+    pub send_interval: i64,
+    /// When the send interval starts counting for the first send; now if None.
+    pub start_time: Option<i64>,
+    /// The time of day sends wait for, if any.
+    pub send_at: Option<SendWindow>,
+    /// Seconds between a full batch and the flush that sends it.
+    pub flush_delay: i64,
+}
+
+impl Schedule {
+    pub fn new() -> Schedule {
+        Schedule {
+            save_interval: DEFAULT_SAVE,
+            send_interval: DEFAULT_SEND,
+            start_time: None,
+            send_at: None,
+            flush_delay: DEFAULT_FLUSH_DELAY,
+        }
+    }
+
+    pub fn from_config(cfg: &mut Config) -> Schedule {
+        match Schedule::from_parsed_config(cfg) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                logger().log(LogLevelFilter::Error,
+                             format!("Invalid schedule, using the defaults: {}", e).as_str());
+                Schedule::new()
+            }
+        }
+    }
+
+    pub fn from_parsed_config(cfg: &mut Config) -> Result<Schedule, String> {
+        let mut schedule = Schedule::new();
+        if let Some(secs) = try!(get_secs(cfg, KEY_SAVE, 1)) {
+            schedule.save_interval = secs;
+        }
+        if let Some(secs) = try!(get_secs(cfg, KEY_SEND, 1)) {
+            schedule.send_interval = secs;
+        }
+        // Older configurations use 0 for "not set".
+        schedule.start_time = try!(get_secs(cfg, KEY_START, 0)).and_then(|start| {
+            if start > 0 { Some(start) } else { None }
+        });
+        if let Some(secs) = try!(get_secs(cfg, KEY_FLUSH_DELAY, 0)) {
+            schedule.flush_delay = secs;
+        }
+        schedule.send_at = match cfg.get(KEY_SEND_AT) {
+            None => None,
+            Some(value) => {
+                match value.as_str().and_then(SendWindow::parse) {
+                    Some(window) => Some(window),
+                    None => return Err(format!("\"{}\" must be a time such as \"02:00\"", KEY_SEND_AT)),
+                }
+            }
+        };
+        Ok(schedule)
+    }
+}
+
+// Reads a number of seconds of at least `min`.
+fn get_secs(cfg: &mut Config, key: &str, min: u64) -> Result<Option<i64>, String> {
+    match cfg.get(key) {
+        None => Ok(None),
+        Some(value) => {
+            match value.as_u64() {
+                Some(secs) if secs >= min => Ok(Some(secs as i64)),
+                _ => Err(format!("\"{}\" must be a number of seconds of at least {}", key, min)),
+            }
+        }
+    }
+}
+
+/// The local clock's offset from UTC in seconds at `at`.
+fn local_utc_offset(at: i64) -> i64 {
+    time::at(time::Timespec::new(at, 0)).tm_utcoff as i64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SchedulerState {
+    /// Nothing is scheduled.
+    Stopped,
+    /// Waiting for the next deadline.
+    Waiting,
+    /// Sending for `op` (Send, Flush or Urgent).
+    Sending { op: TimerOp },
+    /// Waiting until `until` to try a send the network did not allow.
+    Deferred { op: TimerOp, until: i64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerOp {
+    Save,
+    /// Send at the end of the send interval.
+    Send,
+    /// Send because a full batch is waiting.
+    Flush,
+    /// Send only the urgent events.
+    Urgent,
+    None,
+}
+
+pub struct Scheduler {
+    schedule: Schedule,
+    state: SchedulerState,
+    // Seconds since the epoch.
+    save_deadline: i64,
+    send_deadline: i64,
+    flush_deadline: Option<i64>,
+    urgent_deadline: Option<i64>,
+    utc_offset: fn(i64) -> i64,
+    // Where the time of the last send is kept, if anywhere.
+    schedule_file: Option<String>,
+}
+
+impl Scheduler {
+    pub fn new(schedule: Schedule) -> Scheduler {
+        Scheduler {
+            schedule: schedule,
+            state: SchedulerState::Stopped,
+            save_deadline: 0,
+            send_deadline: 0,
+            flush_deadline: None,
+            urgent_deadline: None,
+            utc_offset: local_utc_offset,
+            schedule_file: None,
+        }
+    }
+
+    /// A scheduler that keeps the time of the last send in `storage`.
+    pub fn with_storage(schedule: Schedule, storage: &Storage) -> Scheduler {
+        let mut scheduler = Scheduler::new(schedule);
+        scheduler.schedule_file = Some(storage.path(SCHEDULE_FILE));
+        scheduler
+    }
+
+    pub fn state(&self) -> SchedulerState {
+        self.state
+    }
+
+    /// Schedules the first save and send.  The first send is a send interval
+    /// after the last send before the restart, or else after "startTime".  A
+    /// send that was due before `now` is due immediately (or at the next send
+    /// window).
+    pub fn start(&mut self, now: i64) {
+        let interval_start = match self.read_last_send() {
+            Some(last_send) => cmp::min(last_send, now),
+            None => self.schedule.start_time.unwrap_or(now),
+        };
+        let first_send = interval_start + self.schedule.send_interval;
+        self.state = SchedulerState::Waiting;
+        self.save_deadline = now + self.schedule.save_interval;
+        self.send_deadline = self.aligned(cmp::max(first_send, now));
+        self.flush_deadline = None;
+        self.urgent_deadline = None;
+    }
+
+    pub fn stop(&mut self) {
+        self.state = SchedulerState::Stopped;
+        self.flush_deadline = None;
+        self.urgent_deadline = None;
+    }
+
+    /// The earliest deadline, in seconds since the epoch.  None unless the
+    /// scheduler is waiting.
+    pub fn next_deadline(&self) -> Option<i64> {
+        match self.state {
+            SchedulerState::Waiting => {}
+            SchedulerState::Deferred { until, .. } => return Some(cmp::min(self.save_deadline, until)),
+            _ => return None,
+        }
+        let deadline = cmp::min(self.save_deadline, self.send_deadline);
+        Some([self.flush_deadline, self.urgent_deadline]
+            .iter()
+            .filter_map(|deadline| *deadline)
+            .fold(deadline, cmp::min))
+    }
+
+    /// Tells the scheduler a full batch of events is waiting.  The flush
+    /// deadline is only set once until the next send.
+    pub fn queue_full(&mut self, now: i64) {
+        if self.state != SchedulerState::Stopped && self.flush_deadline.is_none() {
+            self.flush_deadline = Some(now + self.schedule.flush_delay);
+        }
+    }
+
+    /// Tells the scheduler urgent events are waiting, to be sent at once.
+    pub fn urgent(&mut self, now: i64) {
+        if self.state != SchedulerState::Stopped && self.urgent_deadline.is_none() {
+            self.urgent_deadline = Some(now);
+        }
+    }
+
+    /// Returns an operation that is due at `now`, or None.  Call it until it
+    /// returns None, since several operations can be due at once.  After a
+    /// Send, Flush or Urgent, call `send_finished` or `defer`.
+    pub fn fire(&mut self, now: i64) -> TimerOp {
+        match self.state {
+            SchedulerState::Waiting => {}
+            SchedulerState::Deferred { op, until } if until <= now => {
+                self.state = SchedulerState::Sending { op: op };
+                return op;
+            }
+            SchedulerState::Deferred { .. } => return self.fire_save(now),
+            _ => return TimerOp::None,
+        }
+        let due = |deadline: Option<i64>| deadline.map_or(false, |deadline| deadline <= now);
+        let op = if due(self.urgent_deadline) {
+            TimerOp::Urgent
+        } else if self.send_deadline <= now {
+            TimerOp::Send
+        } else if due(self.flush_deadline) {
+            TimerOp::Flush
+        } else {
+            return self.fire_save(now);
+        };
+        self.state = SchedulerState::Sending { op: op };
+        op
+    }
+
+    fn fire_save(&mut self, now: i64) -> TimerOp {
+        if self.save_deadline <= now {
+            self.save_deadline = now + self.schedule.save_interval;
+            TimerOp::Save
+        } else {
+            TimerOp::None
+        }
+    }
+
+    /// Ends a send.  Urgent events go out with every send, and a full batch
+    /// with a Send or Flush; a Send also starts the next send interval.
+    pub fn send_finished(&mut self, now: i64) {
+        let op = match self.state {
+            SchedulerState::Sending { op } => op,
+            _ => return,
+        };
+        if op == TimerOp::Send {
+            let next = now + self.schedule.send_interval;
+            self.send_deadline = self.aligned(next);
+            self.write_last_send(now);
+        }
+        if op != TimerOp::Urgent {
+            self.flush_deadline = None;
+        }
+        self.urgent_deadline = None;
+        self.state = SchedulerState::Waiting;
+    }
+
+    /// Tells the scheduler everything was sent at `now` outside of its
+    /// deadlines, e.g. by an explicit flush.  A deferred send counts as done.
+    pub fn flushed(&mut self, now: i64) {
+        if let SchedulerState::Deferred { op, .. } = self.state {
+            self.state = SchedulerState::Sending { op: op };
+            self.send_finished(now);
+        }
+        self.flush_deadline = None;
+        self.urgent_deadline = None;
+    }
+
+    /// Puts off a send the network does not allow until `until`.
+    pub fn defer(&mut self, until: i64) {
+        if let SchedulerState::Sending { op } = self.state {
+            self.state = SchedulerState::Deferred {
+                op: op,
+                until: until,
+            };
+        }
+    }
+
+    /// Tries a deferred send at `now`, since the network may allow it now.
+    pub fn network_changed(&mut self, now: i64) {
+        if let SchedulerState::Deferred { op, until } = self.state {
+            self.state = SchedulerState::Deferred {
+                op: op,
+                until: cmp::min(until, now),
+            };
+        }
+    }
+
+    fn read_last_send(&self) -> Option<i64> {
+        let file_name = match self.schedule_file {
+            Some(ref file_name) => file_name,
+            None => return None,
+        };
+        let mut json = String::new();
+        match File::open(file_name) {
+            Ok(mut file) => {
+                if file.read_to_string(&mut json).is_err() {
+                    return None;
+                }
+            }
+            Err(_) => return None,
+        }
+        serde_json::from_str::<Value>(&json)
+            .ok()
+            .and_then(|value| value.find(KEY_LAST_SEND).and_then(|v| v.as_i64()))
+    }
+
+    fn write_last_send(&self, now: i64) {
+        if let Some(ref file_name) = self.schedule_file {
+            let json = format!("{{\"{0}\":{1}}}", KEY_LAST_SEND, now);
+            Config::new().create_and_write_json(file_name, &json);
+        }
+    }
+
+    // Moves `at` to the next send window, if there is one.
+    fn aligned(&self, at: i64) -> i64 {
+        match self.schedule.send_at {
+            Some(window) => window.next_at_or_after(at, (self.utc_offset)(at)),
+            None => at,
+        }
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! scheduler {
+    before_each {
+        // 2016-05-25 22:36:57 UTC.
+        let now = 1464215817;
+        let mut schedule = Schedule::new();
+        schedule.save_interval = 3600;
+        schedule.send_interval = 86400;
+        schedule.flush_delay = 60;
+        let mut scheduler = Scheduler::new(schedule.clone());
+    }
+
+    it "should do nothing until started" {
+        assert_eq!(scheduler.state(), SchedulerState::Stopped);
+        assert_eq!(scheduler.next_deadline(), None);
+        scheduler.queue_full(now);
+        assert_eq!(scheduler.fire(now + 86400), TimerOp::None);
+    }
+
+    it "should wait for the first save after starting" {
+        scheduler.start(now);
+        assert_eq!(scheduler.state(), SchedulerState::Waiting);
+        assert_eq!(scheduler.next_deadline(), Some(now + 3600));
+        assert_eq!(scheduler.fire(now + 3599), TimerOp::None);
+    }
+
+    it "should save every save interval" {
+        scheduler.start(now);
+        assert_eq!(scheduler.fire(now + 3600), TimerOp::Save);
+        assert_eq!(scheduler.fire(now + 3600), TimerOp::None);
+        assert_eq!(scheduler.state(), SchedulerState::Waiting);
+        assert_eq!(scheduler.next_deadline(), Some(now + 7200));
+    }
+
+    it "should send at the end of the send interval and start the next one" {
+        scheduler.start(now);
+        assert_eq!(scheduler.fire(now + 86400), TimerOp::Send);
+        assert_eq!(scheduler.state(), SchedulerState::Sending { op: TimerOp::Send });
+        assert_eq!(scheduler.next_deadline(), None);
+        assert_eq!(scheduler.fire(now + 86400), TimerOp::None);
+
+        scheduler.send_finished(now + 86410);
+        assert_eq!(scheduler.state(), SchedulerState::Waiting);
+        // The save that was also due comes next.
+        assert_eq!(scheduler.fire(now + 86410), TimerOp::Save);
+        assert_eq!(scheduler.send_deadline, now + 86410 + 86400);
+    }
+
+    it "should start the send interval at the last send before a restart" {
+        use std::fs;
+        use storage::Storage;
+
+        let dir = "test_scheduler_storage";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let storage = Storage::new(dir);
+        let mut scheduler = Scheduler::with_storage(schedule.clone(), &storage);
+        scheduler.start(now);
+        assert_eq!(scheduler.fire(now + 86400), TimerOp::Send);
+        scheduler.send_finished(now + 86400);
+
+        let mut restarted = Scheduler::with_storage(schedule, &storage);
+        restarted.start(now + 86400 + 3600);
+        assert_eq!(restarted.send_deadline, now + 2 * 86400);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    it "should keep the save and send deadlines independent" {
+        schedule.save_interval = 86400 * 2;
+        let mut scheduler = Scheduler::new(schedule);
+        scheduler.start(now);
+        assert_eq!(scheduler.next_deadline(), Some(now + 86400));
+        assert_eq!(scheduler.fire(now + 86400), TimerOp::Send);
+        scheduler.send_finished(now + 86400);
+        assert_eq!(scheduler.next_deadline(), Some(now + 86400 * 2));
+    }
+
+    it "should flush a full batch after the flush delay" {
+        scheduler.start(now);
+        scheduler.queue_full(now + 10);
+        scheduler.queue_full(now + 20);
+        assert_eq!(scheduler.next_deadline(), Some(now + 70));
+        assert_eq!(scheduler.fire(now + 69), TimerOp::None);
+        assert_eq!(scheduler.fire(now + 70), TimerOp::Flush);
+        assert_eq!(scheduler.state(), SchedulerState::Sending { op: TimerOp::Flush });
+
+        scheduler.send_finished(now + 75);
+        assert_eq!(scheduler.state(), SchedulerState::Waiting);
+        assert_eq!(scheduler.flush_deadline, None);
+        // The send interval still counts from the start.
+        assert_eq!(scheduler.send_deadline, now + 86400);
+    }
+
+    it "should send once when the flush and send deadlines are both due" {
+        scheduler.start(now);
+        scheduler.queue_full(now + 86380);
+        assert_eq!(scheduler.fire(now + 86440), TimerOp::Send);
+        scheduler.send_finished(now + 86440);
+        assert_eq!(scheduler.flush_deadline, None);
+        assert_eq!(scheduler.fire(now + 86440), TimerOp::Save);
+        assert_eq!(scheduler.fire(now + 86440), TimerOp::None);
+    }
+
+    it "should ignore a finished send it did not start" {
+        scheduler.start(now);
+        scheduler.send_finished(now + 10);
+        assert_eq!(scheduler.send_deadline, now + 86400);
+    }
+
+    it "should retry a deferred send" {
+        scheduler.start(now);
+        assert_eq!(scheduler.fire(now + 86400), TimerOp::Send);
+        scheduler.defer(now + 86460);
+        assert_eq!(scheduler.state(), SchedulerState::Deferred { op: TimerOp::Send, until: now + 86460 });
+        // Saves go on while the send waits.
+        assert_eq!(scheduler.fire(now + 86400), TimerOp::Save);
+        assert_eq!(scheduler.next_deadline(), Some(now + 86460));
+        assert_eq!(scheduler.fire(now + 86459), TimerOp::None);
+        assert_eq!(scheduler.fire(now + 86460), TimerOp::Send);
+        scheduler.send_finished(now + 86460);
+        assert_eq!(scheduler.send_deadline, now + 86460 + 86400);
+    }
+
+    it "should try a deferred flush as soon as the network changes" {
+        scheduler.start(now);
+        scheduler.queue_full(now);
+        assert_eq!(scheduler.fire(now + 60), TimerOp::Flush);
+        scheduler.defer(now + 120);
+        scheduler.network_changed(now + 90);
+        assert_eq!(scheduler.next_deadline(), Some(now + 90));
+        assert_eq!(scheduler.fire(now + 90), TimerOp::Flush);
+        scheduler.send_finished(now + 90);
+        assert_eq!(scheduler.state(), SchedulerState::Waiting);
+        assert_eq!(scheduler.send_deadline, now + 86400);
+    }
+
+    it "should send urgent events at once without the batch" {
+        scheduler.start(now);
+        scheduler.queue_full(now);
+        scheduler.urgent(now + 10);
+        assert_eq!(scheduler.next_deadline(), Some(now + 10));
+        assert_eq!(scheduler.fire(now + 10), TimerOp::Urgent);
+        assert_eq!(scheduler.state(), SchedulerState::Sending { op: TimerOp::Urgent });
+        scheduler.send_finished(now + 10);
+        assert_eq!(scheduler.urgent_deadline, None);
+        // The full batch still waits for its flush.
+        assert_eq!(scheduler.next_deadline(), Some(now + 60));
+        assert_eq!(scheduler.fire(now + 60), TimerOp::Flush);
+    }
+
+    it "should send urgent events with a deferred send" {
+        scheduler.start(now);
+        scheduler.queue_full(now);
+        assert_eq!(scheduler.fire(now + 60), TimerOp::Flush);
+        scheduler.defer(now + 120);
+        scheduler.urgent(now + 90);
+        assert_eq!(scheduler.fire(now + 90), TimerOp::None);
+        assert_eq!(scheduler.fire(now + 120), TimerOp::Flush);
+        scheduler.send_finished(now + 120);
+        assert_eq!(scheduler.urgent_deadline, None);
+        assert_eq!(scheduler.fire(now + 120), TimerOp::None);
+    }
+
+    it "should count a flush as the sends it covers" {
+        scheduler.start(now);
+        scheduler.queue_full(now);
+        scheduler.urgent(now + 10);
+        scheduler.flushed(now + 20);
+        assert_eq!(scheduler.next_deadline(), Some(now + 3600));
+
+        scheduler.queue_full(now + 100);
+        assert_eq!(scheduler.fire(now + 160), TimerOp::Flush);
+        scheduler.defer(now + 220);
+        scheduler.flushed(now + 190);
+        assert_eq!(scheduler.state(), SchedulerState::Waiting);
+        assert_eq!(scheduler.fire(now + 220), TimerOp::None);
+    }
+
+    it "should only defer a send in progress" {
+        scheduler.start(now);
+        scheduler.defer(now + 60);
+        scheduler.network_changed(now);
+        assert_eq!(scheduler.state(), SchedulerState::Waiting);
+    }
+
+    it "should stop" {
+        scheduler.start(now);
+        scheduler.queue_full(now);
+        scheduler.stop();
+        assert_eq!(scheduler.state(), SchedulerState::Stopped);
+        assert_eq!(scheduler.next_deadline(), None);
+        assert_eq!(scheduler.fire(now + 86400), TimerOp::None);
+    }
+
+    it "should count the first send interval from the start time" {
+        schedule.start_time = Some(now - 86000);
+        let mut scheduler = Scheduler::new(schedule.clone());
+        scheduler.start(now);
+        assert_eq!(scheduler.send_deadline, now + 400);
+
+        schedule.start_time = Some(now - 86400 * 3);
+        let mut overdue = Scheduler::new(schedule);
+        overdue.start(now);
+        assert_eq!(overdue.fire(now), TimerOp::Send);
+    }
+
+    it "should wait for the send window" {
+        fn utc(_: i64) -> i64 {
+            0
+        }
+        fn utc_plus_two(_: i64) -> i64 {
+            7200
+        }
+        schedule.send_at = SendWindow::parse("02:00");
+        let mut scheduler = Scheduler::new(schedule.clone());
+        scheduler.utc_offset = utc;
+        scheduler.start(now);
+        // 2016-05-27 02:00 UTC is the first 02:00 a day after 2016-05-25 22:36.
+        assert_eq!(scheduler.send_deadline, 1464314400);
+        assert_eq!(scheduler.fire(1464314400), TimerOp::Send);
+        scheduler.send_finished(1464314460);
+        assert_eq!(scheduler.send_deadline, 1464314400 + 86400);
+
+        let mut local = Scheduler::new(schedule);
+        local.utc_offset = utc_plus_two;
+        local.start(now);
+        assert_eq!(local.send_deadline, 1464314400 - 7200);
+    }
+
+    it "should parse send windows" {
+        assert_eq!(SendWindow::parse("02:00"), Some(SendWindow { secs: 7200 }));
+        assert_eq!(SendWindow::parse("23:59"), Some(SendWindow { secs: 86340 }));
+        assert_eq!(SendWindow::parse("24:00"), None);
+        assert_eq!(SendWindow::parse("2"), None);
+        assert_eq!(SendWindow::parse("02:00:00"), None);
+        assert_eq!(SendWindow { secs: 7200 }.next_at_or_after(1464314400, 0), 1464314400);
+    }
+
+    it "should read the schedule from the configuration" {
+        let mut cfg = Config::new();
+        cfg.parse_json(r#"{ "sendInterval": 10, "saveInterval": 2, "startTime": 0,
+                            "sendAt": "02:30", "flushDelay": 0 }"#.to_string());
+        let schedule = Schedule::from_parsed_config(&mut cfg).unwrap();
+        assert_eq!(schedule,
+                   Schedule {
+                       save_interval: 2,
+                       send_interval: 10,
+                       start_time: None,
+                       send_at: SendWindow::parse("02:30"),
+                       flush_delay: 0,
+                   });
+    }
+
+    it "should reject an invalid schedule instead of panicking" {
+        let mut cfg = Config::new();
+        cfg.parse_json(r#"{ "sendInterval": 0 }"#.to_string());
+        assert!(Schedule::from_parsed_config(&mut cfg).is_err());
+        cfg.parse_json(r#"{ "saveInterval": "hourly" }"#.to_string());
+        assert!(Schedule::from_parsed_config(&mut cfg).is_err());
+        cfg.parse_json(r#"{ "sendAt": "2am" }"#.to_string());
+        assert!(Schedule::from_parsed_config(&mut cfg).is_err());
+    }
+}
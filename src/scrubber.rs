@@ -0,0 +1,254 @@
+extern crate regex;
+extern crate serde_json;
+
+use config::{Config, get_optional_string, get_optional_u64};
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::regex::Regex;
+use self::serde_json::Value;
+
+// Removes personal data that ends up in event fields by accident, e.g. an
+// email address or a file path in a label.  The category, action and label of
+// every recorded event are scrubbed before the event is queued.  Configured in
+// metricsconfig.json:
+//
+//     "scrubbing": {
+//         "detectors": ["email", "ip", "mac", "homePath", "digits"],
+//         "minDigits": 9,
+//         "patterns": [
+//             { "pattern": "token=[A-Za-z0-9]+", "placeholder": "token=[redacted]" }
+//         ]
+//     }
+//
+// Each match of a built-in detector is replaced with a placeholder such as
+// "[email]"; "homePath" replaces only the home directory, so
+// "/home/alice/.config/app" becomes "[home]/.config/app".  "digits" matches
+// runs of at least "minDigits" (default 9) digits, e.g. phone or card
+// numbers.  A pattern's placeholder may refer to its groups ("$1") and
+// defaults to "[redacted]".  The application can also install a callback
+// with `MetricsController::set_scrubber`, which runs last.  Nothing is
+// scrubbed unless configured.  The number of redactions is in the stats.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+const KEY_SCRUBBING: &'static str = "scrubbing";
+const DEFAULT_MIN_DIGITS: u64 = 9;
+const DEFAULT_PLACEHOLDER: &'static str = "[redacted]";
+
+// The order matters: MAC addresses would otherwise be taken for IPv6
+// addresses, and the digits of an address for a digit run.
+const DETECTORS: [&'static str; 5] = ["email", "mac", "ip", "homePath", "digits"];
+
+const EMAIL: &'static str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}";
+const MAC: &'static str = r"\b[0-9A-Fa-f]{2}(?:[:-][0-9A-Fa-f]{2}){5}\b";
+const IPV6: &'static str = concat!(r"\b(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}\b",
+                                    r"|\b(?:[0-9A-Fa-f]{1,4}:){1,6}:[0-9A-Fa-f]{1,4}(?::[0-9A-Fa-f]{1,4}){0,5}\b",
+                                    r"|\b(?:[0-9A-Fa-f]{1,4}:){1,7}:",
+                                    r"|::[0-9A-Fa-f]{1,4}(?::[0-9A-Fa-f]{1,4}){0,6}\b");
+const IPV4: &'static str = r"\b(?:(?:25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])\.){3}(?:25[0-5]|2[0-4][0-9]|1[0-9][0-9]|[1-9]?[0-9])\b";
+const HOME_PATH: &'static str = r"(?:/home|/Users)/[^/\s]+|[A-Za-z]:\\Users\\[^\\\s]+";
+
+/// Replaces the text it is given; used for custom scrubbing.
+pub type ScrubCallback = Box<Fn(&str) -> String + Send>;
+
+pub struct ScrubRule {
+    pub pattern: Regex,
+    pub placeholder: String,
+}
+
+impl ScrubRule {
+    fn new(pattern: &str, placeholder: &str) -> Result<ScrubRule, String> {
+        let regex = try!(Regex::new(pattern)
+            .map_err(|e| format!("invalid scrubbing pattern {}: {}", pattern, e)));
+        Ok(ScrubRule {
+            pattern: regex,
+            placeholder: placeholder.to_string(),
+        })
+    }
+
+    fn detector(name: &str, min_digits: u64) -> Result<ScrubRule, String> {
+        match name {
+            "email" => ScrubRule::new(EMAIL, "[email]"),
+            "mac" => ScrubRule::new(MAC, "[mac]"),
+            "ip" => ScrubRule::new(&format!("{}|{}", IPV6, IPV4), "[ip]"),
+            "homePath" => ScrubRule::new(HOME_PATH, "[home]"),
+            "digits" => ScrubRule::new(&format!("[0-9]{{{},}}", min_digits), "[number]"),
+            _ => Err(format!("unknown scrubbing detector {}", name)),
+        }
+    }
+
+    fn from_value(value: &Value) -> Result<ScrubRule, String> {
+        let pattern = match try!(get_optional_string(value, "pattern")) {
+            Some(pattern) => pattern,
+            None => return Err("scrubbing pattern needs a pattern".to_string()),
+        };
+        let placeholder = try!(get_optional_string(value, "placeholder"))
+            .unwrap_or(DEFAULT_PLACEHOLDER.to_string());
+        ScrubRule::new(&pattern, &placeholder)
+    }
+}
+
+pub struct Scrubber {
+    rules: Vec<ScrubRule>,
+    callback: Option<ScrubCallback>,
+}
+
+impl Scrubber {
+    /// A scrubber that leaves everything as it is.
+    pub fn new() -> Scrubber {
+        Scrubber {
+            rules: Vec::new(),
+            callback: None,
+        }
+    }
+
+    pub fn from_config(cfg: &mut Config) -> Scrubber {
+        match Scrubber::from_parsed_config(cfg) {
+            Ok(scrubber) => scrubber,
+            Err(e) => {
+                // Without the configured rules events could leak the data
+                // they were meant to remove, so scrub with every detector.
+                logger().log(LogLevelFilter::Error,
+                             format!("Invalid scrubbing settings, using every detector: {}", e)
+                                 .as_str());
+                Scrubber::with_all_detectors()
+            }
+        }
+    }
+
+    pub fn from_parsed_config(cfg: &mut Config) -> Result<Scrubber, String> {
+        let value = match cfg.get(KEY_SCRUBBING) {
+            None => return Ok(Scrubber::new()),
+            Some(value @ Value::Object(_)) => value,
+            Some(_) => return Err("\"scrubbing\" must be an object".to_string()),
+        };
+        let min_digits = try!(get_optional_u64(&value, "minDigits")).unwrap_or(DEFAULT_MIN_DIGITS);
+        let mut names = Vec::new();
+        match value.find("detectors") {
+            None => {}
+            Some(&Value::Array(ref detectors)) => {
+                for detector in detectors {
+                    match detector.as_str() {
+                        Some(name) => names.push(name.to_string()),
+                        None => return Err("scrubbing detectors must be strings".to_string()),
+                    }
+                }
+            }
+            Some(_) => return Err("scrubbing detectors must be an array".to_string()),
+        }
+
+        if min_digits == 0 {
+            return Err("\"minDigits\" must be at least 1".to_string());
+        }
+        if let Some(name) = names.iter().find(|name| !DETECTORS.iter().any(|d| *d == name.as_str())) {
+            return Err(format!("unknown scrubbing detector {}", name));
+        }
+
+        let mut scrubber = Scrubber::new();
+        for name in DETECTORS.iter() {
+            if names.iter().any(|n| n.as_str() == *name) {
+                scrubber.rules.push(try!(ScrubRule::detector(name, min_digits)));
+            }
+        }
+        match value.find("patterns") {
+            None => {}
+            Some(&Value::Array(ref patterns)) => {
+                for pattern in patterns {
+                    scrubber.rules.push(try!(ScrubRule::from_value(pattern)));
+                }
+            }
+            Some(_) => return Err("scrubbing patterns must be an array".to_string()),
+        }
+        Ok(scrubber)
+    }
+
+    /// A scrubber with every built-in detector with the default settings.
+    pub fn with_all_detectors() -> Scrubber {
+        let mut scrubber = Scrubber::new();
+        for name in DETECTORS.iter() {
+            // The built-in patterns are known to be valid.
+            scrubber.rules.push(ScrubRule::detector(name, DEFAULT_MIN_DIGITS).unwrap());
+        }
+        scrubber
+    }
+
+    pub fn set_callback(&mut self, callback: Option<ScrubCallback>) {
+        self.callback = callback;
+    }
+
+    /// Returns the scrubbed text and the number of redactions made.  A
+    /// callback that changes the text counts as one redaction.
+    pub fn scrub(&self, text: &str) -> (String, u64) {
+        let mut scrubbed = text.to_string();
+        let mut redactions = 0;
+        for rule in &self.rules {
+            let matches = rule.pattern.find_iter(&scrubbed).count() as u64;
+            if matches > 0 {
+                scrubbed = rule.pattern.replace_all(&scrubbed, rule.placeholder.as_str());
+                redactions += matches;
+            }
+        }
+        if let Some(ref callback) = self.callback {
+            let replaced = callback(&scrubbed);
+            if replaced != scrubbed {
+                scrubbed = replaced;
+                redactions += 1;
+            }
+        }
+        (scrubbed, redactions)
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! scrubber {
+    before_each {
+        let all = Scrubber::with_all_detectors();
+        let mut cfg = Config::new();
+    }
+
+    it "should leave text alone without rules" {
+        assert_eq!(Scrubber::new().scrub("alice@example.com"),
+                   ("alice@example.com".to_string(), 0));
+    }
+
+    it "should replace emails and addresses" {
+        assert_eq!(all.scrub("from alice@example.com at 192.168.1.20"),
+                   ("from [email] at [ip]".to_string(), 2));
+        assert_eq!(all.scrub("peer fe80::1ff:fe23:4567:890a"), ("peer [ip]".to_string(), 1));
+        assert_eq!(all.scrub("wlan0 00:1A:2b:3C:4d:5E"), ("wlan0 [mac]".to_string(), 1));
+    }
+
+    it "should replace only the home directory of a path" {
+        assert_eq!(all.scrub("/home/alice/.config/app.json"),
+                   ("[home]/.config/app.json".to_string(), 1));
+        assert_eq!(all.scrub(r"C:\Users\bob\AppData"), (r"[home]\AppData".to_string(), 1));
+    }
+
+    it "should replace long digit runs only" {
+        assert_eq!(all.scrub("call 0123456789, code 42"),
+                   ("call [number], code 42".to_string(), 1));
+    }
+
+    it "should use configured detectors, patterns and a callback" {
+        cfg.parse_json(r#"{ "scrubbing": { "detectors": ["digits"], "minDigits": 4,
+                                           "patterns": [ { "pattern": "token=\\w+",
+                                                           "placeholder": "token=[x]" } ] } }"#
+            .to_string());
+        let mut scrubber = Scrubber::from_parsed_config(&mut cfg).unwrap();
+        assert_eq!(scrubber.scrub("token=abc pin 1234 alice@example.com"),
+                   ("token=[x] pin [number] alice@example.com".to_string(), 2));
+
+        scrubber.set_callback(Some(Box::new(|text: &str| text.replace("alice", "[name]"))));
+        assert_eq!(scrubber.scrub("alice"), ("[name]".to_string(), 1));
+    }
+
+    it "should reject unknown detectors and invalid patterns" {
+        cfg.parse_json(r#"{ "scrubbing": { "detectors": ["ssn"] } }"#.to_string());
+        assert!(Scrubber::from_parsed_config(&mut cfg).is_err());
+        cfg.parse_json(r#"{ "scrubbing": { "patterns": [ { "pattern": "(" } ] } }"#.to_string());
+        assert!(Scrubber::from_parsed_config(&mut cfg).is_err());
+    }
+}
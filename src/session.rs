@@ -0,0 +1,414 @@
+extern crate serde_json;
+extern crate uuid;
+
+use config::Config;
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::serde_json::Value;
+use self::serde_json::builder::ObjectBuilder;
+use self::uuid::Uuid;
+use storage::Storage;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+
+// Google Analytics sessions.  A session starts when the application calls
+// `MetricsController::start_session`; its first hit carries `sc=start`.  It
+// ends when the application calls `end_session` or after "sessionTimeout"
+// seconds (default 1800) without events, in which case the next event starts
+// a new one.  Every hit in a session carries the session id (cd9) and its
+// sequence number in the session (cd10).  When a session ends, a
+// "metrics-controller"/"session-duration" event is sent with `sc=end`, the
+// duration in seconds as its value and the reason as its label.
+//
+// The open session is kept in session.dat so that a session interrupted by a
+// crash is ended on the next startup:
+//
+//     {"id": "...", "started": 1464215817, "lastActivity": 1464216017, "sequence": 12}
+//
+// To avoid a write for every event, the last activity is only saved once a
+// minute, so the duration of an interrupted session may be a little short.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+#[cfg(not(test))]
+const SESSION_FILE: &'static str = "session.dat";
+#[cfg(not(test))]
+const KEY_SESSION_TIMEOUT: &'static str = "sessionTimeout";
+const DEFAULT_SESSION_TIMEOUT: i64 = 1800;
+// Seconds between saves of the last activity.
+const PERSIST_INTERVAL: i64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionControl {
+    Start,
+    End,
+}
+
+impl SessionControl {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SessionControl::Start => "start",
+            SessionControl::End => "end",
+        }
+    }
+
+    fn from_str(name: &str) -> Option<SessionControl> {
+        match name {
+            "start" => Some(SessionControl::Start),
+            "end" => Some(SessionControl::End),
+            _ => None,
+        }
+    }
+}
+
+/// The session an event belongs to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    pub id: String,
+    /// 1 for the first hit of the session.
+    pub sequence: u64,
+    pub control: Option<SessionControl>,
+}
+
+impl SessionInfo {
+    pub fn to_value(&self) -> Value {
+        let mut builder = ObjectBuilder::new()
+            .insert("id", &self.id)
+            .insert("sequence", self.sequence);
+        if let Some(control) = self.control {
+            builder = builder.insert("control", control.as_str());
+        }
+        builder.build()
+    }
+
+    pub fn from_value(value: &Value) -> Option<SessionInfo> {
+        match (value.find("id").and_then(|v| v.as_str()),
+               value.find("sequence").and_then(|v| v.as_u64())) {
+            (Some(id), Some(sequence)) => {
+                Some(SessionInfo {
+                    id: id.to_string(),
+                    sequence: sequence,
+                    control: value.find("control")
+                        .and_then(|v| v.as_str())
+                        .and_then(SessionControl::from_str),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    pub id: String,
+    /// Seconds since the epoch.
+    pub started: i64,
+    pub last_activity: i64,
+    /// Hits sent in the session so far.
+    pub sequence: u64,
+}
+
+impl Session {
+    fn new(now: i64) -> Session {
+        Session {
+            id: Uuid::new_v4().to_hyphenated_string(),
+            started: now,
+            last_activity: now,
+            sequence: 0,
+        }
+    }
+
+    pub fn duration(&self) -> u64 {
+        if self.last_activity > self.started {
+            (self.last_activity - self.started) as u64
+        } else {
+            0
+        }
+    }
+
+    /// The session info of the hit that ends the session.
+    pub fn end_info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id.clone(),
+            sequence: self.sequence + 1,
+            control: Some(SessionControl::End),
+        }
+    }
+
+    fn from_json(json: &str) -> Option<Session> {
+        let value: Value = match serde_json::from_str(json) {
+            Ok(value) => value,
+            Err(_) => return None,
+        };
+        match (value.find("id").and_then(|v| v.as_str()),
+               value.find("started").and_then(|v| v.as_i64()),
+               value.find("lastActivity").and_then(|v| v.as_i64()),
+               value.find("sequence").and_then(|v| v.as_u64())) {
+            (Some(id), Some(started), Some(last_activity), Some(sequence)) => {
+                Some(Session {
+                    id: id.to_string(),
+                    started: started,
+                    last_activity: last_activity,
+                    sequence: sequence,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> Result<String, String> {
+        let value = ObjectBuilder::new()
+            .insert("id", &self.id)
+            .insert("started", self.started)
+            .insert("lastActivity", self.last_activity)
+            .insert("sequence", self.sequence)
+            .build();
+        serde_json::to_string(&value).map_err(|e| e.to_string())
+    }
+}
+
+pub struct SessionTracker {
+    // None if the session is not persisted.
+    file_name: Option<String>,
+    timeout: i64,
+    current: Option<Session>,
+    // True between start_session and end_session, so that a session ended by
+    // the timeout is followed by a new one.
+    active: bool,
+    persisted_activity: i64,
+}
+
+impl SessionTracker {
+    /// Reads the session left open by the previous run from `file_name`.
+    /// Returns the tracker, with no session open, and that session, which
+    /// the caller reports as interrupted.
+    pub fn open(file_name: &str, timeout: i64) -> (SessionTracker, Option<Session>) {
+        let mut json = String::new();
+        let interrupted = match File::open(file_name) {
+            Ok(mut file) => {
+                match file.read_to_string(&mut json).ok().and_then(|_| Session::from_json(&json)) {
+                    Some(session) => Some(session),
+                    None => {
+                        logger().log(LogLevelFilter::Error,
+                                     format!("{} is corrupt, ignoring it", file_name).as_str());
+                        None
+                    }
+                }
+            }
+            Err(_) => None,
+        };
+        let mut tracker = SessionTracker::in_memory(timeout);
+        tracker.file_name = Some(file_name.to_string());
+        if interrupted.is_some() {
+            tracker.remove_file();
+        }
+        (tracker, interrupted)
+    }
+
+    /// A tracker that does not persist its session.
+    pub fn in_memory(timeout: i64) -> SessionTracker {
+        SessionTracker {
+            file_name: None,
+            timeout: timeout,
+            current: None,
+            active: false,
+            persisted_activity: 0,
+        }
+    }
+
+    pub fn current(&self) -> Option<&Session> {
+        self.current.as_ref()
+    }
+
+    /// Starts a new session.  Returns the session it replaces, if any.
+    pub fn start(&mut self, now: i64) -> Option<Session> {
+        let ended = self.end(now);
+        self.active = true;
+        self.current = Some(Session::new(now));
+        self.save(now);
+        ended
+    }
+
+    /// Ends the current session, if any, and returns it.
+    pub fn end(&mut self, now: i64) -> Option<Session> {
+        self.active = false;
+        self.finish(Some(now))
+    }
+
+    /// Ends the current session if it has been idle for the timeout, and
+    /// returns it.  Its duration ends at the last activity.
+    pub fn expire(&mut self, now: i64) -> Option<Session> {
+        let idle = match self.current {
+            Some(ref session) => now - session.last_activity >= self.timeout,
+            None => false,
+        };
+        if idle { self.finish(None) } else { None }
+    }
+
+    /// The session info `next_hit` would return, without counting the hit.
+    /// A session that `next_hit` would start gets a different id, of the
+    /// same length.
+    pub fn peek_hit(&self) -> Option<SessionInfo> {
+        let (id, sequence) = match self.current {
+            Some(ref session) => (session.id.clone(), session.sequence + 1),
+            None if self.active => (Uuid::new_v4().to_hyphenated_string(), 1),
+            None => return None,
+        };
+        Some(SessionInfo {
+            id: id,
+            sequence: sequence,
+            control: if sequence == 1 { Some(SessionControl::Start) } else { None },
+        })
+    }
+
+    /// Counts a hit in the current session, starting one if a session timed
+    /// out since `start`.  Returns the session info for the hit.
+    pub fn next_hit(&mut self, now: i64) -> Option<SessionInfo> {
+        if self.current.is_none() && self.active {
+            self.current = Some(Session::new(now));
+        }
+        let info = match self.current {
+            Some(ref mut session) => {
+                session.sequence += 1;
+                session.last_activity = now;
+                SessionInfo {
+                    id: session.id.clone(),
+                    sequence: session.sequence,
+                    control: if session.sequence == 1 {
+                        Some(SessionControl::Start)
+                    } else {
+                        None
+                    },
+                }
+            }
+            None => return None,
+        };
+        if info.sequence == 1 || now - self.persisted_activity >= PERSIST_INTERVAL {
+            self.save(now);
+        }
+        Some(info)
+    }
+
+    fn finish(&mut self, end: Option<i64>) -> Option<Session> {
+        let mut session = match self.current.take() {
+            Some(session) => session,
+            None => return None,
+        };
+        if let Some(end) = end {
+            session.last_activity = end;
+        }
+        self.remove_file();
+        Some(session)
+    }
+
+    fn save(&mut self, now: i64) {
+        self.persisted_activity = now;
+        let (file_name, session) = match (self.file_name.as_ref(), self.current.as_ref()) {
+            (Some(file_name), Some(session)) => (file_name, session),
+            _ => return,
+        };
+        let written = session.to_json()
+            .and_then(|json| {
+                File::create(file_name)
+                    .and_then(|mut file| file.write_all(json.as_bytes()))
+                    .map_err(|e| e.to_string())
+            });
+        if let Err(e) = written {
+            logger().log(LogLevelFilter::Error,
+                         format!("Could not write {}: {}", file_name, e).as_str());
+        }
+    }
+
+    fn remove_file(&self) {
+        if let Some(ref file_name) = self.file_name {
+            let _ = fs::remove_file(file_name);
+        }
+    }
+}
+
+/// The tracker for session.dat, with the timeout from metricsconfig.json,
+/// and the session interrupted in the previous run.
+#[cfg(not(test))]
+pub fn open_default(storage: &Storage, cfg: &mut Config) -> (SessionTracker, Option<Session>) {
+    SessionTracker::open(&storage.path(SESSION_FILE), timeout_from_config(cfg))
+}
+
+#[cfg(test)]
+pub fn open_default(_storage: &Storage, _cfg: &mut Config) -> (SessionTracker, Option<Session>) {
+    (SessionTracker::in_memory(DEFAULT_SESSION_TIMEOUT), None)
+}
+
+#[cfg(not(test))]
+fn timeout_from_config(cfg: &mut Config) -> i64 {
+    match cfg.get(KEY_SESSION_TIMEOUT) {
+        None => DEFAULT_SESSION_TIMEOUT,
+        Some(value) => {
+            match value.as_u64() {
+                Some(secs) if secs > 0 => secs as i64,
+                _ => {
+                    logger().log(LogLevelFilter::Error,
+                                 "\"sessionTimeout\" must be a positive number of seconds");
+                    DEFAULT_SESSION_TIMEOUT
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! session_tracker {
+    before_each {
+        let file = "test_session.dat";
+        let _ = fs::remove_file(file);
+        let now = 1464215817;
+        let mut tracker = SessionTracker::in_memory(100);
+    }
+
+    it "should not track events outside a session" {
+        assert_eq!(tracker.next_hit(now), None);
+    }
+
+    it "should number the hits of a session" {
+        assert_eq!(tracker.start(now), None);
+        let first = tracker.next_hit(now).unwrap();
+        assert_eq!(first.sequence, 1);
+        assert_eq!(first.control, Some(SessionControl::Start));
+        let second = tracker.next_hit(now + 5).unwrap();
+        assert_eq!((second.id, second.sequence, second.control), (first.id, 2, None));
+
+        let ended = tracker.end(now + 10).unwrap();
+        assert_eq!(ended.duration(), 10);
+        assert_eq!(ended.end_info().sequence, 3);
+        assert_eq!(tracker.next_hit(now + 20), None);
+    }
+
+    it "should end an idle session and start a new one on the next hit" {
+        tracker.start(now);
+        let first = tracker.next_hit(now + 10).unwrap();
+        assert_eq!(tracker.expire(now + 109), None);
+        let expired = tracker.expire(now + 110).unwrap();
+        assert_eq!(expired.duration(), 10);
+
+        let next = tracker.next_hit(now + 120).unwrap();
+        assert!(next.id != first.id);
+        assert_eq!(next.control, Some(SessionControl::Start));
+    }
+
+    it "should report a session interrupted by a crash once" {
+        {
+            let (mut tracker, interrupted) = SessionTracker::open(file, 100);
+            assert_eq!(interrupted, None);
+            tracker.start(now);
+            tracker.next_hit(now + 30);
+            tracker.next_hit(now + 100);
+        }
+        let (_, interrupted) = SessionTracker::open(file, 100);
+        let session = interrupted.unwrap();
+        assert_eq!((session.sequence, session.duration()), (2, 100));
+        assert_eq!(SessionTracker::open(file, 100).1, None);
+    }
+}
@@ -0,0 +1,1044 @@
+extern crate serde_json;
+extern crate uuid;
+
+use compression::Compression;
+use connector::ConnectionConfig;
+use config::{Config, get_optional_string, get_optional_u64};
+use events::Event;
+use events::Events;
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use queue_time::batch_body;
+use self::serde_json::Value;
+use self::uuid::Uuid;
+use stats::Stats;
+use storage::Storage;
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use telemetry::TelemetryConfig;
+use transmitter::{Transmitter, METRICS_SERVER_URL, RETRY_MAX, RETRY_WAIT};
+use validation::{DryRun, HitReport, parse_hit_parsing_result, validate_hit};
+
+// A sink is one destination for recorded events.  The worker takes each batch
+// off the event queue once and hands it to every configured sink.  A sink
+// keeps only the events its include/exclude rules accept, encodes them in its
+// own format and queues the result in its own file before sending it.  Each
+// sink has its own retry settings, so a backend that is down does not hold up
+// or drop data for the others.  Sinks whose backend accepts it can compress
+// their request bodies ("compression": "gzip" or "deflate").
+//
+// Sinks are configured in metricsconfig.json:
+//
+//     "sinks": [
+//         { "name": "ga", "format": "ga",
+//           "exclude": [ { "category": "eng*" } ] },
+//         { "name": "internal", "format": "telemetry",
+//           "url": "https://collector.example.com",
+//           "include": [ { "category": "eng*" } ],
+//           "retries": 3, "retryWait": 1000, "compression": "gzip" }
+//     ]
+//
+// Without a "sinks" entry a single sink is created from the top-level
+// settings: Telemetry if "telemetryServer" is set, Google Analytics otherwise.
+//
+// Setting "dryRun" to "local" or "debug" puts every sink in dry-run mode:
+// Google Analytics batches are checked by the local validator or posted hit
+// by hit to the validation server ("debugUrl", Google's /debug/collect by
+// default) and nothing is sent to production.  Telemetry pings are dropped.
+// Dry-run batches are queued in a separate file, so batches waiting to be
+// sent to production are left alone.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+const KEY_SINKS: &'static str = "sinks";
+const KEY_CONNECTION: &'static str = "connection";
+const KEY_DRY_RUN: &'static str = "dryRun";
+const KEY_DEBUG_URL: &'static str = "debugUrl";
+const KEY_SEND_DELETION_REQUEST: &'static str = "sendDeletionRequest";
+const DELETION_REQUEST_CATEGORY: &'static str = "metrics-controller";
+const DELETION_REQUEST_ACTION: &'static str = "deletion-request";
+const DEFAULT_GA_SINK: &'static str = "ga";
+const DEFAULT_TELEMETRY_SINK: &'static str = "telemetry";
+
+/// Matches events on category, action and label.  A missing field matches
+/// anything and a pattern ending in `*` matches by prefix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventRule {
+    pub category: Option<String>,
+    pub action: Option<String>,
+    pub label: Option<String>,
+}
+
+impl EventRule {
+    pub fn from_value(value: &Value) -> Result<EventRule, String> {
+        if !value.is_object() {
+            return Err("rule must be an object".to_string());
+        }
+        Ok(EventRule {
+            category: try!(get_optional_string(value, "category")),
+            action: try!(get_optional_string(value, "action")),
+            label: try!(get_optional_string(value, "label")),
+        })
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        pattern_matches(&self.category, &event.category) &&
+        pattern_matches(&self.action, &event.action) &&
+        pattern_matches(&self.label, &event.label)
+    }
+}
+
+fn pattern_matches(pattern: &Option<String>, value: &str) -> bool {
+    match *pattern {
+        None => true,
+        Some(ref p) if p.ends_with('*') => value.starts_with(&p[..p.len() - 1]),
+        Some(ref p) => p == value,
+    }
+}
+
+/// An event is accepted when it matches at least one include rule (or there
+/// are none) and no exclude rule.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EventFilter {
+    pub include: Vec<EventRule>,
+    pub exclude: Vec<EventRule>,
+}
+
+impl EventFilter {
+    pub fn accepts(&self, event: &Event) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|rule| rule.matches(event))) &&
+        !self.exclude.iter().any(|rule| rule.matches(event))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SinkFormat {
+    GoogleAnalytics { url: String },
+    Telemetry(TelemetryConfig),
+}
+
+pub struct Sink {
+    name: String,
+    format: SinkFormat,
+    filter: EventFilter,
+    transmitter: Transmitter,
+    queue: PendingBatches,
+    // Urgent events, sent ahead of and apart from `queue`.
+    urgent: PendingBatches,
+    failures: u32,
+    dry_run: Option<DryRun>,
+    storage: Storage,
+}
+
+impl Sink {
+    pub fn new(name: &str,
+               format: SinkFormat,
+               filter: EventFilter,
+               transmitter: Transmitter,
+               storage: &Storage)
+               -> Sink {
+        Sink {
+            name: name.to_string(),
+            format: format,
+            filter: filter,
+            transmitter: transmitter,
+            queue: PendingBatches::load(&storage.path(&format!("{}_pending.dat", name))),
+            urgent: PendingBatches::load(&storage.path(&format!("{}_urgent.dat", name))),
+            failures: 0,
+            dry_run: None,
+            storage: storage.clone(),
+        }
+    }
+
+    /// Validates batches instead of sending them.
+    pub fn set_dry_run(&mut self, dry_run: DryRun) {
+        let file_name = format!("{}_dryrun.dat", self.name);
+        self.queue = PendingBatches::load(&self.storage.path(&file_name));
+        let file_name = format!("{}_dryrun_urgent.dat", self.name);
+        self.urgent = PendingBatches::load(&self.storage.path(&file_name));
+        self.dry_run = Some(dry_run);
+    }
+
+    pub fn google_analytics(connection: ConnectionConfig, storage: &Storage) -> Sink {
+        Sink::new(DEFAULT_GA_SINK,
+                  SinkFormat::GoogleAnalytics { url: METRICS_SERVER_URL.to_string() },
+                  EventFilter::default(),
+                  Transmitter::with_connection(RETRY_MAX, RETRY_WAIT, connection),
+                  storage)
+    }
+
+    fn from_value(value: &Value,
+                  default_connection: &ConnectionConfig,
+                  storage: &Storage)
+                  -> Result<Sink, String> {
+        let name = match try!(get_optional_string(value, "name")) {
+            Some(name) => name,
+            None => return Err("sink has no name".to_string()),
+        };
+        // The name is part of the file name of the pending batches.
+        let valid = |c: char| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' => true,
+            _ => false,
+        };
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(format!("sink name {:?} may only have letters, digits, _ and -", name));
+        }
+        let url = try!(get_optional_string(value, "url"));
+        let format = match try!(get_optional_string(value, "format")) {
+            None => SinkFormat::GoogleAnalytics { url: url.unwrap_or(METRICS_SERVER_URL.to_string()) },
+            Some(ref f) if f == "ga" => {
+                SinkFormat::GoogleAnalytics { url: url.unwrap_or(METRICS_SERVER_URL.to_string()) }
+            }
+            Some(ref f) if f == "telemetry" => {
+                let mut tc = match url {
+                    Some(url) => TelemetryConfig::new(&url),
+                    None => return Err(format!("telemetry sink {} has no url", name)),
+                };
+                if let Some(namespace) = try!(get_optional_string(value, "namespace")) {
+                    tc.namespace = namespace;
+                }
+                if let Some(doc_type) = try!(get_optional_string(value, "docType")) {
+                    tc.doc_type = doc_type;
+                }
+                if let Some(doc_version) = try!(get_optional_u64(value, "docVersion")) {
+                    tc.doc_version = doc_version;
+                }
+                SinkFormat::Telemetry(tc)
+            }
+            Some(f) => return Err(format!("unknown format {} for sink {}", f, name)),
+        };
+        let filter = EventFilter {
+            include: try!(get_rules(value, "include")),
+            exclude: try!(get_rules(value, "exclude")),
+        };
+        let retries = try!(get_optional_u64(value, "retries")).unwrap_or(RETRY_MAX as u64);
+        let wait_time = try!(get_optional_u64(value, "retryWait")).unwrap_or(RETRY_WAIT as u64);
+        let compression = match try!(get_optional_string(value, "compression")) {
+            None => Compression::None,
+            Some(c) => {
+                match Compression::from_name(&c) {
+                    Some(compression) => compression,
+                    None => return Err(format!("unknown compression {} for sink {}", c, name)),
+                }
+            }
+        };
+
+        let connection = match value.find("connection") {
+            Some(connection) => try!(ConnectionConfig::from_value(Some(connection))),
+            None => default_connection.clone(),
+        };
+
+        let mut transmitter = Transmitter::with_connection(retries as u32, wait_time as u32, connection);
+        transmitter.set_compression(compression);
+        Ok(Sink::new(&name, format, filter, transmitter, storage))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Encodes the events of `batch` accepted by this sink's filter and adds
+    /// them to the sink's queue.
+    pub fn enqueue(&mut self, events: &Events, batch: &[Event]) {
+        let selected = self.select(batch);
+        self.enqueue_unfiltered(events, &selected);
+    }
+
+    /// Like `enqueue`, but for urgent events, which are sent before the
+    /// batches already queued.
+    pub fn enqueue_urgent(&mut self, events: &Events, batch: &[Event]) {
+        let selected = self.select(batch);
+        for payload in self.encode(events, &selected) {
+            self.urgent.add(payload);
+        }
+    }
+
+    fn select(&self, batch: &[Event]) -> Vec<Event> {
+        batch.iter()
+            .filter(|event| self.filter.accepts(event))
+            .cloned()
+            .collect()
+    }
+
+    fn enqueue_unfiltered(&mut self, events: &Events, selected: &[Event]) {
+        for payload in self.encode(events, selected) {
+            self.queue.add(payload);
+        }
+    }
+
+    // Encodes events for this sink's backend, as the payloads of one or more
+    // batches.
+    fn encode(&self, events: &Events, selected: &[Event]) -> Vec<Value> {
+        let mut payloads = Vec::new();
+        if selected.is_empty() {
+            return payloads;
+        }
+
+        match self.format {
+            SinkFormat::GoogleAnalytics { .. } => {
+                // Every hit carries its own client id.
+                payloads.push(events.format_batch(selected));
+            }
+            SinkFormat::Telemetry(ref config) => {
+                // A ping has a single client id, so a batch recorded across a
+                // reset or rotation of the id is split into several pings.
+                let mut start = 0;
+                while start < selected.len() {
+                    let client_id = &selected[start].client_id;
+                    let end = selected[start..]
+                        .iter()
+                        .position(|event| &event.client_id != client_id)
+                        .map_or(selected.len(), |len| start + len);
+                    let doc_id = Uuid::new_v4().to_hyphenated_string();
+                    payloads.push(events.format_ping(&doc_id, config, &selected[start..end]));
+                    start = end;
+                }
+            }
+        }
+        payloads
+    }
+
+    /// Sends the queued batches, urgent ones first, then oldest first.  Stops
+    /// at the first failure and keeps the remaining batches for the next
+    /// send.  Returns true if every batch was sent.  `events` is only locked
+    /// to update the stats, not while a batch is being sent.
+    pub fn flush(&mut self, events: &Mutex<Events>) -> bool {
+        self.flush_queue(true, events) && self.flush_queue(false, events)
+    }
+
+    /// Sends only the urgent batches, leaving the others queued.
+    pub fn flush_urgent(&mut self, events: &Mutex<Events>) -> bool {
+        self.flush_queue(true, events)
+    }
+
+    fn pending(&mut self, urgent: bool) -> &mut PendingBatches {
+        if urgent {
+            &mut self.urgent
+        } else {
+            &mut self.queue
+        }
+    }
+
+    fn flush_queue(&mut self, urgent: bool, events: &Mutex<Events>) -> bool {
+        let ids = self.pending(urgent).ids();
+        for batch_id in ids {
+            let payload = match self.pending(urgent).get(&batch_id) {
+                Some(payload) => payload,
+                None => continue,
+            };
+            let (payload, stale) = {
+                let ev_data = events.lock().unwrap();
+                self.add_queue_time(payload, &ev_data)
+            };
+            let sent = match (self.dry_run.clone(), &payload) {
+                // Every hit was stale.
+                (_, &Value::String(ref body)) if body.is_empty() => true,
+                (Some(dry_run), _) => self.validate(&dry_run, &batch_id, &payload, events),
+                (None, _) => self.transmit(&batch_id, &payload),
+            };
+
+            let (uncompressed, transmitted) = self.transmitter.take_byte_counts();
+            let mut ev_data = events.lock().unwrap();
+            ev_data.stats_mut().record_transmission(uncompressed, transmitted);
+
+            if sent {
+                ev_data.stats_mut().stale_dropped += stale;
+                self.pending(urgent).remove(&batch_id);
+                self.failures = 0;
+            } else {
+                self.failures += 1;
+                logger().log(LogLevelFilter::Info,
+                             format!("Sink {} failed to send ({} consecutive failures), {} \
+                                      batches kept for a later retry",
+                                     self.name,
+                                     self.failures,
+                                     self.queue.len() + self.urgent.len())
+                                 .as_str());
+                return false;
+            }
+        }
+        true
+    }
+
+    // Turns a queued Google Analytics batch into the request body, with the
+    // queue time of each hit as of now.  Returns the body and the number of
+    // stale hits left out.  Malformed batches are returned as they are.
+    fn add_queue_time(&self, payload: Value, events: &Events) -> (Value, u64) {
+        match self.format {
+            SinkFormat::GoogleAnalytics { .. } => {
+                match batch_body(&payload, events.clock().now_millis(), events.stale_hit_policy()) {
+                    Some((body, stale)) => (Value::String(body), stale),
+                    None => (payload, 0),
+                }
+            }
+            SinkFormat::Telemetry(_) => (payload, 0),
+        }
+    }
+
+    fn transmit(&mut self, batch_id: &str, payload: &Value) -> bool {
+        match self.format {
+            SinkFormat::GoogleAnalytics { ref url } => {
+                match payload.as_str() {
+                    Some(body) => self.transmitter.transmit_to(url, body.to_string()),
+                    None => {
+                        logger().log(LogLevelFilter::Error,
+                                     format!("Dropping malformed batch {} in sink {}",
+                                             batch_id,
+                                             self.name)
+                                         .as_str());
+                        true
+                    }
+                }
+            }
+            SinkFormat::Telemetry(ref config) => {
+                let doc_id = payload.find("id").and_then(|id| id.as_str()).unwrap_or(batch_id);
+                match serde_json::to_string(payload) {
+                    Ok(body) => self.transmitter.transmit_json(&config.submit_url(doc_id), body),
+                    Err(e) => {
+                        logger().log(LogLevelFilter::Error,
+                                     format!("Could not serialize ping {}: {}", batch_id, e)
+                                         .as_str());
+                        true
+                    }
+                }
+            }
+        }
+    }
+
+    // Checks the hits of a dry-run batch and records the reports.  Returns
+    // false if the validation server could not be reached, so the batch is
+    // kept and checked again later.
+    fn validate(&mut self,
+                dry_run: &DryRun,
+                batch_id: &str,
+                payload: &Value,
+                events: &Mutex<Events>)
+                -> bool {
+        let body = match (&self.format, payload.as_str()) {
+            (&SinkFormat::GoogleAnalytics { .. }, Some(body)) => body,
+            _ => {
+                logger().log(LogLevelFilter::Info,
+                             format!("Dry run: not sending batch {} of sink {}", batch_id, self.name)
+                                 .as_str());
+                return true;
+            }
+        };
+
+        let mut reports = Vec::new();
+        for hit in body.lines().filter(|hit| !hit.is_empty()) {
+            match *dry_run {
+                DryRun::Local => reports.push(validate_hit(hit)),
+                DryRun::Debug { ref url } => {
+                    let result = self.transmitter
+                        .post_for_response(url, hit.to_string())
+                        .and_then(|response| parse_hit_parsing_result(&response));
+                    match result {
+                        Ok(mut hit_reports) => reports.append(&mut hit_reports),
+                        Err(e) => {
+                            logger().log(LogLevelFilter::Error,
+                                         format!("Could not validate batch {}: {}", batch_id, e)
+                                             .as_str());
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+
+        for report in reports.iter().filter(|report| !report.valid) {
+            log_invalid_hit(&self.name, report);
+        }
+        events.lock().unwrap().add_validation_reports(reports);
+        true
+    }
+}
+
+fn log_invalid_hit(sink_name: &str, report: &HitReport) {
+    for message in &report.messages {
+        logger().log(LogLevelFilter::Error,
+                     format!("Invalid hit in sink {}: {} {}: {} ({})",
+                             sink_name,
+                             message.message_type,
+                             message.parameter.as_ref().map(|p| p.as_str()).unwrap_or("-"),
+                             message.description,
+                             report.hit)
+                         .as_str());
+    }
+}
+
+pub struct Sinks {
+    sinks: Vec<Sink>,
+    deletion_request_enabled: bool,
+}
+
+impl Sinks {
+    pub fn new(sinks: Vec<Sink>) -> Sinks {
+        Sinks {
+            sinks: sinks,
+            deletion_request_enabled: false,
+        }
+    }
+
+    pub fn from_parsed_config(cfg: &mut Config, storage: &Storage) -> Sinks {
+        let connection = match ConnectionConfig::from_value(cfg.get(KEY_CONNECTION).as_ref()) {
+            Ok(connection) => connection,
+            Err(e) => {
+                // Sending with settings other than the ones asked for (e.g.
+                // bypassing a required proxy) is worse than not sending.
+                logger().log(LogLevelFilter::Error,
+                             format!("Invalid connection settings, not sending: {}", e).as_str());
+                return Sinks::new(Vec::new());
+            }
+        };
+
+        let dry_run = match cfg.get(KEY_DRY_RUN) {
+            None => None,
+            Some(value) => {
+                let debug_url = cfg.get(KEY_DEBUG_URL).and_then(|url| url.as_str().map(|s| s.to_string()));
+                match value.as_str().and_then(|name| DryRun::from_name(name, debug_url)) {
+                    Some(dry_run) => Some(dry_run),
+                    None => {
+                        // Sending to production when a dry run was asked for
+                        // is worse than not sending at all.
+                        logger().log(LogLevelFilter::Error,
+                                     "\"dryRun\" must be \"local\" or \"debug\", not sending");
+                        return Sinks::new(Vec::new());
+                    }
+                }
+            }
+        };
+
+        let mut sinks = match cfg.get(KEY_SINKS) {
+            Some(sinks_value) => Sinks::read_sinks(&sinks_value, &connection, storage),
+            None => {
+                let sink = match TelemetryConfig::from_parsed_config(cfg) {
+                    Some(tc) => {
+                        Sink::new(DEFAULT_TELEMETRY_SINK,
+                                  SinkFormat::Telemetry(tc),
+                                  EventFilter::default(),
+                                  Transmitter::with_connection(RETRY_MAX, RETRY_WAIT, connection),
+                                  storage)
+                    }
+                    None => Sink::google_analytics(connection, storage),
+                };
+                vec![sink]
+            }
+        };
+
+        if let Some(dry_run) = dry_run {
+            for sink in &mut sinks {
+                sink.set_dry_run(dry_run.clone());
+            }
+        }
+        let mut sinks = Sinks::new(sinks);
+        sinks.deletion_request_enabled = match cfg.get(KEY_SEND_DELETION_REQUEST) {
+            Some(Value::Bool(send)) => send,
+            _ => false,
+        };
+        sinks
+    }
+
+    fn read_sinks(sinks_value: &Value,
+                  connection: &ConnectionConfig,
+                  storage: &Storage)
+                  -> Vec<Sink> {
+        let mut sinks = Vec::new();
+        match sinks_value.as_array() {
+            Some(values) => {
+                for value in values {
+                    match Sink::from_value(value, connection, storage) {
+                        Ok(sink) => sinks.push(sink),
+                        Err(e) => {
+                            logger().log(LogLevelFilter::Error,
+                                         format!("Ignoring sink: {}", e).as_str())
+                        }
+                    }
+                }
+            }
+            None => logger().log(LogLevelFilter::Error, "\"sinks\" must be an array"),
+        }
+        sinks
+    }
+
+    /// Takes the urgent events and the next batch of events off the queue,
+    /// routes them to every sink and lets each sink send what it has queued,
+    /// urgent events first.  Batches left over from earlier failures are sent
+    /// first; while any sink still has some, the queue is left as it is, so
+    /// that events pile up in the bounded queue rather than in the sinks'
+    /// pending batches.  Returns true if every sink sent everything it had
+    /// queued.  Without any sink, e.g. because of an invalid config, the
+    /// events are left queued and false is returned.  `events` is locked
+    /// while the batches are encoded but not while they are sent, so
+    /// recording does not wait for the network.
+    pub fn dispatch(&mut self, events: &Mutex<Events>) -> bool {
+        {
+            let mut ev_data = events.lock().unwrap();
+            if self.sinks.is_empty() {
+                logger().log(LogLevelFilter::Debug, "No sinks, events kept in the queue");
+                return false;
+            }
+            ev_data.end_idle_session();
+            ev_data.report_dropped_events();
+            self.enqueue_urgent(&mut ev_data);
+            // Events keep the id they were recorded with, so only events
+            // recorded from now on are sent under the rotated id.
+            ev_data.rotate_client_id_if_due();
+        }
+        if !self.flush_all(events) {
+            logger().log(LogLevelFilter::Debug,
+                         "Earlier batches are still pending, events kept in the queue");
+            return false;
+        }
+        {
+            let mut ev_data = events.lock().unwrap();
+            let batch = ev_data.take_events();
+            for sink in &mut self.sinks {
+                sink.enqueue(&ev_data, &batch);
+            }
+        }
+        self.flush_all(events)
+    }
+
+    fn flush_all(&mut self, events: &Mutex<Events>) -> bool {
+        let mut success = true;
+        for sink in &mut self.sinks {
+            success = sink.flush(events) && success;
+        }
+        success
+    }
+
+    /// Like `dispatch`, but only sends the urgent events, leaving the rest
+    /// of the queue and the batches of earlier sends for the next send.
+    pub fn dispatch_urgent(&mut self, events: &Mutex<Events>) -> bool {
+        if self.sinks.is_empty() {
+            return false;
+        }
+        self.enqueue_urgent(&mut events.lock().unwrap());
+        let mut success = true;
+        for sink in &mut self.sinks {
+            success = sink.flush_urgent(events) && success;
+        }
+        success
+    }
+
+    // Hands the urgent events to every sink.  They stay on disk until every
+    // sink has queued them.
+    fn enqueue_urgent(&mut self, events: &mut Events) {
+        let urgent = events.urgent_events().to_vec();
+        for sink in &mut self.sinks {
+            sink.enqueue_urgent(events, &urgent);
+        }
+        events.take_urgent_events();
+    }
+
+    /// Deletes every batch waiting to be sent, in memory and on disk.
+    pub fn delete_pending(&mut self) {
+        for sink in &mut self.sinks {
+            sink.queue.clear();
+            sink.urgent.clear();
+        }
+    }
+
+    /// Tells every sink's backend that the user asked for their data to be
+    /// deleted, if "sendDeletionRequest" is set.  The request is a single
+    /// event, sent regardless of the sinks' filters and kept until it is
+    /// accepted.
+    pub fn send_deletion_request(&mut self, events: &Mutex<Events>) {
+        if !self.deletion_request_enabled {
+            return;
+        }
+        {
+            let ev_data = events.lock().unwrap();
+            let request = Event {
+                category: DELETION_REQUEST_CATEGORY.to_string(),
+                action: DELETION_REQUEST_ACTION.to_string(),
+                label: String::new(),
+                value: 1,
+                floating_point_value: None,
+                timestamp: ev_data.time_string(),
+                created: ev_data.clock().now_millis(),
+                client_id: ev_data.client_id().to_string(),
+                privacy: ev_data.privacy().clone(),
+                session: None,
+            };
+            for sink in &mut self.sinks {
+                sink.enqueue_unfiltered(&ev_data, &[request.clone()]);
+            }
+        }
+        for sink in &mut self.sinks {
+            sink.flush(events);
+        }
+    }
+}
+
+/// Encoded batches that have not yet been accepted by the server, keyed by
+/// a zero-padded sequence number so that they sort in the order they were
+/// queued.  The queue is written to disk on every change so batches survive
+/// a restart.
+pub struct PendingBatches {
+    file_name: String,
+    batches: BTreeMap<String, Value>,
+    next_sequence: u64,
+}
+
+impl PendingBatches {
+    pub fn load(file_name: &str) -> PendingBatches {
+        let mut batches = BTreeMap::new();
+        let mut cfg = Config::new();
+        if cfg.init(file_name) {
+            for batch_id in cfg.keys() {
+                if let Some(batch) = cfg.get(&batch_id) {
+                    batches.insert(batch_id, batch);
+                }
+            }
+        }
+        let next_sequence = batches.keys()
+            .filter_map(|batch_id| parse_sequence(batch_id))
+            .max()
+            .map_or(0, |last| last + 1);
+        PendingBatches {
+            file_name: file_name.to_string(),
+            batches: batches,
+            next_sequence: next_sequence,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.batches.len()
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.batches.keys().cloned().collect()
+    }
+
+    pub fn get(&self, batch_id: &str) -> Option<Value> {
+        self.batches.get(batch_id).cloned()
+    }
+
+    /// Queues `batch` after the others and returns its id.
+    pub fn add(&mut self, batch: Value) -> String {
+        let batch_id = format!("{:020}", self.next_sequence);
+        self.next_sequence += 1;
+        self.batches.insert(batch_id.clone(), batch);
+        self.save();
+        batch_id
+    }
+
+    /// Drops every batch and deletes the file.
+    pub fn clear(&mut self) {
+        self.batches.clear();
+        let _ = fs::remove_file(&self.file_name);
+    }
+
+    pub fn remove(&mut self, batch_id: &str) {
+        self.batches.remove(batch_id);
+        self.save();
+    }
+
+    fn save(&self) {
+        let json = match serde_json::to_string(&Value::Object(self.batches.clone())) {
+            Ok(json) => json,
+            Err(e) => {
+                logger().log(LogLevelFilter::Error,
+                             format!("Could not serialize pending batches: {}", e).as_str());
+                return;
+            }
+        };
+        Config::new().create_and_write_json(&self.file_name, &json);
+    }
+}
+
+// The sequence number of a batch id written by `PendingBatches::add`.
+fn parse_sequence(batch_id: &str) -> Option<u64> {
+    if batch_id.len() != 20 {
+        return None;
+    }
+    batch_id.parse().ok()
+}
+
+fn get_rules(value: &Value, key: &str) -> Result<Vec<EventRule>, String> {
+    match value.find(key) {
+        None => Ok(Vec::new()),
+        Some(&Value::Array(ref rules)) => {
+            let mut result = Vec::new();
+            for rule in rules {
+                result.push(try!(EventRule::from_value(rule)));
+            }
+            Ok(result)
+        }
+        Some(_) => Err(format!("expected an array for {}", key)),
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! sink_filtering {
+    before_each {
+        use events::Event;
+        use privacy::PrivacySettings;
+
+        let eng_event = Event {
+            category: "eng-memory".to_string(),
+            action: "sample".to_string(),
+            label: "rss".to_string(),
+            value: 1,
+            floating_point_value: None,
+            timestamp: "2016-05-25 22:36:57".to_string(),
+            created: 1464215817000,
+            client_id: "9eccb690-93aa-4513-835a-9a4f0f0e2a71".to_string(),
+            privacy: PrivacySettings::new(),
+            session: None,
+        };
+        let user_event = Event {
+            category: "user".to_string(),
+            action: "open-app".to_string(),
+            label: "main".to_string(),
+            value: 1,
+            floating_point_value: None,
+            timestamp: "2016-05-25 22:36:57".to_string(),
+            created: 1464215817000,
+            client_id: "9eccb690-93aa-4513-835a-9a4f0f0e2a71".to_string(),
+            privacy: PrivacySettings::new(),
+            session: None,
+        };
+        let eng_rule = EventRule {
+            category: Some("eng*".to_string()),
+            action: None,
+            label: None,
+        };
+    }
+
+    it "should accept everything with no rules" {
+        let filter = EventFilter::default();
+        assert!(filter.accepts(&eng_event));
+        assert!(filter.accepts(&user_event));
+    }
+
+    it "should only accept included events" {
+        let filter = EventFilter { include: vec![eng_rule], exclude: Vec::new() };
+        assert!(filter.accepts(&eng_event));
+        assert!(!filter.accepts(&user_event));
+    }
+
+    it "should never accept excluded events" {
+        let filter = EventFilter { include: Vec::new(), exclude: vec![eng_rule] };
+        assert!(!filter.accepts(&eng_event));
+        assert!(filter.accepts(&user_event));
+    }
+
+    it "should match every field of a rule" {
+        let rule = EventRule {
+            category: Some("user".to_string()),
+            action: Some("open-app".to_string()),
+            label: Some("other".to_string()),
+        };
+        assert!(!rule.matches(&user_event));
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! sink_config {
+    before_each {
+        use config::Config;
+        use storage::Storage;
+
+        let mut cfg = Config::new();
+    }
+
+    it "should default to a single Google Analytics sink" {
+        cfg.parse_json("{\"analytics\": \"UA-1\"}".to_string());
+        let sinks = Sinks::from_parsed_config(&mut cfg, &Storage::current());
+        assert_eq!(sinks.sinks.len(), 1);
+        assert_eq!(sinks.sinks[0].name(), "ga");
+    }
+
+    it "should use the legacy telemetry settings" {
+        cfg.parse_json("{\"telemetryServer\": \"https://t.example.com\"}".to_string());
+        let sinks = Sinks::from_parsed_config(&mut cfg, &Storage::current());
+        assert_eq!(sinks.sinks.len(), 1);
+        assert_eq!(sinks.sinks[0].format,
+                   SinkFormat::Telemetry(TelemetryConfig::new("https://t.example.com")));
+    }
+
+    it "should read every configured sink" {
+        cfg.parse_json(r#"{ "sinks": [
+                              { "name": "test_ga", "exclude": [ { "category": "eng*" } ] },
+                              { "name": "test_internal", "format": "telemetry",
+                                "url": "https://c.example.com", "retries": 3,
+                                "compression": "gzip",
+                                "include": [ { "category": "eng*" } ] } ] }"#.to_string());
+        let sinks = Sinks::from_parsed_config(&mut cfg, &Storage::current());
+        assert_eq!(sinks.sinks.len(), 2);
+        assert_eq!(sinks.sinks[0].filter.exclude.len(), 1);
+        assert_eq!(sinks.sinks[1].filter.include.len(), 1);
+    }
+
+    it "should skip sinks with an invalid config" {
+        cfg.parse_json(r#"{ "sinks": [ { "name": "test_bad", "format": "carrier-pigeon" },
+                                        { "name": "test_zip", "compression": "zip" },
+                                        { "format": "ga" } ] }"#.to_string());
+        let sinks = Sinks::from_parsed_config(&mut cfg, &Storage::current());
+        assert_eq!(sinks.sinks.len(), 0);
+    }
+
+    it "should skip sinks whose name is not safe in a file name" {
+        cfg.parse_json(r#"{ "sinks": [ { "name": "../test_up" }, { "name": "test/down" },
+                                        { "name": "" }, { "name": "test_ok-1" } ] }"#.to_string());
+        let sinks = Sinks::from_parsed_config(&mut cfg, &Storage::current());
+        assert_eq!(sinks.sinks.len(), 1);
+        assert_eq!(sinks.sinks[0].name(), "test_ok-1");
+    }
+
+    it "should put every sink in dry-run mode" {
+        use validation::DryRun;
+
+        cfg.parse_json(r#"{ "dryRun": "debug", "debugUrl": "http://localhost:9999/debug",
+                            "sinks": [ { "name": "test_dry_ga" } ] }"#.to_string());
+        let sinks = Sinks::from_parsed_config(&mut cfg, &Storage::current());
+        assert_eq!(sinks.sinks[0].dry_run,
+                   Some(DryRun::Debug { url: "http://localhost:9999/debug".to_string() }));
+    }
+
+    it "should not send anything with an unknown dry-run mode" {
+        cfg.parse_json(r#"{ "dryRun": "yes" }"#.to_string());
+        let sinks = Sinks::from_parsed_config(&mut cfg, &Storage::current());
+        assert_eq!(sinks.sinks.len(), 0);
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! pending_batches {
+    it "should keep pending batches across loads" {
+        use std::fs;
+        let file = "test_pending_batches.dat";
+        let batch_id;
+        {
+            let mut pending = PendingBatches::load(file);
+            batch_id = pending.add(Value::String("v=1&t=event".to_string()));
+        }
+        let mut pending = PendingBatches::load(file);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.get(&batch_id), Some(Value::String("v=1&t=event".to_string())));
+        pending.remove(&batch_id);
+        assert_eq!(PendingBatches::load(file).len(), 0);
+        let _ = fs::remove_file(file);
+    }
+
+    it "should return batches in the order they were queued, across loads" {
+        use std::fs;
+        let file = "test_pending_batches_order.dat";
+        let mut ids = Vec::new();
+        {
+            let mut pending = PendingBatches::load(file);
+            for n in 0..12 {
+                ids.push(pending.add(Value::U64(n)));
+            }
+            pending.remove(&ids[11]);
+            ids.pop();
+        }
+        let mut pending = PendingBatches::load(file);
+        ids.push(pending.add(Value::U64(12)));
+        assert_eq!(pending.ids(), ids);
+        let values: Vec<Value> = ids.iter().filter_map(|id| pending.get(id)).collect();
+        assert_eq!(values[10], Value::U64(10));
+        assert_eq!(values[11], Value::U64(12));
+        let _ = fs::remove_file(file);
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! urgent_dispatch {
+    before_each {
+        use clock::ManualClock;
+        use controller::EventInfo;
+        use events::Priority;
+        use std::sync::Arc;
+        use storage::TestStorage;
+
+        let test_storage = TestStorage::new("test_urgent_dispatch");
+        let storage = test_storage.storage().clone();
+        let event_info = EventInfo::new(
+                    "en-us",
+                    "linux",
+                    "1.2",
+                    "RPi/2",
+                    "iot_app",
+                    "1.0",
+                    "default",
+                    "rust test",
+                    "arm");
+        let clock = Arc::new(ManualClock::new(1464215817000));
+        let mut events = Events::new(event_info,
+                                     "CD_ap".to_string(),
+                                     &storage,
+                                     &mut Config::load(&storage),
+                                     clock);
+        let mut sink = Sink::google_analytics(ConnectionConfig::default(), &storage);
+        sink.set_dry_run(DryRun::Local);
+        let mut sinks = Sinks::new(vec![sink]);
+    }
+
+    it "should send urgent events without the batches already queued" {
+        events.insert_event("category", "action", "label", 1).unwrap();
+        let batch = events.take_events();
+        sinks.sinks[0].enqueue(&events, &batch);
+        events.insert_event_with_priority("crash", "signal", "SIGSEGV", 11, Priority::Urgent)
+            .unwrap();
+
+        let events = Mutex::new(events);
+        assert!(sinks.dispatch_urgent(&events));
+        assert_eq!((sinks.sinks[0].urgent.len(), sinks.sinks[0].queue.len()), (0, 1));
+        let mut events = events.into_inner().unwrap();
+        let reports = events.take_validation_reports();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].hit.contains("&ec=crash&"));
+    }
+
+    it "should keep the events without any sink" {
+        events.insert_event("category", "action", "label", 1).unwrap();
+        events.insert_event_with_priority("crash", "signal", "SIGSEGV", 11, Priority::Urgent)
+            .unwrap();
+        sinks.sinks.clear();
+        let events = Mutex::new(events);
+        assert!(!sinks.dispatch(&events));
+        assert!(!sinks.dispatch_urgent(&events));
+        let events = events.into_inner().unwrap();
+        assert_eq!((events.urgent_len(), events.len()), (1, 1));
+    }
+
+    it "should leave the events queued while earlier batches are pending" {
+        // Nothing listens on port 1, so the earlier batch cannot be sent.
+        sinks.sinks[0].set_dry_run(DryRun::Debug { url: "http://127.0.0.1:1/debug/collect".to_string() });
+        events.insert_event("category", "action", "earlier", 1).unwrap();
+        let batch = events.take_events();
+        sinks.sinks[0].enqueue(&events, &batch);
+        events.insert_event("category", "action", "later", 1).unwrap();
+
+        let events = Mutex::new(events);
+        assert!(!sinks.dispatch(&events));
+        assert_eq!(sinks.sinks[0].queue.len(), 1);
+        assert_eq!(events.lock().unwrap().len(), 1);
+    }
+
+    it "should send urgent events before the batches already queued" {
+        events.insert_event("category", "action", "label", 1).unwrap();
+        let batch = events.take_events();
+        sinks.sinks[0].enqueue(&events, &batch);
+        events.insert_event_with_priority("crash", "signal", "SIGSEGV", 11, Priority::Urgent)
+            .unwrap();
+
+        let events = Mutex::new(events);
+        assert!(sinks.dispatch(&events));
+        let mut events = events.into_inner().unwrap();
+        let reports = events.take_validation_reports();
+        assert_eq!(reports.len(), 2);
+        assert!(reports[0].hit.contains("&ec=crash&"));
+        assert!(reports[1].hit.contains("&ec=category&"));
+    }
+}
@@ -0,0 +1,49 @@
+// Counters describing what the library has done with the events it was
+// given.  A snapshot is available from `MetricsController::get_stats`.
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Total size of the request bodies accepted by a server, before
+    /// compression.
+    pub bytes_uncompressed: u64,
+    /// Total size of the same request bodies as they were transmitted.
+    pub bytes_transmitted: u64,
+    /// Hits checked in dry-run mode.
+    pub hits_validated: u64,
+    /// Hits the dry-run validator found problems with.
+    pub invalid_hits: u64,
+    /// Events dropped by a sampling rule.
+    pub sampled_out: u64,
+    /// Events dropped by a rate limit.
+    pub rate_limited: u64,
+    /// Events dropped because the queue was full.
+    pub overflow_dropped: u64,
+    /// Personal data found in event fields and replaced with placeholders.
+    pub redactions: u64,
+    /// Hits not sent because they were queued for longer than Google
+    /// Analytics accepts ("staleHits": "drop").
+    pub stale_dropped: u64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    pub fn record_transmission(&mut self, uncompressed: u64, transmitted: u64) {
+        self.bytes_uncompressed += uncompressed;
+        self.bytes_transmitted += transmitted;
+    }
+
+    pub fn record_validation(&mut self, valid: bool) {
+        self.hits_validated += 1;
+        if !valid {
+            self.invalid_hits += 1;
+        }
+    }
+
+    /// Number of bytes compression has saved so far.
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_uncompressed.saturating_sub(self.bytes_transmitted)
+    }
+}
@@ -0,0 +1,81 @@
+use std::path::{Path, PathBuf};
+
+// Where a controller finds metricsconfig.json and keeps its files (cid.dat,
+// upload.dat, session.dat, the sinks' pending batches, ...).  Controllers in
+// the same process need a directory each.  The default is the current
+// directory.
+
+pub const CONFIG_FILE: &'static str = "metricsconfig.json";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Storage {
+    dir: PathBuf,
+}
+
+impl Storage {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Storage {
+        Storage { dir: dir.as_ref().to_path_buf() }
+    }
+
+    /// The current directory.
+    pub fn current() -> Storage {
+        Storage { dir: PathBuf::new() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The path of `file_name` in the directory.  An absolute `file_name` is
+    /// used as it is.
+    pub fn path(&self, file_name: &str) -> String {
+        self.dir.join(file_name).to_string_lossy().into_owned()
+    }
+
+    pub fn config_file(&self) -> String {
+        self.path(CONFIG_FILE)
+    }
+}
+
+/// A storage directory of its own for a test, so that tests running in
+/// parallel do not share files.  It is deleted when the test ends.
+#[cfg(test)]
+pub struct TestStorage {
+    storage: Storage,
+}
+
+#[cfg(test)]
+impl TestStorage {
+    pub fn new(prefix: &str) -> TestStorage {
+        use std::fs;
+        use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+        static NEXT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+        let dir = format!("{}_{}", prefix, NEXT.fetch_add(1, Ordering::SeqCst));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestStorage { storage: Storage::new(dir) }
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestStorage {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_dir_all(self.storage.dir());
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! storage {
+    it "should keep files in its directory" {
+        assert_eq!(Storage::current().path("cid.dat"), "cid.dat");
+        let storage = Storage::new("component");
+        assert_eq!(storage.config_file(), Path::new("component").join(CONFIG_FILE).to_string_lossy());
+        assert_eq!(storage.path("/var/spill.dat"), "/var/spill.dat");
+    }
+}
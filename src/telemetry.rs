@@ -0,0 +1,202 @@
+extern crate serde_json;
+extern crate time;
+
+use config::Config;
+use controller::EventInfo;
+use events::Event;
+use self::serde_json::Value;
+use self::serde_json::builder::ObjectBuilder;
+
+// Telemetry ping output.  Instead of Google Analytics hits, queued events can
+// be wrapped in a Telemetry-style JSON ping and submitted to
+// `<server>/submit/<namespace>/<doctype>/<version>/<docId>`.
+//
+// Pings are queued by the sink that sends them (see sinks.rs) before they are
+// submitted.  A ping that fails to send is retried later with the same docId,
+// so the server can discard duplicates.
+
+const PING_FORMAT_VERSION: u64 = 4;
+const DEFAULT_NAMESPACE: &'static str = "metrics-controller";
+const DEFAULT_DOC_TYPE: &'static str = "event";
+const DEFAULT_DOC_VERSION: u64 = 1;
+
+const KEY_SERVER: &'static str = "telemetryServer";
+const KEY_NAMESPACE: &'static str = "telemetryNamespace";
+const KEY_DOC_TYPE: &'static str = "telemetryDocType";
+const KEY_DOC_VERSION: &'static str = "telemetryDocVersion";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TelemetryConfig {
+    pub server_url: String,
+    pub namespace: String,
+    pub doc_type: String,
+    pub doc_version: u64,
+}
+
+impl TelemetryConfig {
+    pub fn new(server_url: &str) -> TelemetryConfig {
+        TelemetryConfig {
+            server_url: server_url.to_string(),
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            doc_type: DEFAULT_DOC_TYPE.to_string(),
+            doc_version: DEFAULT_DOC_VERSION,
+        }
+    }
+
+    /// Reads the legacy top-level Telemetry settings.  Returns `None` when no
+    /// `telemetryServer` is configured.
+    pub fn from_parsed_config(cfg: &mut Config) -> Option<TelemetryConfig> {
+        if cfg.get(KEY_SERVER).is_none() {
+            return None;
+        }
+        let mut tc = TelemetryConfig::new(&cfg.get_string(KEY_SERVER));
+        if cfg.get(KEY_NAMESPACE).is_some() {
+            tc.namespace = cfg.get_string(KEY_NAMESPACE);
+        }
+        if cfg.get(KEY_DOC_TYPE).is_some() {
+            tc.doc_type = cfg.get_string(KEY_DOC_TYPE);
+        }
+        if cfg.get(KEY_DOC_VERSION).is_some() {
+            tc.doc_version = cfg.get_u64(KEY_DOC_VERSION);
+        }
+        Some(tc)
+    }
+
+    pub fn submit_url(&self, doc_id: &str) -> String {
+        format!("{0}/submit/{1}/{2}/{3}/{4}",
+                self.server_url.trim_right_matches('/'),
+                self.namespace,
+                self.doc_type,
+                self.doc_version,
+                doc_id)
+    }
+}
+
+/// Builds a Telemetry ping from a batch of events.  The `application` and
+/// `environment` sections are derived from the caller's `EventInfo`.
+pub fn build_ping(config: &TelemetryConfig,
+                  doc_id: &str,
+                  client_id: &str,
+                  event_info: &EventInfo,
+                  events: &[Event])
+                  -> Value {
+    let mut payload_events = Vec::new();
+    for event in events {
+        let value = match event.floating_point_value {
+            Some(v) => Value::F64(v),
+            None => Value::U64(event.value),
+        };
+        payload_events.push(ObjectBuilder::new()
+            .insert("timestamp", &event.timestamp)
+            .insert("category", &event.category)
+            .insert("action", &event.action)
+            .insert("label", &event.label)
+            .insert("value", value)
+            .build());
+    }
+
+    ObjectBuilder::new()
+        .insert("type", &config.doc_type)
+        .insert("id", doc_id)
+        .insert("creationDate", get_creation_date())
+        .insert("version", PING_FORMAT_VERSION)
+        .insert("clientId", client_id)
+        .insert_object("application", |app| {
+            app.insert("name", &event_info.app_name)
+                .insert("version", &event_info.app_version)
+                .insert("channel", &event_info.app_update_channel)
+                .insert("platform", &event_info.app_platform)
+                .insert("architecture", &event_info.arch)
+        })
+        .insert_object("environment", |env| {
+            env.insert_object("system", |system| {
+                system.insert("device", &event_info.device)
+                    .insert("arch", &event_info.arch)
+                    .insert_object("os", |os| {
+                        os.insert("name", &event_info.os)
+                            .insert("version", &event_info.os_version)
+                            .insert("locale", &event_info.locale)
+                    })
+            })
+        })
+        .insert_object("payload", |payload| {
+            // Histograms are not recorded by the library yet; the section is
+            // present so the ping matches the expected schema.
+            payload.insert("events", payload_events)
+                .insert_object("histograms", |histograms| histograms)
+        })
+        .build()
+}
+
+#[cfg(not(test))]
+fn get_creation_date() -> String {
+    format!("{}", time::now_utc().rfc3339())
+}
+
+#[cfg(test)]
+fn get_creation_date() -> String {
+    "2016-05-25T22:36:57Z".to_string()
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! telemetry_ping {
+    before_each {
+        use controller::EventInfo;
+        use events::Event;
+        use privacy::PrivacySettings;
+
+        let event_info = EventInfo::new(
+                    "en-us",
+                    "linux",
+                    "1.2",
+                    "RPi/2",
+                    "iot_app",
+                    "1.0",
+                    "nightly",
+                    "rust test",
+                    "arm");
+        let config = TelemetryConfig::new("https://incoming.example.com/");
+        let event = Event {
+            category: "category".to_string(),
+            action: "action".to_string(),
+            label: "label".to_string(),
+            value: 1,
+            floating_point_value: None,
+            timestamp: "2016-05-25 22:36:57".to_string(),
+            created: 1464215817000,
+            client_id: "9eccb690-93aa-4513-835a-9a4f0f0e2a71".to_string(),
+            privacy: PrivacySettings::new(),
+            session: None,
+        };
+    }
+
+    it "should build the submit url from the config" {
+        assert_eq!(config.submit_url("abc"),
+                   "https://incoming.example.com/submit/metrics-controller/event/1/abc");
+    }
+
+    it "should include the client id and doc id" {
+        let ping = build_ping(&config, "abc", "cid-1", &event_info, &[event]);
+        assert_eq!(ping.find("clientId").unwrap().as_str(), Some("cid-1"));
+        assert_eq!(ping.find("id").unwrap().as_str(), Some("abc"));
+        assert_eq!(ping.find("creationDate").unwrap().as_str(), Some("2016-05-25T22:36:57Z"));
+    }
+
+    it "should derive the application and environment sections from EventInfo" {
+        let ping = build_ping(&config, "abc", "cid-1", &event_info, &[event]);
+        assert_eq!(ping.lookup("application.channel").unwrap().as_str(), Some("nightly"));
+        assert_eq!(ping.lookup("environment.system.os.name").unwrap().as_str(), Some("linux"));
+        assert_eq!(ping.lookup("environment.system.device").unwrap().as_str(), Some("RPi/2"));
+    }
+
+    it "should put the events in the payload" {
+        let mut fp_event = event.clone();
+        fp_event.floating_point_value = Some(2.5);
+        let ping = build_ping(&config, "abc", "cid-1", &event_info, &[event, fp_event]);
+        let events = ping.lookup("payload.events").unwrap().as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].find("value").unwrap().as_u64(), Some(1));
+        assert_eq!(events[1].find("value").unwrap().as_f64(), Some(2.5));
+    }
+}
@@ -19,11 +19,21 @@ use std::io::Write;
 #[allow(unused_imports)]
 use std::error::Error as StdError;
 
+use compression::Compression;
+use connector::{ConnectionConfig, MetricsConnector};
+use self::hyper::client::pool::{Config as PoolConfig, Pool};
+use self::hyper::header::{ContentEncoding, ContentType, Headers};
 use self::hyper::status::StatusCode;
+use std::io;
+use std::io::Read;
+use std::time::{Duration, Instant};
 
-const METRICS_SERVER_URL: &'static str = "https://www.google-analytics.com/batch";
-const RETRY_MAX: u32 = 10;
-const RETRY_WAIT: u32 = 500;
+pub const METRICS_SERVER_URL: &'static str = "https://www.google-analytics.com/batch";
+pub const RETRY_MAX: u32 = 10;
+pub const RETRY_WAIT: u32 = 500;
+
+// Idle keep-alive connections kept open per transmitter.
+const MAX_IDLE_CONNECTIONS: usize = 2;
 
 // Shortcut to MetricsLoggerFactory function that gets the logger instance.
 #[allow(non_upper_case_globals)]
@@ -31,63 +41,189 @@ const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
 
 pub struct Transmitter {
     metrics_server_url: String,
+    retries: u32,
+    wait_time: u32,
+    compression: Compression,
+    // Long-lived client so that keep-alive connections are reused across
+    // requests and retries.
+    client: hyper::Client,
+    send_deadline: Option<Duration>,
+    // Uncompressed and transmitted sizes of the bodies sent since the last
+    // call to `take_byte_counts`.
+    byte_counts: (u64, u64),
 }
 
 impl Transmitter {
     pub fn new() -> Transmitter {
+        Transmitter::with_retries(RETRY_MAX, RETRY_WAIT)
+    }
+
+    /// Creates a Transmitter that makes up to `retries` attempts per request,
+    /// waiting `wait_time` msecs in between.
+    pub fn with_retries(retries: u32, wait_time: u32) -> Transmitter {
+        Transmitter::with_connection(retries, wait_time, ConnectionConfig::default())
+    }
+
+    /// Like `with_retries`, but connects using the given proxy, TLS and
+    /// timeout settings.
+    pub fn with_connection(retries: u32,
+                           wait_time: u32,
+                           connection: ConnectionConfig)
+                           -> Transmitter {
         logger().log(LogLevelFilter::Info, "Creating Transmitter");
-        Transmitter { metrics_server_url: METRICS_SERVER_URL.to_string() }
+        let pool = Pool::with_connector(PoolConfig { max_idle: MAX_IDLE_CONNECTIONS },
+                                        MetricsConnector::new(connection.clone()));
+        let mut client = hyper::Client::with_connector(pool);
+        client.set_read_timeout(connection.read_timeout);
+        client.set_write_timeout(connection.write_timeout);
+        Transmitter {
+            metrics_server_url: METRICS_SERVER_URL.to_string(),
+            retries: retries,
+            wait_time: wait_time,
+            compression: Compression::None,
+            client: client,
+            send_deadline: connection.send_deadline,
+            byte_counts: (0, 0),
+        }
+    }
+
+    /// Compresses request bodies before they are sent.  Only use this with
+    /// servers that accept a compressed body.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Returns the uncompressed and transmitted sizes of the bodies that were
+    /// successfully sent since the last call, and resets them.
+    pub fn take_byte_counts(&mut self) -> (u64, u64) {
+        let counts = self.byte_counts;
+        self.byte_counts = (0, 0);
+        counts
+    }
+
+    pub fn transmit(&mut self, body: String) -> bool {
+        let url = self.metrics_server_url.clone();
+        self.post(&url, body, Headers::new())
     }
 
-    pub fn transmit(&self, body: String) -> bool {
-        // TODO: perhaps make the retries configurable.
+    /// Sends a Google Analytics batch body to `url` instead of the default
+    /// metrics server.
+    pub fn transmit_to(&mut self, url: &str, body: String) -> bool {
+        self.post(url, body, Headers::new())
+    }
+
+    /// Sends a JSON document (e.g. a Telemetry ping) to `url`.
+    pub fn transmit_json(&mut self, url: &str, body: String) -> bool {
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        self.post(url, body, headers)
+    }
 
-        let mut sender = SendWithRetry {
-            url: &self.metrics_server_url,
-            body: &body,
-            retries: RETRY_MAX,
-            wait_time: RETRY_WAIT,
+    /// Posts `body` to `url` once, without retries or compression, and
+    /// returns the response body.  Used to ask a validation server about
+    /// hits instead of sending them.
+    pub fn post_for_response(&mut self, url: &str, body: String) -> Result<String, String> {
+        logger().log(LogLevelFilter::Info,
+                     format!("Sending {} bytes to {}", body.len(), url).as_str());
+        let mut response = match self.client.post(url).body(body.as_bytes()).send() {
+            Ok(response) => response,
+            Err(error) => return Err(error.description().to_string()),
         };
+        if response.status != StatusCode::Ok {
+            return Err(format!("server said {}", response.status));
+        }
+        let mut text = String::new();
+        match response.read_to_string(&mut text) {
+            Ok(_) => Ok(text),
+            Err(error) => Err(error.to_string()),
+        }
+    }
 
-        // Rust note: Even though 'sender' is declared as mutable, it
-        // needs to be explicitly passed as mutable otherwise it will
-        // be considered immutable.
-        self.send(&mut sender)
+    fn post(&mut self, url: &str, body: String, mut headers: Headers) -> bool {
+        let payload = match self.compression.compress(body.as_bytes()) {
+            Ok(payload) => {
+                if let Some(encoding) = self.compression.content_encoding() {
+                    headers.set(ContentEncoding(vec![encoding]));
+                }
+                payload
+            }
+            Err(e) => {
+                logger().log(LogLevelFilter::Error,
+                             format!("Could not compress body, sending it uncompressed: {}", e)
+                                 .as_str());
+                body.as_bytes().to_vec()
+            }
+        };
+
+        let sent = {
+            let mut sender = SendWithRetry {
+                url: url,
+                body: &payload,
+                headers: headers,
+                client: &self.client,
+                retries: self.retries,
+                wait_time: self.wait_time,
+                deadline: self.send_deadline,
+            };
+
+            // Rust note: Even though 'sender' is declared as mutable, it
+            // needs to be explicitly passed as mutable otherwise it will
+            // be considered immutable.
+            self.send(&mut sender)
+        };
+        if sent {
+            self.byte_counts.0 += body.len() as u64;
+            self.byte_counts.1 += payload.len() as u64;
+        }
+        sent
     }
 
     fn send<T: CanRetry>(&self, sender: &mut T) -> bool {
+        let started = Instant::now();
+        let deadline = sender.get_deadline();
+        let deadline_passed = || deadline.map_or(false, |deadline| started.elapsed() >= deadline);
 
         // This function retries sending the crash ping a given number of times
-        // and waits a given number of msecs in between retries.
+        // and waits a given number of msecs in between retries.  It gives up
+        // early once the deadline for the batch has passed.
         match retry::retry(sender.get_retries(),
                            sender.get_wait_time(),
                            || sender.send(),
                            // This next line evaluates to true if the request was successful
                            // and false if it failed and we need to retry.  Think of this
                            // as the condition to keep retrying or stop.
-                           |send_response| match *send_response {
-                               Ok(ref status) => {
-                                   if *status == StatusCode::Ok {
-                                       true
-                                   } else {
-                                       logger().log(LogLevelFilter::Info,
-                                                    "Server said 'not ok' (retry)");
+                           |send_response| {
+                               let done = match *send_response {
+                                   Ok(ref status) => {
+                                       if *status == StatusCode::Ok {
+                                           true
+                                       } else {
+                                           logger().log(LogLevelFilter::Info,
+                                                        "Server said 'not ok' (retry)");
+                                           false
+                                       }
+                                   }
+                                   Err(ref error) => {
+                                       logger().log(LogLevelFilter::Error,
+                                                    format!("Error sending data (retry): {}",
+                                                            error)
+                                                        .as_str());
                                        false
                                    }
-                               }
-                               Err(ref error) => {
-                                   logger().log(LogLevelFilter::Error,
-                                                format!("Error sending data (retry): {}", error)
-                                                    .as_str());
-                                   false
-                               }
+                               };
+                               done || deadline_passed()
                            }) {
             // This below is the final disposition of retrying n times.
-            Ok(_) => {
+            Ok(Ok(StatusCode::Ok)) => {
                 logger().log(LogLevelFilter::Debug,
                              "Final disposition of 'send': success");
                 return true;
             }
+            Ok(_) => {
+                logger().log(LogLevelFilter::Error,
+                             "Could not send data to server (final): send deadline passed");
+                return false;
+            }
             Err(error) => {
                 logger().log(LogLevelFilter::Error,
                              format!("Could not send data to server (final): {}", error).as_str());
@@ -110,14 +246,18 @@ impl Transmitter {
 trait CanRetry {
     fn get_retries(&self) -> u32;
     fn get_wait_time(&self) -> u32;
+    fn get_deadline(&self) -> Option<Duration>;
     fn send(&mut self) -> Result<StatusCode, String>;
 }
 
 struct SendWithRetry<'a> {
     url: &'a str,
-    body: &'a String,
+    body: &'a [u8],
+    headers: Headers,
+    client: &'a hyper::Client,
     retries: u32,
     wait_time: u32,
+    deadline: Option<Duration>,
 }
 
 impl<'a> CanRetry for SendWithRetry<'a> {
@@ -127,13 +267,20 @@ impl<'a> CanRetry for SendWithRetry<'a> {
     fn get_wait_time(&self) -> u32 {
         self.wait_time
     }
+    fn get_deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
     fn send(&mut self) -> Result<StatusCode, String> {
         logger().log(LogLevelFilter::Info,
-                     format!("Sending {} to {}", self.body, self.url).as_str());
+                     format!("Sending {} bytes to {}", self.body.len(), self.url).as_str());
         send_helper(self.body);
-        let client = hyper::Client::new();
-        match client.post(self.url).body(self.body).send() {
-            Ok(response) => return Ok(response.status),
+        match self.client.post(self.url).headers(self.headers.clone()).body(self.body).send() {
+            Ok(mut response) => {
+                // Read the rest of the response so the connection can go
+                // back to the pool.
+                let _ = io::copy(&mut response, &mut io::sink());
+                return Ok(response.status);
+            }
             Err(error) => return Err(error.description().to_string()),
         }
     }
@@ -141,10 +288,10 @@ impl<'a> CanRetry for SendWithRetry<'a> {
 
 #[allow(unused_variables)]
 #[cfg(not(feature = "integration"))]
-fn send_helper<'a>(body: &'a String) {}
+fn send_helper<'a>(body: &'a [u8]) {}
 
 #[cfg(feature = "integration")]
-fn send_helper<'a>(body: &'a String) {
+fn send_helper<'a>(body: &'a [u8]) {
     let path = Path::new("integration1.dat");
     let display = path.display();
     let mut file = match File::create(&path) {
@@ -153,8 +300,8 @@ fn send_helper<'a>(body: &'a String) {
     };
 
     logger().log(LogLevelFilter::Debug,
-                 format!("Writing {} to {}", body, display).as_str());
-    let _ = file.write(body.as_bytes());
+                 format!("Writing {} to {}", String::from_utf8_lossy(body), display).as_str());
+    let _ = file.write(body);
 }
 
 #[cfg(not(feature = "integration"))]
@@ -172,6 +319,7 @@ struct MockSendWithRetry {
     attempts: u32,
     succeed_on_attempt: u32,
     succeeded_on_attempt: u32,
+    deadline: Option<Duration>,
     result: SendResult,
 }
 
@@ -184,6 +332,9 @@ impl CanRetry for MockSendWithRetry {
     fn get_wait_time(&self) -> u32 {
         self.wait_time
     }
+    fn get_deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
     fn send(&mut self) -> Result<StatusCode, String> {
         // Should the 'send' function succeed?
         //
@@ -235,6 +386,7 @@ fn test_send_success() {
         attempts: 0,
         succeed_on_attempt: 1,
         succeeded_on_attempt: 0, // This is populated by the test.
+        deadline: None,
         result: SendResult::Success,
     };
     let mock_transmitter = create_mock_transmitter();
@@ -253,6 +405,7 @@ fn test_send_retry_success() {
         attempts: 0,
         succeed_on_attempt: 3,
         succeeded_on_attempt: 0, // This is populated by the test.
+        deadline: None,
         result: SendResult::Success,
     };
     let mock_transmitter = create_mock_transmitter();
@@ -272,6 +425,7 @@ fn test_send_retry_failure() {
         attempts: 0,
         succeed_on_attempt: 0,
         succeeded_on_attempt: 0,
+        deadline: None,
         result: SendResult::Failure,
     };
     let mock_transmitter = create_mock_transmitter();
@@ -279,3 +433,22 @@ fn test_send_retry_failure() {
 
     assert_eq!(bret, false);
 }
+
+#[cfg(not(feature = "integration"))]
+#[test]
+fn test_send_stops_at_deadline() {
+    let mut mock_sender = MockSendWithRetry {
+        retries: 5,
+        wait_time: 1,
+        attempts: 0,
+        succeed_on_attempt: 5,
+        succeeded_on_attempt: 0,
+        deadline: Some(Duration::from_secs(0)),
+        result: SendResult::Success,
+    };
+    let mock_transmitter = create_mock_transmitter();
+    let bret = mock_transmitter.send(&mut mock_sender);
+
+    assert_eq!(bret, false);
+    assert_eq!(mock_sender.attempts, 1);
+}
@@ -0,0 +1,470 @@
+extern crate serde_json;
+
+use config::Config;
+use log::LogLevelFilter;
+use logger::MetricsLoggerFactory;
+use logger::MetricsLogger;
+use self::serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use url::percent_encoding::percent_decode;
+
+// Checks Google Analytics Measurement Protocol hits.  In dry-run mode the
+// GA sinks hand their batches to a validator instead of sending them to
+// production, either Google's validation server ("debug") or the local rules
+// below ("local").  Both produce one HitReport per hit, in the same form as
+// the `hitParsingResult` array returned by the validation server.
+//
+// The same limits are applied when an event is recorded, so that an over-long
+// field is caught there rather than silently truncated or dropped by Google
+// Analytics.  Depending on "oversizeFields" in metricsconfig.json ("truncate",
+// the default, or "reject") such a field is truncated with a warning or the
+// event is rejected with an EventError.
+
+#[allow(non_upper_case_globals)]
+const logger: fn() -> &'static MetricsLogger = MetricsLoggerFactory::get_logger;
+
+const KEY_OVERSIZE_FIELDS: &'static str = "oversizeFields";
+
+pub const DEBUG_SERVER_URL: &'static str = "https://www.google-analytics.com/debug/collect";
+
+// Limits from the Measurement Protocol parameter reference, in bytes.
+pub const MAX_HIT_BYTES: usize = 8192;
+const PARAMETER_LIMITS: &'static [(&'static str, usize)] = &[("ec", 150),
+                                                              ("ea", 500),
+                                                              ("el", 500),
+                                                              ("an", 100),
+                                                              ("av", 100),
+                                                              ("ul", 20)];
+const MAX_CUSTOM_DIMENSION_BYTES: usize = 150;
+const HIT_TYPES: &'static [&'static str] = &["pageview", "screenview", "event", "transaction",
+                                              "item", "social", "exception", "timing"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DryRun {
+    /// Check hits against the local rules.
+    Local,
+    /// Post hits to a Measurement Protocol validation server.
+    Debug { url: String },
+}
+
+impl DryRun {
+    pub fn from_name(name: &str, url: Option<String>) -> Option<DryRun> {
+        match name {
+            "local" => Some(DryRun::Local),
+            "debug" => Some(DryRun::Debug { url: url.unwrap_or(DEBUG_SERVER_URL.to_string()) }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HitMessage {
+    /// "ERROR", "WARN" or "INFO".
+    pub message_type: String,
+    pub description: String,
+    pub parameter: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HitReport {
+    pub hit: String,
+    pub valid: bool,
+    pub messages: Vec<HitMessage>,
+}
+
+impl HitReport {
+    fn new(hit: &str) -> HitReport {
+        HitReport {
+            hit: hit.to_string(),
+            valid: true,
+            messages: Vec::new(),
+        }
+    }
+
+    fn error(&mut self, parameter: Option<&str>, description: String) {
+        self.valid = false;
+        self.messages.push(HitMessage {
+            message_type: "ERROR".to_string(),
+            description: description,
+            parameter: parameter.map(|p| p.to_string()),
+        });
+    }
+}
+
+/// Checks a single hit (a URL encoded query string) against the required
+/// parameters, length limits and encoding rules of the Measurement Protocol.
+pub fn validate_hit(hit: &str) -> HitReport {
+    let mut report = HitReport::new(hit);
+    if hit.len() > MAX_HIT_BYTES {
+        report.error(None,
+                     format!("The hit is {} bytes long, the limit is {}",
+                             hit.len(),
+                             MAX_HIT_BYTES));
+    }
+
+    let mut parameters = Vec::new();
+    for pair in hit.split('&').filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let raw_value = parts.next().unwrap_or("");
+        if name.is_empty() {
+            report.error(None, format!("Parameter without a name: {}", pair));
+            continue;
+        }
+        if raw_value.bytes().any(|b| b <= b' ' || b >= 0x7f) {
+            report.error(Some(name),
+                         format!("The value of {} is not URL encoded", name));
+        }
+        match percent_decode(raw_value.as_bytes()).decode_utf8() {
+            Ok(value) => parameters.push((name.to_string(), value.into_owned())),
+            Err(_) => {
+                report.error(Some(name),
+                             format!("The value of {} is not valid UTF-8", name))
+            }
+        }
+    }
+
+    let find = |name: &str| {
+        parameters.iter()
+            .find(|&&(ref n, _)| n == name)
+            .map(|&(_, ref value)| value.clone())
+    };
+
+    match find("v") {
+        Some(ref v) if v == "1" => {}
+        Some(v) => report.error(Some("v"), format!("Unsupported protocol version {}", v)),
+        None => report.error(Some("v"), "The protocol version (v) is required".to_string()),
+    }
+    match find("tid") {
+        Some(ref tid) if is_property_id(tid) => {}
+        Some(tid) => {
+            report.error(Some("tid"),
+                         format!("{} is not a valid tracking id (UA-XXXX-Y)", tid))
+        }
+        None => report.error(Some("tid"), "The tracking id (tid) is required".to_string()),
+    }
+    if find("cid").is_none() && find("uid").is_none() {
+        report.error(Some("cid"), "A client id (cid) or user id (uid) is required".to_string());
+    }
+    match find("t") {
+        Some(ref t) if HIT_TYPES.contains(&t.as_str()) => {
+            if t == "event" {
+                for &required in ["ec", "ea"].iter() {
+                    if find(required).map_or(true, |value| value.is_empty()) {
+                        report.error(Some(required),
+                                     format!("{} is required for event hits", required));
+                    }
+                }
+            }
+        }
+        Some(t) => report.error(Some("t"), format!("Unknown hit type {}", t)),
+        None => report.error(Some("t"), "The hit type (t) is required".to_string()),
+    }
+    if let Some(ev) = find("ev") {
+        if ev.parse::<u64>().is_err() {
+            report.error(Some("ev"),
+                         format!("The event value must be a non-negative integer, not {}", ev));
+        }
+    }
+
+    for &(ref name, ref value) in &parameters {
+        if let Some(limit) = parameter_limit(name) {
+            if value.len() > limit {
+                report.error(Some(name.as_str()),
+                             format!("{} is {} bytes long, the limit is {}",
+                                     name,
+                                     value.len(),
+                                     limit));
+            }
+        }
+    }
+    report
+}
+
+/// The Measurement Protocol limit, in bytes, for the parameter `name`.
+pub fn parameter_limit(name: &str) -> Option<usize> {
+    if name.starts_with("cd") && is_digits(&name[2..]) {
+        return Some(MAX_CUSTOM_DIMENSION_BYTES);
+    }
+    PARAMETER_LIMITS.iter().find(|&&(n, _)| n == name).map(|&(_, limit)| limit)
+}
+
+fn is_property_id(tid: &str) -> bool {
+    let parts: Vec<&str> = tid.split('-').collect();
+    parts.len() == 3 && (parts[0] == "UA" || parts[0] == "YT" || parts[0] == "MO") &&
+    is_digits(parts[1]) && is_digits(parts[2])
+}
+
+fn is_digits(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b >= b'0' && b <= b'9')
+}
+
+/// What to do with an event field that is longer than the Measurement
+/// Protocol allows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OversizePolicy {
+    Truncate,
+    Reject,
+}
+
+impl OversizePolicy {
+    pub fn from_name(name: &str) -> Option<OversizePolicy> {
+        match name {
+            "truncate" => Some(OversizePolicy::Truncate),
+            "reject" => Some(OversizePolicy::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn from_config(cfg: &mut Config) -> OversizePolicy {
+        match cfg.get(KEY_OVERSIZE_FIELDS) {
+            None => OversizePolicy::Truncate,
+            Some(value) => {
+                match value.as_str().and_then(OversizePolicy::from_name) {
+                    Some(policy) => policy,
+                    None => {
+                        logger().log(LogLevelFilter::Error,
+                                     "\"oversizeFields\" must be \"truncate\" or \"reject\", \
+                                      truncating");
+                        OversizePolicy::Truncate
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Why an event could not be recorded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventError {
+    /// A field is longer than the Measurement Protocol allows for
+    /// `parameter`, and the policy is to reject such events.
+    FieldTooLong {
+        parameter: &'static str,
+        length: usize,
+        limit: usize,
+    },
+    /// The encoded hit is longer than MAX_HIT_BYTES.
+    HitTooLong { length: usize, limit: usize },
+}
+
+impl fmt::Display for EventError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EventError::FieldTooLong { parameter, length, limit } => {
+                write!(f,
+                       "{} ({}) is {} bytes long, the limit is {}",
+                       parameter_description(parameter),
+                       parameter,
+                       length,
+                       limit)
+            }
+            EventError::HitTooLong { length, limit } => {
+                write!(f, "the encoded hit is {} bytes long, the limit is {}", length, limit)
+            }
+        }
+    }
+}
+
+impl Error for EventError {
+    fn description(&self) -> &str {
+        match *self {
+            EventError::FieldTooLong { .. } => "event field too long",
+            EventError::HitTooLong { .. } => "hit too long",
+        }
+    }
+}
+
+fn parameter_description(parameter: &str) -> &'static str {
+    match parameter {
+        "ec" => "event category",
+        "ea" => "event action",
+        "el" => "event label",
+        "an" => "application name",
+        "av" => "application version",
+        "ul" => "locale",
+        _ => "custom dimension",
+    }
+}
+
+/// Checks `value` against the limit for `parameter`.  Returns the value,
+/// truncated if the policy allows it, or an error.
+pub fn check_field(parameter: &'static str,
+                   value: &str,
+                   policy: OversizePolicy)
+                   -> Result<String, EventError> {
+    let limit = match parameter_limit(parameter) {
+        Some(limit) if value.len() > limit => limit,
+        _ => return Ok(value.to_string()),
+    };
+    let error = EventError::FieldTooLong {
+        parameter: parameter,
+        length: value.len(),
+        limit: limit,
+    };
+    match policy {
+        OversizePolicy::Reject => Err(error),
+        OversizePolicy::Truncate => {
+            logger().log(LogLevelFilter::Warn, format!("Truncating: {}", error).as_str());
+            Ok(truncate(value, limit).to_string())
+        }
+    }
+}
+
+// Cuts `value` to at most `limit` bytes without splitting a character.
+fn truncate(value: &str, limit: usize) -> &str {
+    let mut end = limit;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// Parses the response of the validation server, e.g.
+///
+/// ```text
+/// { "hitParsingResult": [ { "valid": false, "hit": "/debug/collect?v=1...",
+///                           "parserMessage": [ { "messageType": "ERROR",
+///                                                "description": "...",
+///                                                "parameter": "tid" } ] } ] }
+/// ```
+pub fn parse_hit_parsing_result(response: &str) -> Result<Vec<HitReport>, String> {
+    let value: Value = match serde_json::from_str(response) {
+        Ok(value) => value,
+        Err(e) => return Err(format!("invalid validation response: {}", e)),
+    };
+    let results = match value.find("hitParsingResult").and_then(|r| r.as_array()) {
+        Some(results) => results,
+        None => return Err("validation response has no hitParsingResult".to_string()),
+    };
+
+    let mut reports = Vec::new();
+    for result in results {
+        let mut messages = Vec::new();
+        if let Some(parser_messages) = result.find("parserMessage").and_then(|m| m.as_array()) {
+            for message in parser_messages {
+                let field = |key: &str| message.find(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+                messages.push(HitMessage {
+                    message_type: field("messageType").unwrap_or("ERROR".to_string()),
+                    description: field("description").unwrap_or(String::new()),
+                    parameter: field("parameter"),
+                });
+            }
+        }
+        reports.push(HitReport {
+            hit: result.find("hit").and_then(|h| h.as_str()).unwrap_or("").to_string(),
+            valid: result.find("valid").and_then(|v| v.as_bool()).unwrap_or(false),
+            messages: messages,
+        });
+    }
+    Ok(reports)
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! hit_validation {
+    before_each {
+        let valid_hit = "v=1&t=event&tid=UA-12345-1&cid=9eccb690-93aa-4513-835a-9a4f0f0e2a71\
+                         &ec=category&ea=action&el=label&ev=1&cd1=linux";
+    }
+
+    it "should accept a well formed event hit" {
+        let report = validate_hit(valid_hit);
+        assert!(report.valid);
+        assert!(report.messages.is_empty());
+    }
+
+    it "should require the protocol parameters" {
+        let report = validate_hit("t=event&ec=category&ea=action");
+        let parameters: Vec<Option<String>> =
+            report.messages.iter().map(|m| m.parameter.clone()).collect();
+        assert!(!report.valid);
+        assert!(parameters.contains(&Some("v".to_string())));
+        assert!(parameters.contains(&Some("tid".to_string())));
+        assert!(parameters.contains(&Some("cid".to_string())));
+    }
+
+    it "should require a category and action for events" {
+        let report = validate_hit("v=1&t=event&tid=UA-12345-1&cid=1&ec=category");
+        assert!(!report.valid);
+        assert_eq!(report.messages[0].parameter, Some("ea".to_string()));
+    }
+
+    it "should enforce the length limits" {
+        use std::iter;
+
+        let long_value: String = iter::repeat("x").take(151).collect();
+        let hit = format!("{}&cd2={}", valid_hit, long_value);
+        let report = validate_hit(&hit);
+        assert!(!report.valid);
+        assert_eq!(report.messages[0].parameter, Some("cd2".to_string()));
+    }
+
+    it "should reject values that are not URL encoded" {
+        let report = validate_hit(&format!("{}&el=two words", valid_hit));
+        assert!(!report.valid);
+        assert_eq!(report.messages[0].parameter, Some("el".to_string()));
+    }
+
+    it "should reject an invalid tracking id" {
+        let report = validate_hit(&valid_hit.replace("UA-12345-1", "CD_ap"));
+        assert!(!report.valid);
+        assert_eq!(report.messages[0].parameter, Some("tid".to_string()));
+    }
+
+    it "should parse the validation server response" {
+        let response = r#"{ "hitParsingResult": [
+                              { "valid": true, "hit": "/debug/collect?v=1", "parserMessage": [] },
+                              { "valid": false, "hit": "/debug/collect?v=1&tid=x",
+                                "parserMessage": [ { "messageType": "ERROR",
+                                                     "description": "bad tid",
+                                                     "parameter": "tid" } ] } ] }"#;
+        let reports = parse_hit_parsing_result(response).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!(reports[0].valid);
+        assert!(!reports[1].valid);
+        assert_eq!(reports[1].messages[0].description, "bad tid");
+        assert_eq!(reports[1].messages[0].parameter, Some("tid".to_string()));
+    }
+
+    it "should reject a response without results" {
+        assert!(parse_hit_parsing_result("{}").is_err());
+    }
+}
+
+#[cfg(not(feature = "integration"))]
+#[cfg(test)]
+describe! field_limits {
+    before_each {
+        use std::iter;
+
+        let long_category: String = iter::repeat("c").take(151).collect();
+    }
+
+    it "should leave fields within the limit alone" {
+        assert_eq!(check_field("ec", "category", OversizePolicy::Reject),
+                   Ok("category".to_string()));
+    }
+
+    it "should truncate an over-long field" {
+        let category = check_field("ec", &long_category, OversizePolicy::Truncate).unwrap();
+        assert_eq!(category.len(), 150);
+    }
+
+    it "should not split a character when truncating" {
+        // 'é' is two bytes, so the 150th byte is in the middle of one.
+        let category = format!("a{}", iter::repeat("é").take(75).collect::<String>());
+        let truncated = check_field("ec", &category, OversizePolicy::Truncate).unwrap();
+        assert_eq!(truncated.len(), 149);
+        assert!(truncated.ends_with("é"));
+    }
+
+    it "should reject an over-long field with a descriptive error" {
+        let error = check_field("ec", &long_category, OversizePolicy::Reject).unwrap_err();
+        assert_eq!(error,
+                   EventError::FieldTooLong { parameter: "ec", length: 151, limit: 150 });
+        assert_eq!(error.to_string(),
+                   "event category (ec) is 151 bytes long, the limit is 150");
+    }
+}
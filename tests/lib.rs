@@ -5,7 +5,6 @@
 extern crate chrono;
 extern crate metrics_controller;
 extern crate serde_json;
-extern crate timer;
 extern crate uuid;
 extern crate time;
 extern crate hyper;
@@ -41,13 +40,18 @@ const KEY_CID:&'static str = "cid";
 #[cfg(feature = "integration")]
 #[test]
 fn test_thread_timer() {
+    use metrics_controller::{ManualClock, WorkerEvent};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     // make sure we are starting with no files created.
     delete_file("integration1.dat");
     delete_file("cid.dat");
+    delete_file("ga_pending.dat");
 
     create_config("metricsconfig.json");
-    let mut controller = MetricsController::new(
+    let clock = Arc::new(ManualClock::new(1464215817000));
+    let mut controller = MetricsController::with_clock(
         "foxbox",
         "1.0",
         "default",
@@ -56,45 +60,42 @@ fn test_thread_timer() {
         "raspberry-pi",
         "arm",
         "linux",
-        "1.2.3.");
+        "1.2.3.",
+        clock.clone());
+    let observed = controller.worker_events();
 
     controller.start_metrics();
 
-    thread::sleep(std::time::Duration::from_secs(10));
-    controller.stop_collecting();
-    drop(controller);
-    // this sleep is needed for the file to get flushed out and saved to the
-    // disk properly.  Otherwise, you get a file not found error.
-    thread::sleep(std::time::Duration::from_secs(2));
-
-    let path = Path::new("thread.dat");
-    let display = path.display();
-    // Open the path in read-only mode.
-    let mut file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", display,
-                                                   Error::description(&why)),
-        Ok(file) => file,
-    };
-
-    // Read the file contents into a string, returns `io::Result<usize>`
-    let mut buffer = [0; 1];
-    match file.read_exact(&mut buffer) {
-        Err(why) => panic!("couldn't read {}: {}", display,
-                                                   Error::description(&why)),
-        Ok(_) => println!("value is:{}", buffer[0]),
+    // Every 2 seconds of clock time is a save cycle (saveInterval is 2).
+    let mut ticks = 0;
+    for _ in 0..20 {
+        clock.advance(Duration::from_secs(2));
+        while let Ok(event) = observed.recv_timeout(Duration::from_millis(100)) {
+            if event == WorkerEvent::Tick {
+                ticks += 1;
+            }
+        }
+        if ticks >= 5 {
+            break;
+        }
     }
-    // The timer should have been called 5 times.
-    assert_eq!(buffer[0], 5);
+    assert!(ticks >= 5);
 
-    // Now remove the file
-    match fs::remove_file("thread.dat") {
-        Err(why) => panic!("couldn't delete: {}", Error::description(&why)),
-        Ok(_) => println!("deleted"),
+    controller.stop_collecting();
+    let mut quit = false;
+    while let Ok(event) = observed.recv_timeout(Duration::from_secs(5)) {
+        if event == WorkerEvent::Quit {
+            quit = true;
+            break;
+        }
     }
+    assert!(quit);
+    drop(controller);
 
     // Clean up any side effects of the test.
     delete_file("integration1.dat");
     delete_file("cid.dat");
+    delete_file("ga_pending.dat");
 }
 
 #[cfg(feature = "integration")]
@@ -135,6 +136,7 @@ fn test_cid_file_creation_and_proper_reuse() {
     // make sure we are starting with no files created.
     delete_file("integration1.dat");
     delete_file("cid.dat");
+    delete_file("ga_pending.dat");
 
     let event_category     = "event category";
     let event_action       = "event action";
@@ -148,7 +150,7 @@ fn test_cid_file_creation_and_proper_reuse() {
         ei.app_platform, ei.locale, ei.device, ei.arch, ei.os,
         ei.os_version);
 
-    metrics_controller.record_event(event_category, event_action, event_label, event_value);
+    metrics_controller.record_event(event_category, event_action, event_label, event_value).unwrap();
     let cid1 = read_client_id();
 
     // This sleep is necessary there is no file system interactions.
@@ -159,7 +161,7 @@ fn test_cid_file_creation_and_proper_reuse() {
             ei.app_platform, ei.locale, ei.device, ei.arch, ei.os,
             ei.os_version);
 
-        metrics_controller2.record_event(event_category, event_action, event_label, event_value);
+        metrics_controller2.record_event(event_category, event_action, event_label, event_value).unwrap();
         let cid2 = read_client_id();
 
         // The same client id should be used for both metrics controllers on the same device.
@@ -203,6 +205,7 @@ fn test_cid_file_creation_and_proper_reuse() {
     // Clean up any side effects of the test.
     delete_file("integration1.dat");
     delete_file("cid.dat");
+    delete_file("ga_pending.dat");
 }
 
 // If this test fails, make sure to run integration tests in serial,
@@ -213,6 +216,7 @@ fn test_max_body_size() {
     // make sure we are starting with no files created.
     delete_file("integration1.dat");
     delete_file("cid.dat");
+    delete_file("ga_pending.dat");
 
     let event_category     = "event category";
     let event_action       = "event action";
@@ -227,7 +231,7 @@ fn test_max_body_size() {
         ei.os_version);
 
     for _ in 0.. 20 {
-        metrics_controller.record_event(event_category, event_action, event_label, event_value);
+        metrics_controller.record_event(event_category, event_action, event_label, event_value).unwrap();
     }
 
     // This sleep is necessary so the main thread does not exit.
@@ -259,6 +263,7 @@ fn test_max_body_size() {
     // Clean up any side effects of the test.
     delete_file("integration1.dat");
     delete_file("cid.dat");
+    delete_file("ga_pending.dat");
 }
 
 
@@ -282,7 +287,7 @@ fn test_google_analytics_received() {
 
     // Test with the max payload number of events (20 hits can go in one POST request).
     for _ in 0 .. 20 {
-        metrics_controller.record_event(event_category, event_action, event_label, event_value);
+        metrics_controller.record_event(event_category, event_action, event_label, event_value).unwrap();
     }
 
     // This sleep is necessary so the main thread does not exit.
@@ -365,6 +370,7 @@ fn test_google_analytics_received() {
     // Clean up any side effects of the test.
     delete_file("integration1.dat");
     delete_file("cid.dat");
+    delete_file("ga_pending.dat");
 
     assert_eq!(success, true);
 }
@@ -0,0 +1,163 @@
+#!/usr/bin/env python
+#
+# Generates include/metrics_controller.h from the C interface in
+# src/foreign_metrics/mod.rs: the #[repr(C)] enums and the #[no_mangle]
+# functions.
+# Run it from the top of the repository after changing the interface:
+#
+#     python tools/gen_header.py
+#
+# With --check it only says whether the header is up to date.
+
+import os
+import re
+import sys
+
+SOURCE = os.path.join("src", "foreign_metrics", "mod.rs")
+HEADER = os.path.join("include", "metrics_controller.h")
+
+TYPES = {
+    "bool": "bool",
+    "i32": "int32_t",
+    "u32": "uint32_t",
+    "i64": "int64_t",
+    "u64": "uint64_t",
+    "f32": "float",
+    "f64": "double",
+    "*const c_char": "const char*",
+    "*mut c_char": "char*",
+}
+
+
+def upper_snake(name):
+    return re.sub(r"(?<!^)(?=[A-Z])", "_", name).upper()
+
+
+def c_type(rust_type, structs):
+    rust_type = rust_type.strip()
+    if rust_type in TYPES:
+        return TYPES[rust_type]
+    pointer = re.match(r"\*(const|mut) (\w+)$", rust_type)
+    if pointer:
+        structs.add(pointer.group(2))
+        const = "const " if pointer.group(1) == "const" else ""
+        return "%s%s*" % (const, pointer.group(2))
+    if re.match(r"\w+$", rust_type):
+        return rust_type
+    raise ValueError("no C type for %s" % rust_type)
+
+
+def summary(doc):
+    # The first paragraph of a doc comment, as C comment lines.
+    lines = []
+    for line in doc:
+        if not line:
+            break
+        lines.append(line)
+    return ["// " + line for line in lines]
+
+
+def parse(source):
+    enums = []
+    functions = []
+    structs = set()
+    doc = []
+    attributes = []
+    lines = source.splitlines()
+    i = 0
+    while i < len(lines):
+        line = lines[i].strip()
+        i += 1
+        if line.startswith("///"):
+            doc.append(line[3:].strip())
+            continue
+        if line.startswith("#["):
+            attributes.append(line)
+            continue
+        enum = re.match(r"pub enum (\w+) \{", line)
+        if enum and "#[repr(C)]" in attributes:
+            variants = []
+            variant_doc = []
+            while lines[i].strip() != "}":
+                variant = lines[i].strip()
+                i += 1
+                if variant.startswith("///"):
+                    variant_doc.append(variant[3:].strip())
+                    continue
+                match = re.match(r"(\w+) = (\d+),", variant)
+                if not match:
+                    raise ValueError("variants need a value: %s" % variant)
+                variants.append((summary(variant_doc), upper_snake(match.group(1)), match.group(2)))
+                variant_doc = []
+            enums.append((summary(doc), enum.group(1), variants))
+        elif line.startswith('pub extern "C" fn') and "#[no_mangle]" in attributes:
+            signature = line
+            while "{" not in signature:
+                signature += " " + lines[i].strip()
+                i += 1
+            match = re.match(r'pub extern "C" fn (\w+)\((.*)\)\s*(->\s*(.+?))?\s*\{', signature)
+            name, args, ret = match.group(1), match.group(2), match.group(4)
+            params = []
+            for arg in [a for a in args.split(",") if a.strip()]:
+                arg_name, arg_type = arg.split(":", 1)
+                params.append("%s %s" % (c_type(arg_type, structs), arg_name.strip()))
+            ret = c_type(ret, structs) if ret else "void"
+            functions.append((summary(doc), ret, name, params))
+        doc = []
+        attributes = []
+    structs -= set(name for _, name, _ in enums)
+    return sorted(structs), enums, functions
+
+
+def header(source):
+    structs, enums, functions = parse(source)
+    out = ["// Generated by tools/gen_header.py from src/foreign_metrics/mod.rs.  Do not edit.",
+           "",
+           "#ifndef METRICS_CONTROLLER_H",
+           "#define METRICS_CONTROLLER_H",
+           "",
+           "#include <stdbool.h>",
+           "#include <stdint.h>",
+           "",
+           "#ifdef __cplusplus",
+           'extern "C" {',
+           "#endif",
+           ""]
+    for struct in structs:
+        out += ["typedef struct %s %s;" % (struct, struct), ""]
+    for doc, name, variants in enums:
+        out += doc
+        out.append("typedef enum %s {" % name)
+        for variant_doc, variant, value in variants:
+            out += ["    " + line for line in variant_doc]
+            out.append("    %s = %s," % (variant, value))
+        out += ["} %s;" % name, ""]
+    for doc, ret, name, params in functions:
+        out += doc
+        indent = " " * len("%s %s(" % (ret, name))
+        out.append("%s %s(%s);" % (ret, name, (",\n" + indent).join(params) or "void"))
+        out.append("")
+    out += ["#ifdef __cplusplus",
+            "}",
+            "#endif",
+            "",
+            "#endif"]
+    return "\n".join(out) + "\n"
+
+
+def main():
+    with open(SOURCE) as f:
+        generated = header(f.read())
+    if "--check" in sys.argv[1:]:
+        with open(HEADER) as f:
+            if f.read() != generated:
+                sys.exit("%s is out of date, run tools/gen_header.py" % HEADER)
+        return
+    if not os.path.isdir(os.path.dirname(HEADER)):
+        os.makedirs(os.path.dirname(HEADER))
+    with open(HEADER, "w") as f:
+        f.write(generated)
+
+
+if __name__ == "__main__":
+    main()